@@ -0,0 +1,97 @@
+//! The admin server, which exports the gateway's metrics.
+
+use crate::{serve, svc};
+use anyhow::Context;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{Request, Response, StatusCode};
+use linkerd_app_core::metrics::FmtMetrics;
+use std::{convert::Infallible, future::Future, net::SocketAddr, sync::Arc};
+
+/// Serves the admin endpoints on `addr` until `shutdown` completes.
+///
+/// `GET /metrics` renders `metrics` in the Prometheus text format.
+pub async fn serve<M>(
+    addr: SocketAddr,
+    metrics: M,
+    shutdown: impl Future + Send,
+) -> anyhow::Result<()>
+where
+    M: FmtMetrics + Send + Sync + 'static,
+{
+    let listen = serve::bind(addr)
+        .await
+        .context("failed to bind admin listener")?;
+    let metrics = Arc::new(metrics);
+    let admin = svc::service_fn(move |req: Request<hyper::body::Incoming>| {
+        let rsp = respond(&*metrics, &req);
+        futures::future::ready(Ok::<_, Infallible>(rsp))
+    });
+    serve::serve(
+        addr,
+        listen,
+        Arc::from([]),
+        shutdown,
+        svc::NewCloneService::from(admin),
+    )
+    .await;
+    Ok(())
+}
+
+fn respond<B>(metrics: &impl FmtMetrics, req: &Request<B>) -> Response<Full<Bytes>> {
+    if req.uri().path() != "/metrics" {
+        let mut rsp = Response::new(Full::new(Bytes::new()));
+        *rsp.status_mut() = StatusCode::NOT_FOUND;
+        return rsp;
+    }
+    if req.method() != http::Method::GET {
+        let mut rsp = Response::new(Full::new(Bytes::new()));
+        *rsp.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+        rsp.headers_mut()
+            .insert(http::header::ALLOW, http::HeaderValue::from_static("GET"));
+        return rsp;
+    }
+    let mut rsp = Response::new(Full::new(Bytes::from(metrics.as_display().to_string())));
+    rsp.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    rsp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt;
+
+    struct Fake;
+
+    impl FmtMetrics for Fake {
+        fn fmt_metrics(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("fake_total 1\n")
+        }
+    }
+
+    fn req(method: http::Method, path: &str) -> Request<()> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn metrics() {
+        use http_body_util::BodyExt;
+
+        let rsp = respond(&Fake, &req(http::Method::GET, "/metrics"));
+        assert_eq!(rsp.status(), StatusCode::OK);
+        let body = rsp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "fake_total 1\n");
+
+        let rsp = respond(&Fake, &req(http::Method::POST, "/metrics"));
+        assert_eq!(rsp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let rsp = respond(&Fake, &req(http::Method::GET, "/"));
+        assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+    }
+}
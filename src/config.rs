@@ -1,11 +1,21 @@
 use crate::{
-    discover::Name,
-    route::{Recognize, RoutingTable},
+    discover::{self, Name},
+    proxy_protocol,
+    route::{
+        Action, Files, FixedResponse, Forwarding, HeaderRules, HostMatch, Mirror, Recognize,
+        Redirect, Rewrite, RoutingTable, Rule, Split, Sticky, Timeouts, WeightedService,
+    },
     svc,
 };
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -14,8 +24,40 @@ pub struct Config {
     pub admin: Option<SocketAddr>,
     pub local_tld: String,
     pub dyn_dns: Option<DynDns>,
+    pub advertise: Option<Advertise>,
+    pub discovery: Discovery,
+    pub reflector: Option<Reflector>,
     pub services: HashMap<Name, Domain>,
     pub routes: RoutingTable,
+
+    /// The services listed by the service index, ordered by name.
+    pub index: Arc<[ListedService]>,
+
+    /// Proxies, such as load balancers, whose forwarding headers are believed.
+    pub trusted_proxies: Arc<[ipnet::IpNet]>,
+}
+
+/// A service as it is listed by the service index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListedService {
+    pub service: Name,
+
+    /// The service's name, without the local TLD.
+    pub name: String,
+
+    /// The URL that clients reach the service at.
+    pub url: String,
+}
+
+/// How to handle requests that match no route.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fallback {
+    /// Send the requests to this service (a key in `[services]`).
+    Service(String),
+
+    /// Respond with an index of the listed services, as HTML or JSON.
+    Index,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -23,11 +65,192 @@ pub struct Domain {
     #[serde(flatten)]
     pub recognize: Recognize,
 
+    #[serde(default)]
+    pub rewrite: Option<Rewrite>,
+
     #[serde(default = "Domain::default_ty_domain")]
     pub service: String,
+
+    /// The mDNS instance name the service announces, such as `ECLSS Sensor`
+    /// for `ECLSS Sensor._http._tcp.local.`.
+    ///
+    /// Only the `simple-mdns` backend uses this, since it can't browse for
+    /// instances. It defaults to the first label of the service's name.
+    #[serde(default)]
+    pub instance: Option<String>,
+
+    #[serde(default)]
+    pub verify: Verify,
+
+    /// Overrides the discovery-wide [`ConflictPolicy`] for this service.
+    #[serde(default)]
+    pub conflict_policy: Option<ConflictPolicy>,
+
+    /// Whether the service is listed by the service index.
+    #[serde(default = "Domain::default_listed")]
+    pub listed: bool,
+
+    /// Changes to the headers of the service's requests and responses.
+    #[serde(default)]
+    pub headers: HeaderRules,
+
+    /// The forwarding headers sent to the service, by any route.
+    #[serde(default)]
+    pub forwarding: Forwarding,
+
+    /// If set, each connection to the service starts with a PROXY protocol
+    /// header of this version, naming the client that it is made for.
+    #[serde(default)]
+    pub proxy_protocol: Option<proxy_protocol::Version>,
+
+    /// Overrides the global [`Timeouts`] for this service.
+    #[serde(default)]
+    pub timeouts: Timeouts,
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+/// A route defined separately from the service it targets, so that a service
+/// can be reached through more than one set of match rules.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RouteConfig {
+    /// The service (a key in `[services]`) that matching requests are sent to.
+    #[serde(default)]
+    pub service: Option<String>,
+
+    /// Services that matching requests are split between, instead of a single
+    /// `service`.
+    #[serde(default)]
+    pub backends: Vec<SplitBackend>,
+
+    /// How clients are kept on the same one of `backends`.
+    #[serde(default)]
+    pub sticky: Option<Sticky>,
+
+    /// Another service that a sample of this route's requests is copied to.
+    #[serde(default)]
+    pub mirror: Option<MirrorConfig>,
+
+    /// Redirects matching requests, instead of sending them to a service.
+    #[serde(default)]
+    pub redirect: Option<RedirectConfig>,
+
+    /// Answers matching requests with a fixed response, instead of sending
+    /// them to a service.
+    #[serde(default)]
+    pub respond: Option<RespondConfig>,
+
+    /// Serves matching requests from a local directory, instead of sending
+    /// them to a service.
+    #[serde(default)]
+    pub files: Option<FilesConfig>,
+
+    /// Routes with a higher priority are matched first.
+    #[serde(default)]
+    pub priority: i32,
+
+    #[serde(flatten)]
+    pub recognize: Recognize,
+
+    #[serde(default)]
+    pub rewrite: Option<Rewrite>,
+
+    /// Changes to the headers of matching requests and their responses.
+    #[serde(default)]
+    pub headers: HeaderRules,
+}
+
+/// One of the services that a route's requests are split between.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SplitBackend {
+    /// The service (a key in `[services]`).
+    pub service: String,
+
+    /// The share of requests that this service receives, relative to the
+    /// route's other backends.
+    #[serde(default = "SplitBackend::default_weight")]
+    pub weight: u32,
+}
+
+/// A service that copies of a route's requests are sent to. Its responses are
+/// discarded.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MirrorConfig {
+    /// The service (a key in `[services]`).
+    pub service: String,
+
+    /// The percentage of requests that are mirrored.
+    #[serde(default = "MirrorConfig::default_percent")]
+    pub percent: f64,
+
+    /// The largest request body that is buffered to be mirrored. Requests
+    /// with larger bodies are not mirrored.
+    #[serde(default = "MirrorConfig::default_max_body_bytes")]
+    pub max_body_bytes: usize,
+
+    /// The most mirrored requests that may be in flight at once. Requests
+    /// that arrive while this many are in flight are not mirrored.
+    #[serde(default = "MirrorConfig::default_max_in_flight")]
+    pub max_in_flight: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RedirectConfig {
+    /// The URL or path to redirect to. `$1`, `$name`, and so on are replaced
+    /// with the route's `path_regex` capture groups.
+    pub location: String,
+
+    #[serde(default = "RedirectConfig::default_status")]
+    pub status: u16,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RespondConfig {
+    #[serde(default = "RespondConfig::default_status")]
+    pub status: u16,
+
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+
+    /// The response body. If neither this nor `file` is set, the gateway's
+    /// status page is sent.
+    #[serde(default)]
+    pub body: Option<String>,
+
+    /// A file to read the response body from when the config is loaded.
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FilesConfig {
+    /// The directory to serve. Requests for a directory are answered with
+    /// its `index.html`.
+    pub root: PathBuf,
+}
+
+/// Constraints that a discovered endpoint must satisfy before traffic is
+/// routed to it.
+///
+/// mDNS is unauthenticated, so any device on the LAN can announce any name.
+/// Announcements that fail these checks are rejected. Unknown keys are
+/// rejected too, so that a misspelled constraint doesn't go unenforced.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Verify {
+    /// If non-empty, the endpoint's address must be in one of these CIDRs.
+    #[serde(default)]
+    pub allowed_cidrs: Vec<ipnet::IpNet>,
+
+    /// If set, the endpoint must be announced on this port.
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    /// TXT record keys and values that the announcement must include, such
+    /// as a shared secret.
+    #[serde(default)]
+    pub txt: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
 pub struct Listeners {
     #[serde(default = "Listeners::default_http")]
     pub http: SocketAddr,
@@ -37,6 +260,152 @@ pub struct Listeners {
 
     #[serde(default)]
     pub queue: QueueConfig,
+
+    /// Sources, such as L4 load balancers, whose connections to the HTTP
+    /// listener start with a PROXY protocol header.
+    #[serde(default)]
+    pub proxy_protocol: AcceptProxyProtocol,
+}
+
+/// The source addresses whose connections must start with a PROXY protocol
+/// header.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct AcceptProxyProtocol {
+    #[serde(default)]
+    pub http: Vec<ipnet::IpNet>,
+}
+
+/// Configures how the gateway advertises itself over mDNS.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Advertise {
+    /// The mDNS instance name for the gateway's own services.
+    #[serde(default = "Advertise::default_instance")]
+    pub instance: String,
+
+    /// The hostname (without the local TLD) that the gateway's own services
+    /// resolve to.
+    #[serde(default = "Advertise::default_hostname")]
+    pub hostname: String,
+
+    /// Whether to register an `_http._tcp` instance for the HTTP listener.
+    #[serde(default = "Advertise::default_true")]
+    pub http: bool,
+
+    /// Whether to register an `_https._tcp` instance for the HTTPS listener.
+    #[serde(default)]
+    pub https: bool,
+
+    /// Whether to publish an alias hostname pointing at the gateway for each
+    /// configured service.
+    #[serde(default)]
+    pub aliases: bool,
+
+    /// The suffix appended to a service's name to form its alias, so that
+    /// `eclss` is published as `eclss-gw.local`.
+    #[serde(default = "Advertise::default_alias_suffix")]
+    pub alias_suffix: String,
+}
+
+/// Configures mDNS service discovery.
+#[serde_with::serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Discovery {
+    /// Names of network interfaces whose subnets discovered services may be
+    /// on. If this is empty, all interfaces are allowed.
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+
+    /// Names of network interfaces whose subnets discovered services may
+    /// *not* be on. This takes precedence over `interfaces`.
+    #[serde(default)]
+    pub exclude_interfaces: Vec<String>,
+
+    /// IP families that discovered services may use.
+    #[serde(default = "Discovery::default_ip_families")]
+    pub ip_families: Vec<IpFamily>,
+
+    /// Which mDNS implementation to use.
+    #[serde(default)]
+    pub backend: MdnsBackend,
+
+    /// What to do when multiple sources announce the same service name with
+    /// different addresses.
+    #[serde(default)]
+    pub conflict_policy: ConflictPolicy,
+
+    /// A file to persist the last known endpoint for each service in, so
+    /// that services can be routed to immediately after a restart.
+    #[serde(default)]
+    pub state_file: Option<PathBuf>,
+
+    /// The maximum age, in seconds, of persisted endpoints. Older entries in
+    /// the state file are ignored at startup, and loaded entries stop being
+    /// routed to once they reach this age without being announced again.
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    #[serde(default = "Discovery::default_state_max_age")]
+    pub state_max_age: Duration,
+}
+
+/// Configures re-announcing mDNS services between network interfaces, so that
+/// devices on one subnet can discover services on another.
+#[serde_with::serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Reflector {
+    /// Names of the network interfaces to reflect announcements between.
+    /// Only announcements from addresses on these interfaces' subnets are
+    /// reflected.
+    pub interfaces: Vec<String>,
+
+    /// Service types to reflect, such as `_http._tcp`.
+    pub service_types: Vec<String>,
+
+    /// If set, only instances whose hostname matches this regex are
+    /// reflected.
+    #[serde_as(as = "Option<serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    pub hostname_regex: Option<regex::Regex>,
+}
+
+/// Which mDNS implementation discovery uses.
+///
+/// Each backend must also be enabled by the cargo feature of the same name.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MdnsBackend {
+    /// The `mdns-sd` crate.
+    #[default]
+    MdnsSd,
+    /// The `simple-mdns` crate.
+    ///
+    /// This backend can't browse, so it finds each service by querying for
+    /// its [`Domain::instance`], which defaults to the first label of its
+    /// hostname, such as `eclss._http._tcp.local.` for `eclss`. For the same
+    /// reason, services can't have `{label}` pattern names. It doesn't receive
+    /// TXT records, so services that use it can't set `verify.txt`.
+    SimpleMdns,
+}
+
+/// What to do when multiple mDNS sources announce the same service name with
+/// different addresses.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// Keep routing to the source that was discovered first.
+    #[default]
+    KeepFirst,
+    /// Route to whichever source announced itself most recently.
+    KeepNewest,
+    /// Stop routing to the service until the conflict is resolved.
+    Refuse,
+    /// Balance requests across all conflicting sources.
+    Balance,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    Ipv4,
+    Ipv6,
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
@@ -65,7 +434,30 @@ struct ConfigFile {
 
     services: HashMap<String, Domain>,
 
+    #[serde(default)]
+    routes: Vec<RouteConfig>,
+
+    #[serde(default)]
+    fallback: Option<Fallback>,
+
+    /// Clients whose `Forwarded` and `X-Forwarded-*` headers are believed.
+    /// These headers are removed from requests sent by any other client.
+    #[serde(default)]
+    trusted_proxies: Vec<ipnet::IpNet>,
+
+    /// How long forwarded requests may take, unless their service sets its
+    /// own timeouts.
+    #[serde(default)]
+    timeouts: Timeouts,
+
     dyn_dns: Option<DynDns>,
+
+    advertise: Option<Advertise>,
+
+    #[serde(default)]
+    discovery: Discovery,
+
+    reflector: Option<Reflector>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -97,6 +489,12 @@ impl Config {
         let path = path.as_ref();
         let file = std::fs::read_to_string(path)
             .with_context(|| format!("failed to open config file '{}'", path.display()))?;
+        Self::parse(&file)
+            .with_context(|| format!("failed to parse config file '{}'", path.display()))
+    }
+
+    /// Parses a config from a TOML string.
+    pub fn parse(toml: &str) -> anyhow::Result<Arc<Self>> {
         let ConfigFile {
             domain,
             services,
@@ -104,11 +502,24 @@ impl Config {
             dyn_dns,
             listen,
             admin,
-        } = toml::from_str(&file)
-            .with_context(|| format!("failed to parse config file '{}'", path.display()))?;
+            advertise,
+            discovery,
+            reflector,
+            routes,
+            fallback,
+            trusted_proxies,
+            timeouts,
+        } = toml::from_str(toml)?;
 
         let domain = Name::from(domain.as_str());
 
+        if let Some(ref reflector) = reflector {
+            anyhow::ensure!(
+                reflector.interfaces.len() >= 2,
+                "The mDNS reflector needs at least two interfaces to reflect between"
+            );
+        }
+
         let admin = if admin.enabled {
             admin.addr.or(Some(Admin::default_addr()))
         } else if let Some(addr) = admin.addr {
@@ -117,6 +528,25 @@ impl Config {
             None
         };
 
+        for (name, domain) in &services {
+            let simple_mdns = discovery.backend == MdnsBackend::SimpleMdns;
+            anyhow::ensure!(
+                !simple_mdns || !discover::is_pattern(name),
+                "Service '{name}' has a `{}` pattern name, but the simple-mdns backend can't \
+                browse for the instances it matches",
+                discover::LABEL,
+            );
+            anyhow::ensure!(
+                simple_mdns || domain.instance.is_none(),
+                "Service '{name}' sets `instance`, which only the simple-mdns backend uses",
+            );
+            anyhow::ensure!(
+                !simple_mdns || domain.verify.txt.is_empty(),
+                "Service '{name}' sets `verify.txt`, but the simple-mdns backend doesn't \
+                receive TXT records, so every announcement would be rejected",
+            );
+        }
+
         let services: HashMap<Name, Domain> = services
             .into_iter()
             .map(|(name, domain)| {
@@ -124,26 +554,267 @@ impl Config {
                 (name, domain)
             })
             .collect();
-        let routes = services
+        let qualify = |service: &str| {
+            let name = Name::from(format!("{service}.{local_tld}."));
+            anyhow::ensure!(
+                services.contains_key(&name),
+                "Route targets service '{service}', which is not configured",
+            );
+            Ok(name)
+        };
+        let explicit = routes
+            .into_iter()
+            .map(|route| {
+                let action = match (route.redirect, route.respond, route.files) {
+                    (None, None, None) => Action::Forward,
+                    (Some(redirect), None, None) => Action::Redirect(redirect.build()?),
+                    (None, Some(respond), None) => Action::Respond(respond.build()?),
+                    (None, None, Some(files)) => Action::Files(files.build()?),
+                    _ => anyhow::bail!(
+                        "A route may only have one of `redirect`, `respond`, or `files`"
+                    ),
+                };
+                if action != Action::Forward {
+                    anyhow::ensure!(
+                        route.service.is_none()
+                            && route.backends.is_empty()
+                            && route.mirror.is_none()
+                            && route.headers.is_empty(),
+                        "A route that should {action} can't have a `service`, `backends`, \
+                        `mirror`, or `headers`",
+                    );
+                    // Only the paths of files are looked up after rewriting.
+                    anyhow::ensure!(
+                        route.rewrite.is_none() || matches!(action, Action::Files(_)),
+                        "A route that should {action} can't have a `rewrite`",
+                    );
+                    return Ok(Rule {
+                        priority: route.priority,
+                        rewrite: route.rewrite,
+                        ..Rule::synthetic(route.recognize, action)
+                    });
+                }
+
+                let split = if route.backends.is_empty() {
+                    anyhow::ensure!(
+                        route.sticky.is_none(),
+                        "Only routes with `backends` can be `sticky`"
+                    );
+                    None
+                } else {
+                    let backends = route
+                        .backends
+                        .iter()
+                        .map(|backend| {
+                            let service = qualify(&backend.service)?;
+                            let domain = &services[&service];
+                            Ok(WeightedService {
+                                weight: backend.weight,
+                                forwarding: domain.forwarding.clone(),
+                                proxy_protocol: domain.proxy_protocol,
+                                timeouts: domain.timeouts.or(timeouts),
+                                service,
+                            })
+                        })
+                        .collect::<anyhow::Result<Vec<_>>>()?;
+                    let split = Split {
+                        backends,
+                        sticky: route.sticky,
+                    };
+                    anyhow::ensure!(
+                        split.total_weight() > 0,
+                        "A route's backends must have a non-zero total weight"
+                    );
+                    Some(split)
+                };
+                let service = match (route.service, &split) {
+                    (Some(service), None) => qualify(&service)?,
+                    (None, Some(split)) => split.backends[0].service.clone(),
+                    (Some(_), Some(_)) => {
+                        anyhow::bail!("A route may have either a `service` or `backends`, not both")
+                    }
+                    (None, None) => anyhow::bail!("A route must have a `service` or `backends`"),
+                };
+                let mirror = match route.mirror {
+                    Some(mirror) => {
+                        anyhow::ensure!(
+                            (0.0..=100.0).contains(&mirror.percent),
+                            "A route mirrors {}% of requests; must be between 0 and 100",
+                            mirror.percent,
+                        );
+                        anyhow::ensure!(
+                            !discover::is_pattern(&mirror.service),
+                            "Requests cannot be mirrored to '{}', which is named by a pattern",
+                            mirror.service,
+                        );
+                        anyhow::ensure!(
+                            mirror.max_in_flight > 0,
+                            "A route's mirror must allow at least one request in flight"
+                        );
+                        let service = qualify(&mirror.service)?;
+                        let domain = &services[&service];
+                        Some(Mirror {
+                            percent: mirror.percent,
+                            max_body_bytes: mirror.max_body_bytes,
+                            in_flight: Arc::new(tokio::sync::Semaphore::new(mirror.max_in_flight)),
+                            headers: Arc::new(domain.headers.clone()),
+                            forwarding: domain.forwarding.clone(),
+                            proxy_protocol: domain.proxy_protocol,
+                            timeouts: domain.timeouts.or(timeouts),
+                            service,
+                        })
+                    }
+                    None => None,
+                };
+                Ok(Rule {
+                    recognize: route.recognize,
+                    service,
+                    priority: route.priority,
+                    rewrite: route.rewrite,
+                    split,
+                    mirror,
+                    action: Action::Forward,
+                    headers: Arc::new(route.headers),
+                    forwarding: services[&service].forwarding.clone(),
+                    proxy_protocol: services[&service].proxy_protocol,
+                    timeouts: services[&service].timeouts.or(timeouts),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let inline = services
             .iter()
             .map(|(name, d)| {
+                // Other conditions narrow the service's own host, rather than
+                // matching on every host; use `host = "*"` for that.
                 let mut recognize = d.recognize.clone();
-                if recognize.is_empty() {
-                    let subdomain = name.trim_end_matches('.').trim_end_matches(&local_tld);
+                if recognize.host.is_none() {
+                    anyhow::ensure!(
+                        !discover::is_pattern(name),
+                        "Service '{name}' is named by a pattern, so it needs a wildcard `host`"
+                    );
+                    let subdomain = name
+                        .strip_suffix('.')
+                        .and_then(|name| name.strip_suffix(local_tld.as_str()))
+                        .unwrap_or(name);
                     recognize.host = Some(format!("{subdomain}{domain}").parse().unwrap());
                 }
-                (recognize, name.clone())
+                Ok(Rule {
+                    rewrite: d.rewrite.clone(),
+                    headers: Arc::new(d.headers.clone()),
+                    forwarding: d.forwarding.clone(),
+                    proxy_protocol: d.proxy_protocol,
+                    timeouts: d.timeouts.or(timeouts),
+                    ..Rule::new(recognize, name.clone())
+                })
             })
-            .collect();
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        for rule in inline.iter().chain(&explicit) {
+            if let Some(ref rewrite) = rule.rewrite {
+                anyhow::ensure!(
+                    rewrite.path.is_none() || rewrite.strip_prefix.is_none(),
+                    "A route to service '{}' sets both `rewrite.path` and `rewrite.strip_prefix`",
+                    rule.service,
+                );
+                anyhow::ensure!(
+                    rewrite.path.is_none() || rule.recognize.path_regex.is_some(),
+                    "A route to service '{}' sets `rewrite.path`, but not `path_regex`",
+                    rule.service,
+                );
+            }
+            anyhow::ensure!(
+                !discover::is_pattern(&rule.service)
+                    || matches!(rule.recognize.host, Some(HostMatch::Wildcard(_))),
+                "Routes to service '{}' need a wildcard `host` to fill in `{}`",
+                rule.service,
+                discover::LABEL,
+            );
+        }
+        let mut index = inline
+            .iter()
+            .filter(|rule| services[&rule.service].listed)
+            .filter_map(|rule| {
+                let url = rule.public_url()?;
+                let name = rule.service.trim_end_matches('.');
+                let name = name.strip_suffix(&local_tld).unwrap_or(name);
+                Some(ListedService {
+                    service: rule.service.clone(),
+                    name: name.trim_end_matches('.').to_string(),
+                    url,
+                })
+            })
+            .collect::<Vec<_>>();
+        index.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let fallback = match fallback {
+            Some(Fallback::Service(service)) => {
+                let service = qualify(&service)?;
+                Some(Rule {
+                    forwarding: services[&service].forwarding.clone(),
+                    proxy_protocol: services[&service].proxy_protocol,
+                    timeouts: services[&service].timeouts.or(timeouts),
+                    ..Rule::new(Recognize::default(), service)
+                })
+            }
+            Some(Fallback::Index) => Some(Rule::synthetic(Recognize::default(), Action::Index)),
+            None => None,
+        };
+        let aliases = advertise
+            .iter()
+            .filter(|advertise| advertise.aliases)
+            .flat_map(|advertise| {
+                let names = services.keys().filter(|name| !discover::is_pattern(name));
+                names.map(|name| {
+                    let alias = advertise.alias_for(name, &local_tld);
+                    let host = alias.trim_end_matches('.').parse().with_context(|| {
+                        format!("alias '{alias}' for service '{name}' is not a valid hostname")
+                    })?;
+                    let recognize = Recognize {
+                        host: Some(host),
+                        ..Default::default()
+                    };
+                    let service = &services[name];
+                    Ok(Rule {
+                        forwarding: service.forwarding.clone(),
+                        proxy_protocol: service.proxy_protocol,
+                        timeouts: service.timeouts.or(timeouts),
+                        ..Rule::new(recognize, name.clone())
+                    })
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let routes = inline
+            .into_iter()
+            .chain(explicit)
+            .chain(aliases)
+            .collect::<RoutingTable>();
+        let routes = match fallback {
+            Some(fallback) => routes.with_fallback(fallback),
+            None => routes,
+        };
+        for (a, b) in routes.ambiguous() {
+            tracing::warn!(
+                first = %a.service,
+                second = %b.service,
+                host = ?a.recognize.host,
+                path_regex = ?a.recognize.path_regex.as_ref().map(regex::Regex::as_str),
+                "Routes overlap and are equally specific; the first will be used. \
+                 Set a `priority` on one of them to disambiguate."
+            );
+        }
 
         Ok(Arc::new(Self {
             domain,
             local_tld,
             services,
             dyn_dns,
+            advertise,
+            discovery,
+            reflector,
             listeners: listen,
             admin,
             routes,
+            index: index.into(),
+            trusted_proxies: trusted_proxies.into(),
         }))
     }
 }
@@ -154,6 +825,203 @@ impl Domain {
     fn default_ty_domain() -> String {
         String::from("_http._tcp")
     }
+
+    const fn default_listed() -> bool {
+        true
+    }
+}
+
+// === impl SplitBackend ===
+
+impl SplitBackend {
+    const fn default_weight() -> u32 {
+        1
+    }
+}
+
+// === impl MirrorConfig ===
+
+impl MirrorConfig {
+    const fn default_percent() -> f64 {
+        100.0
+    }
+
+    const fn default_max_body_bytes() -> usize {
+        64 * 1024
+    }
+
+    const fn default_max_in_flight() -> usize {
+        16
+    }
+}
+
+// === impl RedirectConfig ===
+
+impl RedirectConfig {
+    const fn default_status() -> u16 {
+        301
+    }
+
+    fn build(self) -> anyhow::Result<Redirect> {
+        let status = http::StatusCode::from_u16(self.status)?;
+        anyhow::ensure!(
+            status.is_redirection(),
+            "Redirect status {status} is not a redirection"
+        );
+        self.location
+            .parse::<http::Uri>()
+            .with_context(|| format!("invalid redirect location '{}'", self.location))?;
+        Ok(Redirect {
+            status,
+            location: self.location,
+        })
+    }
+}
+
+// === impl RespondConfig ===
+
+impl RespondConfig {
+    const fn default_status() -> u16 {
+        200
+    }
+
+    fn build(self) -> anyhow::Result<FixedResponse> {
+        let status = http::StatusCode::from_u16(self.status)?;
+        let mut headers = http::HeaderMap::new();
+        for (name, value) in self.headers {
+            let name = name
+                .parse::<http::header::HeaderName>()
+                .with_context(|| format!("invalid header name '{name}'"))?;
+            let value = value
+                .parse::<http::HeaderValue>()
+                .with_context(|| format!("invalid value for header '{name}'"))?;
+            headers.append(name, value);
+        }
+
+        let body = match (self.body, self.file) {
+            (Some(body), None) => Some(bytes::Bytes::from(body)),
+            (None, Some(path)) => {
+                let body = std::fs::read(&path)
+                    .with_context(|| format!("failed to read '{}'", path.display()))?;
+                Some(bytes::Bytes::from(body))
+            }
+            (None, None) => None,
+            (Some(_), Some(_)) => {
+                anyhow::bail!("A response may have either a `body` or a `file`, not both")
+            }
+        };
+        if body.is_some() && !headers.contains_key(http::header::CONTENT_TYPE) {
+            headers.insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("text/plain; charset=utf-8"),
+            );
+        }
+
+        Ok(FixedResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+// === impl FilesConfig ===
+
+impl FilesConfig {
+    fn build(self) -> anyhow::Result<Files> {
+        anyhow::ensure!(
+            self.root.is_dir(),
+            "'{}' is not a directory that files can be served from",
+            self.root.display()
+        );
+        Ok(Files { root: self.root })
+    }
+}
+
+// === impl Advertise ===
+
+impl Advertise {
+    fn default_instance() -> String {
+        String::from("multipass")
+    }
+
+    fn default_hostname() -> String {
+        String::from("multipass")
+    }
+
+    fn default_alias_suffix() -> String {
+        String::from("-gw")
+    }
+
+    const fn default_true() -> bool {
+        true
+    }
+
+    /// Returns the fully-qualified hostname for the gateway itself.
+    pub fn qualified_hostname(&self, local_tld: &str) -> String {
+        format!("{}.{local_tld}.", self.hostname)
+    }
+
+    /// Returns the fully-qualified alias hostname for the service `name`.
+    ///
+    /// `name` is a fully-qualified service name such as `eclss.local.`.
+    pub fn alias_for(&self, name: &str, local_tld: &str) -> String {
+        let name = name.strip_suffix('.').unwrap_or(name);
+        let subdomain = name
+            .strip_suffix(local_tld)
+            .and_then(|name| name.strip_suffix('.'))
+            .unwrap_or(name);
+        format!("{subdomain}{}.{local_tld}.", self.alias_suffix)
+    }
+}
+
+// === impl Discovery ===
+
+impl Discovery {
+    fn default_ip_families() -> Vec<IpFamily> {
+        vec![IpFamily::Ipv4, IpFamily::Ipv6]
+    }
+
+    const fn default_state_max_age() -> Duration {
+        Duration::from_secs(60 * 60 * 24)
+    }
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self {
+            interfaces: Vec::new(),
+            exclude_interfaces: Vec::new(),
+            ip_families: Self::default_ip_families(),
+            backend: MdnsBackend::default(),
+            conflict_policy: ConflictPolicy::default(),
+            state_file: None,
+            state_max_age: Self::default_state_max_age(),
+        }
+    }
+}
+
+// === impl MdnsBackend ===
+
+impl MdnsBackend {
+    /// Returns the cargo feature that enables this backend.
+    pub fn feature(&self) -> &'static str {
+        match self {
+            Self::MdnsSd => "mdns-sd",
+            Self::SimpleMdns => "simple-mdns",
+        }
+    }
+}
+
+// === impl IpFamily ===
+
+impl IpFamily {
+    pub fn of(ip: &std::net::IpAddr) -> Self {
+        match ip {
+            std::net::IpAddr::V4(_) => Self::Ipv4,
+            std::net::IpAddr::V6(_) => Self::Ipv6,
+        }
+    }
 }
 
 // === impl Listeners ===
@@ -174,6 +1042,7 @@ impl Default for Listeners {
             http: Self::default_http(),
             https: Self::default_https(),
             queue: QueueConfig::default(),
+            proxy_protocol: AcceptProxyProtocol::default(),
         }
     }
 }
@@ -232,6 +1101,7 @@ impl<T> svc::ExtractParam<svc::queue::Timeout, T> for QueueConfig {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::route::{ForwardedHeader, ForwardingMode};
 
     #[test]
     fn route() {
@@ -251,6 +1121,732 @@ path_regex = "/eclss/*"
         );
     }
 
+    #[test]
+    fn inline_route_host() {
+        let toml = r#"
+        domain = "example.com"
+
+        [services.eclss]
+        path_regex = "^/eclss/"
+
+        [services.grafana]
+        host = "*"
+        path_regex = "^/grafana/"
+        "#;
+        let config = Config::parse(toml).unwrap();
+        let select = |host: &str, path: &str| {
+            let req = http::Request::builder()
+                .header("host", host)
+                .uri(path)
+                .body(())
+                .unwrap();
+            linkerd_router::SelectRoute::select(&config.routes, &req)
+                .ok()
+                .filter(|route| route.rule.action == Action::Forward)
+                .map(|route| route.service)
+        };
+        assert_eq!(
+            select("eclss.example.com", "/eclss/"),
+            Some(Name::from("eclss.local."))
+        );
+        assert_eq!(
+            select("grafana.example.com", "/eclss/"),
+            None,
+            "other conditions don't widen a service past its own host"
+        );
+        assert_eq!(
+            select("eclss.example.com", "/grafana/"),
+            Some(Name::from("grafana.local.")),
+            "`host = \"*\"` matches every host"
+        );
+    }
+
+    #[test]
+    fn advertise_aliases() {
+        let toml = r#"
+        domain = "example.com"
+
+        [advertise]
+        aliases = true
+
+        [services."eclss"]
+        "#;
+        let config = Config::parse(toml).unwrap();
+        let advertise = config.advertise.as_ref().expect("advertise is configured");
+        assert_eq!(advertise.instance, "multipass");
+        assert_eq!(advertise.qualified_hostname("local"), "multipass.local.");
+        assert!(advertise.http);
+        assert!(!advertise.https);
+        assert_eq!(
+            advertise.alias_for("eclss.local.", "local"),
+            "eclss-gw.local."
+        );
+        assert_eq!(
+            advertise.alias_for("mylocal.local.", "local"),
+            "mylocal-gw.local.",
+            "only the TLD label is removed"
+        );
+
+        let req = http::Request::builder()
+            .header("host", "eclss-gw.local")
+            .uri("/")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            linkerd_router::SelectRoute::select(&config.routes, &req)
+                .unwrap()
+                .service,
+            Name::from("eclss.local.")
+        );
+
+        let toml = r#"
+        domain = "example.com"
+
+        [advertise]
+        aliases = true
+        alias_suffix = " gw"
+
+        [services."eclss"]
+        "#;
+        assert!(
+            Config::parse(toml).is_err(),
+            "invalid aliases are rejected rather than panicking"
+        );
+    }
+
+    #[test]
+    fn routes() {
+        let toml = r#"
+        domain = "example.com"
+
+        [services."grafana"]
+
+        [[routes]]
+        service = "grafana"
+        host = "example.com"
+        path_regex = "^/grafana"
+        priority = 10
+        "#;
+        let config = Config::parse(toml).unwrap();
+        for (host, path) in [("grafana.example.com", "/"), ("example.com", "/grafana/d")] {
+            let req = http::Request::builder()
+                .header("host", host)
+                .uri(path)
+                .body(())
+                .unwrap();
+            assert_eq!(
+                linkerd_router::SelectRoute::select(&config.routes, &req)
+                    .unwrap()
+                    .service,
+                Name::from("grafana.local."),
+                "{host}{path} should route to grafana"
+            );
+        }
+
+        let toml = r#"
+        domain = "example.com"
+
+        [[routes]]
+        service = "grafana"
+        host = "example.com"
+
+        [services."eclss"]
+        "#;
+        assert!(
+            Config::parse(toml).is_err(),
+            "routes must target configured services"
+        );
+    }
+
+    #[test]
+    fn wildcard_routes() {
+        let toml = r#"
+        domain = "example.com"
+
+        [services."{label}"]
+        host = "*.lab.example.com"
+        "#;
+        let config = Config::parse(toml).unwrap();
+        let req = http::Request::builder()
+            .header("host", "Foo.Lab.example.com.")
+            .uri("/")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            linkerd_router::SelectRoute::select(&config.routes, &req)
+                .unwrap()
+                .service,
+            Name::from("foo.local.")
+        );
+
+        let toml = r#"
+        domain = "example.com"
+
+        [services."{label}"]
+        host = "lab.example.com"
+        "#;
+        assert!(
+            Config::parse(toml).is_err(),
+            "pattern services need a wildcard host"
+        );
+    }
+
+    #[test]
+    fn rewrite() {
+        let toml = r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        path_regex = "^/eclss(/|$)"
+        rewrite = { strip_prefix = "/eclss" }
+
+        [[routes]]
+        service = "eclss"
+        path_regex = "^/sensors/(?P<id>[0-9]+)"
+        rewrite = { path = "/api/sensor/$id" }
+        "#;
+        let config = Config::parse(toml).unwrap();
+        let select = |path: &str| {
+            let req = http::Request::builder()
+                .header("host", "example.com")
+                .uri(path)
+                .body(())
+                .unwrap();
+            let selected = linkerd_router::SelectRoute::select(&config.routes, &req).unwrap();
+            selected.rule.rewrite_path(path).map(|r| r.path)
+        };
+        assert_eq!(select("/eclss/index.html").as_deref(), Some("/index.html"));
+        assert_eq!(select("/sensors/3").as_deref(), Some("/api/sensor/3"));
+
+        let toml = r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        host = "eclss.example.com"
+        rewrite = { path = "/api" }
+        "#;
+        assert!(
+            Config::parse(toml).is_err(),
+            "`rewrite.path` needs a `path_regex`"
+        );
+    }
+
+    #[test]
+    fn split() {
+        let toml = r#"
+        domain = "example.com"
+
+        [timeouts]
+        request = 10
+
+        [services."eclss"]
+        [services."eclss-v2"]
+        proxy_protocol = "v2"
+        timeouts = { request = 30 }
+
+        [[routes]]
+        host = "sensors.example.com"
+        backends = [
+            { service = "eclss", weight = 9 },
+            { service = "eclss-v2" },
+        ]
+        sticky = { cookie = "eclss-canary" }
+        "#;
+        let config = Config::parse(toml).unwrap();
+        let req = http::Request::builder()
+            .header("host", "sensors.example.com")
+            .header("cookie", "eclss-canary=1")
+            .body(())
+            .unwrap();
+        let selected = linkerd_router::SelectRoute::select(&config.routes, &req).unwrap();
+        assert_eq!(selected.service, Name::from("eclss-v2.local."));
+        let split = selected.rule.split.as_ref().unwrap();
+        assert_eq!(split.total_weight(), 10);
+        assert_eq!(
+            split.sticky,
+            Some(Sticky::Cookie("eclss-canary".to_string()))
+        );
+        let backend = &split.backends[selected.backend.unwrap()];
+        assert_eq!(backend.service, selected.service);
+        assert_eq!(backend.proxy_protocol, Some(proxy_protocol::Version::V2));
+        assert_eq!(backend.timeouts.request, Some(Duration::from_secs(30)));
+        assert_eq!(split.backends[0].proxy_protocol, None);
+        assert_eq!(
+            split.backends[0].timeouts.request,
+            Some(Duration::from_secs(10))
+        );
+
+        let toml = r#"
+        domain = "example.com"
+
+        [services."eclss"]
+
+        [[routes]]
+        host = "sensors.example.com"
+        backends = [{ service = "eclss", weight = 0 }]
+        "#;
+        assert!(Config::parse(toml).is_err(), "weights must not all be zero");
+    }
+
+    #[test]
+    fn mirror() {
+        let toml = r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        proxy_protocol = "v1"
+        timeouts = { request = 5 }
+        [services."eclss-v2"]
+        timeouts = { request = 30 }
+
+        [[routes]]
+        service = "eclss"
+        host = "sensors.example.com"
+        mirror = { service = "eclss-v2", percent = 25, max_in_flight = 2 }
+        "#;
+        let config = Config::parse(toml).unwrap();
+        let req = http::Request::builder()
+            .header("host", "sensors.example.com")
+            .body(())
+            .unwrap();
+        let selected = linkerd_router::SelectRoute::select(&config.routes, &req).unwrap();
+        assert_eq!(selected.service, Name::from("eclss.local."));
+        let mirror = selected.rule.mirror.as_ref().unwrap();
+        assert_eq!(mirror.service, Name::from("eclss-v2.local."));
+        assert_eq!(mirror.percent, 25.0);
+        assert_eq!(mirror.max_body_bytes, 64 * 1024);
+        assert_eq!(mirror.in_flight.available_permits(), 2);
+        assert_eq!(
+            mirror.proxy_protocol, None,
+            "mirrors use their own service's settings"
+        );
+        assert_eq!(mirror.timeouts.request, Some(Duration::from_secs(30)));
+
+        let toml = r#"
+        domain = "example.com"
+
+        [services."eclss"]
+
+        [[routes]]
+        service = "eclss"
+        host = "sensors.example.com"
+        mirror = { service = "eclss-v2" }
+        "#;
+        assert!(
+            Config::parse(toml).is_err(),
+            "mirrored service must be configured"
+        );
+    }
+
+    #[test]
+    fn actions() {
+        let toml = r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        host = "www.example.com"
+
+        [[routes]]
+        host = "example.com"
+        path_regex = "^(.*)$"
+        redirect = { location = "https://www.example.com$1", status = 308 }
+
+        [[routes]]
+        host = "www.example.com"
+        path_regex = "^/robots.txt$"
+        respond = { body = "User-agent: *\nDisallow: /\n" }
+
+        [[routes]]
+        host = "www.example.com"
+        path_regex = "^/old"
+        respond = { status = 410 }
+        "#;
+        let config = Config::parse(toml).unwrap();
+        let select = |host: &str, path: &str| {
+            let req = http::Request::builder()
+                .uri(path)
+                .header("host", host)
+                .body(())
+                .unwrap();
+            linkerd_router::SelectRoute::select(&config.routes, &req)
+                .unwrap()
+                .rule
+        };
+
+        let rule = select("example.com", "/eclss");
+        assert_eq!(
+            rule.redirect_location(&"/eclss".parse().unwrap())
+                .unwrap()
+                .unwrap(),
+            "https://www.example.com/eclss"
+        );
+
+        let rule = select("www.example.com", "/robots.txt");
+        let Action::Respond(ref robots) = rule.action else {
+            panic!("expected a fixed response, got {:?}", rule.action);
+        };
+        assert_eq!(robots.status, http::StatusCode::OK);
+        assert_eq!(robots.headers["content-type"], "text/plain; charset=utf-8");
+        assert_eq!(
+            robots.body.as_deref(),
+            Some(&b"User-agent: *\nDisallow: /\n"[..])
+        );
+
+        let rule = select("www.example.com", "/old/page");
+        assert_eq!(
+            rule.action,
+            Action::Respond(FixedResponse {
+                status: http::StatusCode::GONE,
+                headers: Default::default(),
+                body: None,
+            })
+        );
+        assert_eq!(&*select("www.example.com", "/").service, "eclss.local.");
+
+        let toml = r#"
+        domain = "example.com"
+
+        [services."eclss"]
+
+        [[routes]]
+        service = "eclss"
+        host = "example.com"
+        redirect = { location = "https://www.example.com" }
+        "#;
+        assert!(
+            Config::parse(toml).is_err(),
+            "routes can't both redirect and have a service"
+        );
+    }
+
+    #[test]
+    fn files() {
+        let toml = format!(
+            r#"
+            domain = "example.com"
+
+            [services."eclss"]
+
+            [[routes]]
+            host = "example.com"
+            files = {{ root = "{}" }}
+            "#,
+            env!("CARGO_MANIFEST_DIR"),
+        );
+        let config = Config::parse(&toml).unwrap();
+        let req = http::Request::builder()
+            .header("host", "example.com")
+            .body(())
+            .unwrap();
+        let selected = linkerd_router::SelectRoute::select(&config.routes, &req).unwrap();
+        assert_eq!(
+            selected.rule.action,
+            Action::Files(Files {
+                root: env!("CARGO_MANIFEST_DIR").into()
+            })
+        );
+
+        let toml = r#"
+        domain = "example.com"
+
+        [services."eclss"]
+
+        [[routes]]
+        host = "example.com"
+        files = { root = "/does/not/exist" }
+        "#;
+        assert!(Config::parse(toml).is_err(), "root must be a directory");
+    }
+
+    #[test]
+    fn fallback() {
+        let toml = r#"
+        domain = "example.com"
+        fallback = { service = "landing" }
+
+        [services."eclss"]
+        [services."landing"]
+        "#;
+        let config = Config::parse(toml).unwrap();
+        let req = http::Request::builder()
+            .header("host", "grafana.example.com")
+            .body(())
+            .unwrap();
+        let selected = linkerd_router::SelectRoute::select(&config.routes, &req).unwrap();
+        assert_eq!(selected.service, Name::from("landing.local."));
+
+        let toml = r#"
+        domain = "example.com"
+        fallback = "index"
+
+        [services."eclss"]
+        [services."grafana"]
+        host = "example.com"
+        path_regex = "^/grafana(/|$)"
+        [services."printer"]
+        listed = false
+        "#;
+        let config = Config::parse(toml).unwrap();
+        let selected = linkerd_router::SelectRoute::select(&config.routes, &req).unwrap();
+        assert_eq!(selected.rule.action, Action::Index);
+        assert_eq!(
+            &*config.index,
+            &[
+                ListedService {
+                    service: Name::from("eclss.local."),
+                    name: "eclss".to_string(),
+                    url: "http://eclss.example.com/".to_string(),
+                },
+                ListedService {
+                    service: Name::from("grafana.local."),
+                    name: "grafana".to_string(),
+                    url: "http://example.com/grafana".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn headers() {
+        let toml = r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        headers.response.remove = ["server"]
+
+        [[routes]]
+        service = "eclss"
+        host = "sensors.example.com"
+        headers.request.set = { "x-client-ip" = "{client_ip}" }
+        "#;
+        let config = Config::parse(toml).unwrap();
+        let select = |host: &str| {
+            let req = http::Request::builder()
+                .header("host", host)
+                .body(())
+                .unwrap();
+            linkerd_router::SelectRoute::select(&config.routes, &req)
+                .unwrap()
+                .rule
+        };
+        let inline = select("eclss.example.com");
+        assert_eq!(inline.headers.response.remove, [http::header::SERVER]);
+        assert!(inline.headers.request.is_empty());
+        let explicit = select("sensors.example.com");
+        assert_eq!(
+            explicit.headers.request.set[&http::HeaderName::from_static("x-client-ip")].to_string(),
+            "{client_ip}"
+        );
+
+        let toml = r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        headers.request.set = { "x-client" = "{client}" }
+        "#;
+        assert!(
+            Config::parse(toml).is_err(),
+            "templates may only refer to known variables"
+        );
+
+        let toml = r#"
+        domain = "example.com"
+
+        [services."eclss"]
+
+        [[routes]]
+        host = "example.com"
+        redirect = { location = "https://www.example.com" }
+        headers.response.remove = ["server"]
+        "#;
+        assert!(
+            Config::parse(toml).is_err(),
+            "routes that redirect can't have header rules"
+        );
+    }
+
+    #[test]
+    fn forwarding() {
+        let toml = r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        [services."grafana"]
+        forwarding = { headers = ["x-forwarded", "x-real-ip"], mode = "replace" }
+
+        [[routes]]
+        service = "grafana"
+        host = "example.com"
+        path_regex = "^/grafana"
+        "#;
+        let config = Config::parse(toml).unwrap();
+        let select = |host: &str, path: &str| {
+            let req = http::Request::builder()
+                .uri(path)
+                .header("host", host)
+                .body(())
+                .unwrap();
+            linkerd_router::SelectRoute::select(&config.routes, &req)
+                .unwrap()
+                .rule
+        };
+        assert_eq!(
+            select("eclss.example.com", "/").forwarding,
+            Forwarding::default()
+        );
+        let grafana = Forwarding {
+            headers: vec![ForwardedHeader::XForwarded, ForwardedHeader::XRealIp],
+            mode: ForwardingMode::Replace,
+        };
+        assert_eq!(select("grafana.example.com", "/").forwarding, grafana);
+        assert_eq!(
+            select("example.com", "/grafana").forwarding,
+            grafana,
+            "routes use the forwarding headers of their service"
+        );
+    }
+
+    #[test]
+    fn timeouts() {
+        let toml = r#"
+        domain = "example.com"
+
+        [timeouts]
+        request = 30
+        response_headers = 10
+
+        [services."eclss"]
+        [services."grafana"]
+        timeouts = { response_headers = 2.5, body_idle = 5, close_upstream = false }
+
+        [[routes]]
+        service = "grafana"
+        host = "example.com"
+        path_regex = "^/grafana"
+        "#;
+        let config = Config::parse(toml).unwrap();
+        let select = |host: &str, path: &str| {
+            let req = http::Request::builder()
+                .uri(path)
+                .header("host", host)
+                .body(())
+                .unwrap();
+            linkerd_router::SelectRoute::select(&config.routes, &req)
+                .unwrap()
+                .rule
+        };
+        let eclss = select("eclss.example.com", "/").timeouts;
+        assert_eq!(
+            eclss,
+            Timeouts {
+                request: Some(Duration::from_secs(30)),
+                response_headers: Some(Duration::from_secs(10)),
+                body_idle: None,
+                close_upstream: None,
+            }
+        );
+        assert!(eclss.closes_upstream());
+
+        let grafana = Timeouts {
+            request: Some(Duration::from_secs(30)),
+            response_headers: Some(Duration::from_millis(2500)),
+            body_idle: Some(Duration::from_secs(5)),
+            close_upstream: Some(false),
+        };
+        assert_eq!(select("grafana.example.com", "/").timeouts, grafana);
+        assert_eq!(
+            select("example.com", "/grafana").timeouts,
+            grafana,
+            "routes use the timeouts of their service"
+        );
+        assert!(!grafana.closes_upstream());
+    }
+
+    #[test]
+    fn verify() {
+        let toml = r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        verify = { allowed_cidrs = ["192.168.1.0/24"], port = 80, txt = { secret = "hunter2" } }
+
+        [services."grafana"]
+        "#;
+        let ConfigFile { services, .. } = dbg!(toml::from_str(toml)).unwrap();
+        let eclss = &services["eclss"].verify;
+        assert_eq!(eclss.allowed_cidrs, vec!["192.168.1.0/24".parse().unwrap()]);
+        assert_eq!(eclss.port, Some(80));
+        assert_eq!(eclss.txt.get("secret").map(String::as_str), Some("hunter2"));
+        assert_eq!(services["grafana"].verify, Verify::default());
+
+        let toml = r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        verify = { alowed_cidrs = ["192.168.1.0/24"] }
+        "#;
+        assert!(
+            Config::parse(toml).is_err(),
+            "unknown constraints are rejected rather than silently ignored"
+        );
+
+        let toml = r#"
+        domain = "example.com"
+
+        [discovery]
+        backend = "simple-mdns"
+
+        [services."eclss"]
+        verify = { txt = { secret = "hunter2" } }
+        "#;
+        assert!(
+            Config::parse(toml).is_err(),
+            "simple-mdns can't verify TXT records"
+        );
+
+        let toml = r#"
+        domain = "example.com"
+
+        [discovery]
+        backend = "simple-mdns"
+
+        [services."{label}"]
+        host = "*.lab.example.com"
+        "#;
+        assert!(
+            Config::parse(toml).is_err(),
+            "simple-mdns can't serve pattern services"
+        );
+
+        let toml = r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        instance = "ECLSS Sensor"
+        "#;
+        assert!(
+            Config::parse(toml).is_err(),
+            "only simple-mdns queries for instance names"
+        );
+
+        let toml = r#"
+        domain = "example.com"
+
+        [discovery]
+        backend = "simple-mdns"
+
+        [services."eclss"]
+        instance = "ECLSS Sensor"
+        "#;
+        let config = Config::parse(toml).unwrap();
+        assert_eq!(
+            config.services[&Name::from("eclss.local.")]
+                .instance
+                .as_deref(),
+            Some("ECLSS Sensor")
+        );
+    }
+
     #[test]
     fn listeners() {
         let toml = r#"
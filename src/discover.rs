@@ -1,27 +1,55 @@
 use crate::config::{self, Config};
 use ahash::AHashMap;
 use anyhow::Context;
-use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
-use std::{
-    net::{IpAddr, SocketAddr},
-    sync::Arc,
-    task::Poll,
-};
-use tokio::sync::watch;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, task::Poll, time::Duration};
+use tokio::sync::{mpsc, watch};
 use tracing::Instrument;
 
+pub use self::{
+    conflict::ConflictMetrics,
+    dynamic::{expand_pattern, is_pattern, LABEL},
+    in_memory::InMemoryDiscover,
+    interfaces::Interfaces,
+    verify::Rejected,
+};
+
+#[cfg(feature = "mdns-sd")]
+mod advertise;
+mod backend;
+mod conflict;
+mod dynamic;
+mod in_memory;
+mod interfaces;
+mod persist;
+#[cfg(feature = "mdns-sd")]
+mod reflect;
+mod verify;
+
 pub type Name = Arc<str>;
 
 #[derive(Clone)]
 pub struct MdnsDiscover {
     domains: Arc<AHashMap<Name, Receiver>>,
-    _daemon: ServiceDaemon,
+    dynamic: dynamic::Dynamic,
+    conflicts: ConflictMetrics,
+    _backend: backend::Backend,
 }
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub struct Discovered {
     pub addr: SocketAddr,
     pub name: http::uri::Authority,
+
+    /// Addresses of other sources announcing the same name, which requests
+    /// are balanced across along with `addr`.
+    ///
+    /// This is empty unless the service's [`config::ConflictPolicy`] is
+    /// `balance`.
+    pub replicas: Vec<SocketAddr>,
+
+    /// `true` if this endpoint was loaded from the persisted discovery state
+    /// and has not yet been confirmed by a live announcement.
+    pub stale: bool,
 }
 
 #[derive(Debug, Clone, thiserror::Error, Default)]
@@ -34,77 +62,246 @@ pub struct NotConfigured(Name);
 
 pub type Receiver = watch::Receiver<Option<Discovered>>;
 
+struct Watch {
+    tx: Arc<watch::Sender<Option<Discovered>>>,
+    verify: config::Verify,
+    sources: conflict::Sources,
+}
+
+/// A configured service whose name is a pattern, such as `{label}.local.`,
+/// rather than a single name.
+#[derive(Clone, Debug)]
+struct Pattern {
+    name: Name,
+    verify: config::Verify,
+    policy: config::ConflictPolicy,
+}
+
 impl MdnsDiscover {
     pub fn new(config: &Config) -> anyhow::Result<Self> {
-        let daemon = ServiceDaemon::new()?;
+        let interfaces = Interfaces::from_config(&config.discovery)?;
+        tracing::info!(%interfaces, "Discovering services on interfaces");
+        let backend = backend::Backend::new(&config.discovery, &interfaces)?;
         let mut ty_domains: AHashMap<&str, AHashMap<Name, _>> = AHashMap::new();
-        let domains = config
-            .services
-            .iter()
-            .map(|(name, config::Domain { ref service, .. })| {
-                let (tx, rx) = tokio::sync::watch::channel(None);
-                ty_domains
-                    .entry(service)
+        let mut ty_patterns: AHashMap<&str, Vec<Pattern>> = AHashMap::new();
+        let mut domains = AHashMap::new();
+        let conflicts = ConflictMetrics::default();
+        for (name, domain) in &config.services {
+            let policy = domain
+                .conflict_policy
+                .unwrap_or(config.discovery.conflict_policy);
+            if is_pattern(name) {
+                ty_patterns
+                    .entry(domain.service.as_str())
                     .or_default()
-                    .insert(name.clone(), tx);
-                (name.clone(), rx)
-            })
-            .collect();
+                    .push(Pattern {
+                        name: name.clone(),
+                        verify: domain.verify.clone(),
+                        policy,
+                    });
+                continue;
+            }
 
-        for (service_type, mut watches) in ty_domains {
-            let service_type = format!("{service_type}.{}.", config.local_tld);
-            let browse = daemon
-                .browse(&service_type)
-                .with_context(|| format!("Failed to browse for {service_type}"))?;
-            tokio::spawn(
-                async move {
-                    tracing::info!("Starting to browse...");
-                    loop {
-                        let event = browse.recv_async().await;
-                        tracing::trace!(?event);
-                        match event {
-                            Err(error) => tracing::error!(%error),
-                            Ok(ServiceEvent::ServiceResolved(service)) => {
-                                let name = service.get_hostname();
-                                match watches.get_mut(name) {
-                                    Some(tx) => {
-                                        tracing::info!(service = name, info = ?format_args!("{service:#?}"), "Service resolved");
-                                        let svc = Discovered::from_service_info(&service, name);
-                                        tx.send_replace(svc);
-                                    }
-                                    None => tracing::debug!(
-                                        service = name,
-                                        info = ?format_args!("{service:#?}"),
-                                        "Service not in config, ignoring update"
-                                    ),
-                                }
-                            }
-                            Ok(ServiceEvent::ServiceRemoved(kind, name)) => {
-                                match watches.get_mut(name.as_str()) {
-                                    Some(tx) => {
-                                        tracing::info!(service = name, kind, "Service removed");
-                                        tx.send_replace(None);
-                                    }
-                                    None => tracing::debug!(
-                                        service = name,
-                                        kind,
-                                        "Service not in config, ignoring removal"
-                                    ),
-                                }
-                            }
-                            Ok(_) => {}
-                        }
-                    }
+            let (tx, rx) = tokio::sync::watch::channel(None);
+            let watch = Watch {
+                tx: Arc::new(tx),
+                verify: domain.verify.clone(),
+                sources: conflict::Sources::new(name.clone(), policy, &conflicts),
+            };
+            ty_domains
+                .entry(domain.service.as_str())
+                .or_default()
+                .insert(name.clone(), watch);
+            domains.insert(name.clone(), rx);
+        }
+
+        let dynamic = dynamic::Dynamic::new(
+            ty_patterns
+                .values()
+                .flatten()
+                .map(|pattern| pattern.name.clone()),
+        );
+        for &service_type in ty_patterns.keys() {
+            ty_domains.entry(service_type).or_default();
+        }
+
+        let seeds = match config.discovery.state_file {
+            Some(ref path) => persist::load(path, config.discovery.state_max_age),
+            None => AHashMap::new(),
+        };
+        for (name, watch) in ty_domains.values().flat_map(|watches| watches.iter()) {
+            let Some(seed) = seeds.get(name) else {
+                continue;
+            };
+            match seed
+                .discovered
+                .clone()
+                .verify_seed(&interfaces, &watch.verify)
+            {
+                Ok(discovered) => {
+                    watch.tx.send_replace(Some(discovered));
+                    tokio::spawn(expire_seed(watch.tx.clone(), name.clone(), seed.expires_in));
                 }
-                .instrument(tracing::info_span!("browse", message = %service_type)),
+                Err(rejected) => tracing::warn!(
+                    target: "multipass::security",
+                    service = %name,
+                    addr = %seed.discovered.addr,
+                    %rejected,
+                    "Rejected persisted endpoint"
+                ),
+            }
+        }
+
+        for (ty, watches) in ty_domains {
+            let service_type = format!("{ty}.{}.", config.local_tld);
+            let patterns = ty_patterns.remove(ty).unwrap_or_default();
+            let targets = watches
+                .keys()
+                .map(|name| backend::Target {
+                    hostname: name.clone(),
+                    instance: config.services[name].instance.clone(),
+                })
+                .collect();
+            let events = backend.browse(&service_type, targets)?;
+            tokio::spawn(
+                publish(
+                    events,
+                    watches,
+                    patterns,
+                    dynamic.clone(),
+                    interfaces.clone(),
+                    conflicts.clone(),
+                )
+                .instrument(tracing::info_span!("publish", message = %service_type)),
             );
         }
 
+        if let Some(ref path) = config.discovery.state_file {
+            persist::spawn(path.clone(), &domains);
+        }
+
+        if let Some(ref reflector) = config.reflector {
+            backend
+                .reflect(&config.local_tld, reflector)
+                .context("failed to start mDNS reflector")?;
+        }
+
+        if let Some(ref advertise) = config.advertise {
+            backend
+                .advertise(config, advertise)
+                .context("failed to advertise gateway over mDNS")?;
+        }
+
         Ok(Self {
             domains: Arc::new(domains),
-            _daemon: daemon,
+            dynamic,
+            conflicts,
+            _backend: backend,
         })
     }
+
+    /// Returns the metrics for conflicting announcements of each service.
+    pub fn conflict_metrics(&self) -> ConflictMetrics {
+        self.conflicts.clone()
+    }
+}
+
+/// Removes a persisted endpoint from a watch once it is older than the state
+/// file's maximum age, unless a live announcement has replaced it.
+async fn expire_seed(tx: Arc<watch::Sender<Option<Discovered>>>, name: Name, after: Duration) {
+    tokio::time::sleep(after).await;
+    tx.send_if_modified(|discovered| {
+        if !discovered
+            .as_ref()
+            .is_some_and(|discovered| discovered.stale)
+        {
+            return false;
+        }
+        tracing::info!(service = %name, "Persisted endpoint expired without being announced");
+        *discovered = None;
+        true
+    });
+}
+
+/// Publishes the endpoints announced by a backend to the watches for each
+/// service name.
+///
+/// Announced names that match one of `patterns` get a watch the first time
+/// they are seen.
+async fn publish(
+    mut events: mpsc::Receiver<backend::Event>,
+    mut watches: AHashMap<Name, Watch>,
+    patterns: Vec<Pattern>,
+    dynamic: dynamic::Dynamic,
+    interfaces: Interfaces,
+    conflicts: ConflictMetrics,
+) {
+    while let Some(event) = events.recv().await {
+        match event {
+            backend::Event::Resolved(announcement) => {
+                let name = announcement.hostname.as_str();
+                if !watches.contains_key(name) {
+                    let pattern = patterns
+                        .iter()
+                        .find(|p| dynamic::pattern_matches(&p.name, name));
+                    if let (Some(pattern), Some(tx)) = (pattern, dynamic.watch(name)) {
+                        let watch = Watch {
+                            tx,
+                            verify: pattern.verify.clone(),
+                            sources: conflict::Sources::new(
+                                Name::from(name),
+                                pattern.policy,
+                                &conflicts,
+                            ),
+                        };
+                        watches.insert(Name::from(name), watch);
+                    }
+                }
+                let Some(watch) = watches.get_mut(name) else {
+                    tracing::debug!(
+                        service = name,
+                        fullname = announcement.fullname,
+                        "Service not in config, ignoring update"
+                    );
+                    continue;
+                };
+                tracing::info!(
+                    service = name,
+                    announcement = ?format_args!("{announcement:#?}"),
+                    "Service resolved"
+                );
+                match Discovered::from_announcement(&announcement, &interfaces, &watch.verify) {
+                    Ok(svc) => {
+                        let svc = watch.sources.resolved(&announcement.fullname, svc);
+                        watch.tx.send_replace(svc);
+                    }
+                    Err(rejected) => tracing::warn!(
+                        target: "multipass::security",
+                        service = name,
+                        fullname = announcement.fullname,
+                        addrs = ?announcement.addrs,
+                        port = announcement.port,
+                        %rejected,
+                        "Rejected mDNS announcement"
+                    ),
+                }
+            }
+            backend::Event::Removed { fullname } => {
+                let mut removed = false;
+                for (name, watch) in watches.iter_mut() {
+                    if let Some(svc) = watch.sources.removed(&fullname) {
+                        tracing::info!(service = %name, fullname, "Service removed");
+                        watch.tx.send_replace(svc);
+                        removed = true;
+                    }
+                }
+                if !removed {
+                    tracing::debug!(fullname, "Service not in config, ignoring removal");
+                }
+            }
+        }
+    }
+    tracing::debug!("Backend stopped browsing");
 }
 
 impl tower::Service<Name> for MdnsDiscover {
@@ -120,24 +317,71 @@ impl tower::Service<Name> for MdnsDiscover {
     }
 
     fn call(&mut self, name: Name) -> Self::Future {
-        futures::future::ready(self.domains.get(&name).cloned().ok_or(NotConfigured(name)))
+        let rx = match self.domains.get(&name) {
+            Some(rx) => Some(rx.clone()),
+            None => self.dynamic.watch(&name).map(|tx| tx.subscribe()),
+        };
+        futures::future::ready(rx.ok_or(NotConfigured(name)))
     }
 }
 
 // === impl Discovered ===
 
 impl Discovered {
-    fn from_service_info(info: &ServiceInfo, name: &str) -> Option<Self> {
+    pub fn new(addr: SocketAddr, name: http::uri::Authority) -> Self {
+        Self {
+            addr,
+            name,
+            replicas: Vec::new(),
+            stale: false,
+        }
+    }
+
+    fn from_announcement(
+        announcement: &backend::Announcement,
+        interfaces: &Interfaces,
+        verify: &config::Verify,
+    ) -> Result<Self, Rejected> {
         // TODO(eliza): construct a load balancer over all addresses?
-        let addr = {
-            let ip = info.get_addresses().iter().next()?;
-            let port = info.get_port();
-            SocketAddr::new(IpAddr::V4(*ip), port)
+        let allowed = announcement
+            .addrs
+            .iter()
+            .copied()
+            .filter(|ip| interfaces.allows(ip))
+            .collect::<Vec<_>>();
+        if allowed.is_empty() {
+            return Err(Rejected::Interface(announcement.addrs.clone()));
+        }
+
+        let addr = SocketAddr::new(verify::select_addr(verify, allowed)?, announcement.port);
+        verify::check(verify, addr, &announcement.txt)?;
+        let name = announcement
+            .hostname
+            .parse()
+            .map_err(|_| Rejected::Hostname(announcement.hostname.clone()))?;
+        Ok(Self::new(addr, name))
+    }
+
+    /// Checks an endpoint loaded from the persisted discovery state the same
+    /// way as a live announcement. Replicas that fail the checks are dropped.
+    ///
+    /// TXT records aren't persisted, so endpoints for services that verify
+    /// TXT records are always rejected until they are announced again.
+    fn verify_seed(
+        mut self,
+        interfaces: &Interfaces,
+        verify: &config::Verify,
+    ) -> Result<Self, Rejected> {
+        let check = |addr: SocketAddr| {
+            if !interfaces.allows(&addr.ip()) {
+                return Err(Rejected::Interface(vec![addr.ip()]));
+            }
+            verify::select_addr(verify, [addr.ip()])?;
+            verify::check(verify, addr, &HashMap::new())
         };
-        Some(Self {
-            addr,
-            name: name.parse().unwrap(),
-        })
+        check(self.addr)?;
+        self.replicas.retain(|&replica| check(replica).is_ok());
+        Ok(self)
     }
 }
 
@@ -147,6 +391,12 @@ impl crate::svc::Param<SocketAddr> for Discovered {
     }
 }
 
+impl crate::svc::Param<crate::http::Replicas> for Discovered {
+    fn param(&self) -> crate::http::Replicas {
+        crate::http::Replicas(self.replicas.clone().into())
+    }
+}
+
 impl crate::svc::Param<linkerd_app_core::proxy::http::normalize_uri::DefaultAuthority>
     for Discovered
 {
@@ -154,3 +404,217 @@ impl crate::svc::Param<linkerd_app_core::proxy::http::normalize_uri::DefaultAuth
         linkerd_app_core::proxy::http::normalize_uri::DefaultAuthority(Some(self.name.clone()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{Announcement, Event};
+    use std::net::IpAddr;
+
+    const NAME: &str = "eclss.local.";
+
+    struct Harness {
+        events: mpsc::Sender<Event>,
+        rx: Receiver,
+    }
+
+    fn harness(verify: config::Verify, policy: config::ConflictPolicy) -> Harness {
+        crate::test_util::trace_init();
+        let interfaces = Interfaces::from_config(&config::Discovery::default())
+            .expect("host must have network interfaces");
+        let (tx, rx) = watch::channel(None);
+        let watch = Watch {
+            tx: Arc::new(tx),
+            verify,
+            sources: conflict::Sources::new(Name::from(NAME), policy, &Default::default()),
+        };
+        let (events, events_rx) = mpsc::channel(8);
+        tokio::spawn(publish(
+            events_rx,
+            [(Name::from(NAME), watch)].into_iter().collect(),
+            Vec::new(),
+            Default::default(),
+            interfaces,
+            Default::default(),
+        ));
+        Harness { events, rx }
+    }
+
+    /// Returns an announcement from an address on one of the host's own
+    /// interfaces, so that it passes interface filtering.
+    fn announcement(fullname: &str, port: u16) -> Announcement {
+        let ip = if_addrs::get_if_addrs()
+            .unwrap()
+            .into_iter()
+            .find(|iface| matches!(iface.ip(), IpAddr::V4(_)))
+            .expect("host must have an IPv4 interface")
+            .ip();
+        Announcement {
+            fullname: fullname.to_string(),
+            hostname: NAME.to_string(),
+            addrs: vec![ip],
+            port,
+            ..Default::default()
+        }
+    }
+
+    impl Harness {
+        async fn send(&mut self, event: Event) -> Option<Discovered> {
+            self.events.send(event).await.unwrap();
+            self.rx.changed().await.unwrap();
+            self.rx.borrow_and_update().clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_and_removes() {
+        let mut harness = harness(Default::default(), Default::default());
+        let announcement = announcement("eclss._http._tcp.local.", 80);
+        let discovered = harness
+            .send(Event::Resolved(announcement.clone()))
+            .await
+            .expect("service must resolve");
+        assert_eq!(discovered.addr, SocketAddr::new(announcement.addrs[0], 80));
+        assert_eq!(discovered.name.as_str(), NAME);
+        assert!(!discovered.stale);
+
+        let removed = harness
+            .send(Event::Removed {
+                fullname: announcement.fullname,
+            })
+            .await;
+        assert_eq!(removed, None);
+    }
+
+    #[test]
+    fn rejects_invalid_hostnames() {
+        let interfaces = Interfaces::from_config(&config::Discovery::default())
+            .expect("host must have network interfaces");
+        let mut announcement = announcement("eclss._http._tcp.local.", 80);
+        announcement.hostname = "eclss local.".to_string();
+        assert_eq!(
+            Discovered::from_announcement(&announcement, &interfaces, &Default::default()),
+            Err(Rejected::Hostname("eclss local.".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_other_services() {
+        let mut harness = harness(Default::default(), Default::default());
+        let mut other = announcement("grafana._http._tcp.local.", 80);
+        other.hostname = "grafana.local.".to_string();
+        harness.events.send(Event::Resolved(other)).await.unwrap();
+        let discovered = harness
+            .send(Event::Resolved(announcement("eclss._http._tcp.local.", 80)))
+            .await
+            .expect("service must resolve");
+        assert_eq!(discovered.addr.port(), 80);
+    }
+
+    #[tokio::test]
+    async fn creates_watches_for_patterns() {
+        crate::test_util::trace_init();
+        let interfaces = Interfaces::from_config(&config::Discovery::default())
+            .expect("host must have network interfaces");
+        let pattern = Pattern {
+            name: Name::from("{label}.local."),
+            verify: Default::default(),
+            policy: Default::default(),
+        };
+        let dynamic = dynamic::Dynamic::new([pattern.name.clone()]);
+        let (events, events_rx) = mpsc::channel(8);
+        tokio::spawn(publish(
+            events_rx,
+            AHashMap::new(),
+            vec![pattern],
+            dynamic.clone(),
+            interfaces,
+            Default::default(),
+        ));
+
+        // Subscribe before the name is announced, as a route would.
+        let mut rx = dynamic.watch("foo.local.").unwrap().subscribe();
+        let mut announcement = announcement("foo._http._tcp.local.", 80);
+        announcement.hostname = "foo.local.".to_string();
+        events.send(Event::Resolved(announcement)).await.unwrap();
+        rx.changed().await.unwrap();
+        let discovered = rx
+            .borrow_and_update()
+            .clone()
+            .expect("service must resolve");
+        assert_eq!(discovered.name.as_str(), "foo.local.");
+    }
+
+    #[tokio::test]
+    async fn rejects_unverified() {
+        let verify = config::Verify {
+            port: Some(8080),
+            ..Default::default()
+        };
+        let mut harness = harness(verify, Default::default());
+        harness
+            .events
+            .send(Event::Resolved(announcement(
+                "spoofed._http._tcp.local.",
+                80,
+            )))
+            .await
+            .unwrap();
+        let discovered = harness
+            .send(Event::Resolved(announcement(
+                "eclss._http._tcp.local.",
+                8080,
+            )))
+            .await
+            .expect("verified announcement must resolve");
+        assert_eq!(discovered.addr.port(), 8080);
+    }
+
+    fn seed(addr: SocketAddr) -> Discovered {
+        let mut seed = Discovered::new(addr, http::uri::Authority::from_static(NAME));
+        seed.stale = true;
+        seed
+    }
+
+    #[test]
+    fn verifies_seeds() {
+        let interfaces = Interfaces::from_config(&config::Discovery::default())
+            .expect("host must have network interfaces");
+        let local = SocketAddr::new(announcement("eclss._http._tcp.local.", 80).addrs[0], 80);
+        let public = "8.8.8.8:80".parse().unwrap();
+
+        let mut replicated = seed(local);
+        replicated.replicas = vec![public, local];
+        let verified = replicated
+            .verify_seed(&interfaces, &Default::default())
+            .expect("seed on a local interface must be accepted");
+        assert_eq!(verified.replicas, vec![local]);
+
+        assert_eq!(
+            seed(public).verify_seed(&interfaces, &Default::default()),
+            Err(Rejected::Interface(vec![public.ip()]))
+        );
+        let verify = config::Verify {
+            port: Some(8080),
+            ..Default::default()
+        };
+        assert!(seed(local).verify_seed(&interfaces, &verify).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_seeds() {
+        let addr = "127.0.0.1:80".parse().unwrap();
+        let (tx, rx) = watch::channel(Some(seed(addr)));
+        let tx = Arc::new(tx);
+        expire_seed(tx.clone(), Name::from(NAME), Duration::from_secs(60)).await;
+        assert_eq!(*rx.borrow(), None);
+
+        // A live announcement replaces the seed, so it isn't expired.
+        tx.send_replace(Some(Discovered::new(
+            addr,
+            http::uri::Authority::from_static(NAME),
+        )));
+        expire_seed(tx.clone(), Name::from(NAME), Duration::from_secs(60)).await;
+        assert!(rx.borrow().is_some());
+    }
+}
@@ -0,0 +1,102 @@
+use super::is_pattern;
+use crate::config::{Advertise, Config};
+use anyhow::Context;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// Registers the gateway's own services, and any per-service aliases, with
+/// the mDNS daemon.
+pub(super) fn register(
+    daemon: &ServiceDaemon,
+    config: &Config,
+    advertise: &Advertise,
+) -> anyhow::Result<()> {
+    let local_tld = &config.local_tld;
+    let hostname = advertise.qualified_hostname(local_tld);
+    let listeners = [
+        (advertise.http, "_http._tcp", config.listeners.http),
+        (advertise.https, "_https._tcp", config.listeners.https),
+    ];
+    for (_, service, listen) in listeners.into_iter().filter(|(enabled, ..)| *enabled) {
+        let addrs = gateway_addrs(listen)?;
+        let ty_domain = format!("{service}.{local_tld}.");
+        register_one(
+            daemon,
+            &ty_domain,
+            &advertise.instance,
+            &hostname,
+            &addrs,
+            listen.port(),
+        )?;
+
+        if advertise.aliases {
+            // Services named by a pattern have no single name to alias.
+            for name in config.services.keys().filter(|name| !is_pattern(name)) {
+                let alias = advertise.alias_for(name, local_tld);
+                let instance = alias
+                    .trim_end_matches('.')
+                    .trim_end_matches(local_tld.as_str());
+                let instance = instance.trim_end_matches('.');
+                register_one(daemon, &ty_domain, instance, &alias, &addrs, listen.port())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn register_one(
+    daemon: &ServiceDaemon,
+    ty_domain: &str,
+    instance: &str,
+    hostname: &str,
+    addrs: &str,
+    port: u16,
+) -> anyhow::Result<()> {
+    let info = ServiceInfo::new(ty_domain, instance, hostname, addrs, port, None)
+        .with_context(|| format!("invalid mDNS service info for {instance}.{ty_domain}"))?;
+    daemon
+        .register(info)
+        .with_context(|| format!("failed to register {instance}.{ty_domain}"))?;
+    tracing::info!(
+        service = ty_domain,
+        instance,
+        hostname,
+        addrs,
+        port,
+        "Advertising gateway"
+    );
+    Ok(())
+}
+
+/// Returns the IPv4 addresses that clients can reach `listen` on, formatted as
+/// a comma-separated list.
+///
+/// If the listener is bound to the unspecified address, this is every
+/// non-loopback IPv4 address on the host.
+fn gateway_addrs(listen: SocketAddr) -> anyhow::Result<String> {
+    let addrs: Vec<Ipv4Addr> = match listen.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => vec![ip],
+        IpAddr::V4(_) => if_addrs::get_if_addrs()
+            .context("failed to list network interfaces")?
+            .into_iter()
+            .filter(|iface| !iface.is_loopback())
+            .filter_map(|iface| match iface.ip() {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            })
+            .collect(),
+        IpAddr::V6(_) => anyhow::bail!(
+            "cannot advertise IPv6 listener {listen}: mDNS advertisements are IPv4-only"
+        ),
+    };
+    anyhow::ensure!(
+        !addrs.is_empty(),
+        "no IPv4 addresses to advertise for listener {listen}"
+    );
+    Ok(addrs
+        .iter()
+        .map(Ipv4Addr::to_string)
+        .collect::<Vec<_>>()
+        .join(","))
+}
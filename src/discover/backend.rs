@@ -0,0 +1,297 @@
+//! mDNS implementations that [`super::MdnsDiscover`] can browse with.
+//!
+//! Each backend translates its library's announcements into a stream of
+//! [`Event`]s, so that interface filtering, verification, and conflict
+//! handling are shared between them.
+use super::{Interfaces, Name};
+use crate::config::{self, Config};
+use std::{collections::HashMap, net::IpAddr};
+use tokio::sync::mpsc;
+
+#[cfg(feature = "mdns-sd")]
+mod sd;
+#[cfg(feature = "simple-mdns")]
+mod simple;
+
+#[cfg(not(any(feature = "mdns-sd", feature = "simple-mdns")))]
+compile_error!("at least one of the `mdns-sd` or `simple-mdns` features must be enabled");
+
+#[derive(Clone)]
+pub(super) enum Backend {
+    #[cfg(feature = "mdns-sd")]
+    MdnsSd(mdns_sd::ServiceDaemon),
+    #[cfg(feature = "simple-mdns")]
+    SimpleMdns,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) enum Event {
+    Resolved(Announcement),
+    Removed { fullname: String },
+}
+
+/// A resolved mDNS service instance.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(super) struct Announcement {
+    /// The instance's full name, such as `eclss._http._tcp.local.`.
+    pub(super) fullname: String,
+    /// The hostname the instance resolved to, such as `eclss.local.`.
+    pub(super) hostname: String,
+    pub(super) addrs: Vec<IpAddr>,
+    pub(super) port: u16,
+    pub(super) txt: HashMap<String, String>,
+}
+
+/// A configured service that a backend browses for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) struct Target {
+    /// The service's name, such as `eclss.local.`.
+    pub(super) hostname: Name,
+    /// The instance name configured for backends that can't browse, if any.
+    pub(super) instance: Option<String>,
+}
+
+/// The number of events buffered between a backend and the task that
+/// publishes them to watches.
+const EVENT_BUFFER: usize = 64;
+
+// === impl Backend ===
+
+impl Backend {
+    /// Starts the configured backend, listening only on `interfaces` where the
+    /// backend allows it.
+    pub(super) fn new(config: &config::Discovery, interfaces: &Interfaces) -> anyhow::Result<Self> {
+        tracing::info!(backend = ?config.backend, "Starting mDNS backend");
+        match config.backend {
+            #[cfg(feature = "mdns-sd")]
+            config::MdnsBackend::MdnsSd => Ok(Self::MdnsSd(sd::daemon(interfaces)?)),
+            #[cfg(feature = "simple-mdns")]
+            config::MdnsBackend::SimpleMdns => {
+                // `simple-mdns` can't be bound to interfaces, so only the
+                // subnet filter applies to its answers.
+                let _ = interfaces;
+                Ok(Self::SimpleMdns)
+            }
+            #[allow(unreachable_patterns)]
+            backend => anyhow::bail!(
+                "mDNS backend {backend:?} is not enabled; rebuild multipass with the `{}` feature",
+                backend.feature()
+            ),
+        }
+    }
+
+    /// Browses for instances of `service_type` that resolve to one of
+    /// `targets`.
+    pub(super) fn browse(
+        &self,
+        service_type: &str,
+        targets: Vec<Target>,
+    ) -> anyhow::Result<mpsc::Receiver<Event>> {
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+        match self {
+            #[cfg(feature = "mdns-sd")]
+            Self::MdnsSd(daemon) => {
+                let _ = targets;
+                sd::browse(daemon, service_type, tx)?
+            }
+            #[cfg(feature = "simple-mdns")]
+            Self::SimpleMdns => simple::browse(service_type, targets, tx)?,
+        }
+        Ok(rx)
+    }
+
+    /// Re-announces instances of the reflector's service types between its
+    /// interfaces.
+    ///
+    /// The reflector uses its own `mdns-sd` daemons, so that its browses don't
+    /// replace discovery's browses for the same service types, and so that
+    /// reflections are only announced on the interfaces they're meant for.
+    pub(super) fn reflect(
+        &self,
+        local_tld: &str,
+        reflector: &config::Reflector,
+    ) -> anyhow::Result<()> {
+        match self {
+            #[cfg(feature = "mdns-sd")]
+            Self::MdnsSd(_) => {
+                let (browser, announcers) = super::reflect::daemons(reflector)?;
+                for service_type in &reflector.service_types {
+                    let service_type = format!("{service_type}.{local_tld}.");
+                    let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+                    sd::browse(&browser, &service_type, tx)?;
+                    super::reflect::spawn(announcers.clone(), reflector, service_type, rx)?;
+                }
+                Ok(())
+            }
+            #[cfg(feature = "simple-mdns")]
+            Self::SimpleMdns => {
+                let _ = (local_tld, reflector);
+                anyhow::bail!("the mDNS reflector requires the `mdns-sd` backend")
+            }
+        }
+    }
+
+    /// Advertises the gateway itself over mDNS.
+    pub(super) fn advertise(
+        &self,
+        config: &Config,
+        advertise: &config::Advertise,
+    ) -> anyhow::Result<()> {
+        match self {
+            #[cfg(feature = "mdns-sd")]
+            Self::MdnsSd(daemon) => super::advertise::register(daemon, config, advertise),
+            #[cfg(feature = "simple-mdns")]
+            Self::SimpleMdns => {
+                let _ = (config, advertise);
+                anyhow::bail!("advertising the gateway requires the `mdns-sd` backend")
+            }
+        }
+    }
+}
+
+/// A test suite that every backend must pass.
+///
+/// Each backend's library is replaced by a fake that answers from the same
+/// scripted announcement, so that the suite runs without multicast
+/// networking, while still exercising the backend's own browse loop.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::{Ipv4Addr, SocketAddrV4},
+        time::Duration,
+    };
+    use tokio::sync::watch;
+
+    const SERVICE_TYPE: &str = "_http._tcp.local.";
+    const HOSTNAME: &str = "eclss.local.";
+    const FULLNAME: &str = "eclss._http._tcp.local.";
+
+    /// The address `eclss._http._tcp.local.` is currently announced at on
+    /// the fake network, if it is announced at all.
+    type Announced = watch::Receiver<Option<SocketAddrV4>>;
+
+    macro_rules! backend_tests {
+        ($($(#[$attr:meta])* $name:ident => $browse:path),+ $(,)?) => {
+            $(
+                $(#[$attr])*
+                mod $name {
+                    #[tokio::test(start_paused = true)]
+                    async fn resolves_moves_and_removes() {
+                        super::resolves_moves_and_removes($browse).await
+                    }
+                }
+            )+
+        };
+    }
+
+    backend_tests! {
+        #[cfg(feature = "mdns-sd")]
+        with_mdns_sd => super::fake_mdns_sd,
+        #[cfg(feature = "simple-mdns")]
+        with_simple_mdns => super::fake_simple_mdns,
+    }
+
+    /// Feeds `mdns-sd` browse events for each change to the announcement.
+    #[cfg(feature = "mdns-sd")]
+    fn fake_mdns_sd(announced: Announced) -> mpsc::Receiver<Event> {
+        use ::mdns_sd::{ServiceEvent, ServiceInfo};
+
+        let events = futures::stream::unfold(announced, |mut announced| async move {
+            announced.changed().await.ok()?;
+            let addr = *announced.borrow_and_update();
+            let event = match addr {
+                Some(addr) => ServiceEvent::ServiceResolved(
+                    ServiceInfo::new(
+                        SERVICE_TYPE,
+                        "eclss",
+                        HOSTNAME,
+                        *addr.ip(),
+                        addr.port(),
+                        None,
+                    )
+                    .unwrap(),
+                ),
+                None => {
+                    ServiceEvent::ServiceRemoved(SERVICE_TYPE.to_string(), FULLNAME.to_string())
+                }
+            };
+            Some((event, announced))
+        });
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+        sd::spawn_browse(events, SERVICE_TYPE, tx);
+        rx
+    }
+
+    /// Answers `simple-mdns` queries from the current announcement.
+    #[cfg(feature = "simple-mdns")]
+    fn fake_simple_mdns(announced: Announced) -> mpsc::Receiver<Event> {
+        struct Fake(Announced);
+
+        impl simple::Resolve for Fake {
+            fn resolve<'a>(
+                &'a self,
+                instance: &'a str,
+            ) -> futures::future::BoxFuture<'a, anyhow::Result<Option<std::net::SocketAddr>>>
+            {
+                let announced = *self.0.borrow();
+                let addr = announced.filter(|_| instance == FULLNAME).map(Into::into);
+                Box::pin(futures::future::ready(Ok(addr)))
+            }
+        }
+
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+        let target = Target {
+            hostname: Name::from(HOSTNAME),
+            instance: None,
+        };
+        simple::spawn_poll(Fake(announced), SERVICE_TYPE, vec![target], tx);
+        rx
+    }
+
+    async fn resolves_moves_and_removes(browse: fn(Announced) -> mpsc::Receiver<Event>) {
+        crate::test_util::trace_init();
+        let (announce, announced) = watch::channel(None);
+        let mut events = browse(announced);
+        let resolved = |addr: SocketAddrV4| {
+            Event::Resolved(Announcement {
+                fullname: FULLNAME.to_string(),
+                hostname: HOSTNAME.to_string(),
+                addrs: vec![IpAddr::V4(*addr.ip())],
+                port: addr.port(),
+                txt: HashMap::new(),
+            })
+        };
+
+        let addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 10), 80);
+        announce.send_replace(Some(addr));
+        assert_eq!(next_event(&mut events).await, resolved(addr));
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(
+            events.try_recv().is_err(),
+            "an unchanged announcement is only published once"
+        );
+
+        let moved = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 11), 80);
+        announce.send_replace(Some(moved));
+        assert_eq!(next_event(&mut events).await, resolved(moved));
+
+        announce.send_replace(None);
+        assert_eq!(
+            next_event(&mut events).await,
+            Event::Removed {
+                fullname: FULLNAME.to_string()
+            }
+        );
+    }
+
+    async fn next_event(events: &mut mpsc::Receiver<Event>) -> Event {
+        let event = tokio::time::timeout(Duration::from_secs(60), events.recv())
+            .await
+            .expect("timed out waiting for event")
+            .expect("backend must not stop");
+        tracing::info!(?event);
+        event
+    }
+}
@@ -0,0 +1,134 @@
+use super::{Announcement, Event};
+use crate::discover::Interfaces;
+use anyhow::Context;
+use futures::{Stream, StreamExt};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent};
+use std::net::IpAddr;
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+/// Returns a daemon that only sends and receives on the addresses of the
+/// allowed `interfaces`.
+pub(super) fn daemon(interfaces: &Interfaces) -> anyhow::Result<ServiceDaemon> {
+    let daemon = ServiceDaemon::new()?;
+    daemon.disable_interface(IfKind::All)?;
+    for ip in interfaces.addrs() {
+        daemon
+            .enable_interface(IfKind::Addr(ip))
+            .with_context(|| format!("failed to bind mDNS daemon to {ip}"))?;
+    }
+    Ok(daemon)
+}
+
+/// Browses for `service_type` using the `mdns-sd` daemon.
+pub(super) fn browse(
+    daemon: &ServiceDaemon,
+    service_type: &str,
+    tx: mpsc::Sender<Event>,
+) -> anyhow::Result<()> {
+    let browse = daemon
+        .browse(service_type)
+        .with_context(|| format!("Failed to browse for {service_type}"))?;
+    spawn_browse(browse.into_stream(), service_type, tx);
+    Ok(())
+}
+
+/// Publishes the [`Event`]s for a stream of `mdns-sd` browse events, until
+/// either the daemon or discovery stops.
+pub(super) fn spawn_browse(
+    events: impl Stream<Item = ServiceEvent> + Send + 'static,
+    service_type: &str,
+    tx: mpsc::Sender<Event>,
+) {
+    tokio::spawn(
+        async move {
+            tracing::info!("Starting to browse...");
+            let mut events = std::pin::pin!(events);
+            while let Some(event) = events.next().await {
+                tracing::trace!(?event);
+                let Some(event) = to_event(event) else {
+                    continue;
+                };
+                if tx.send(event).await.is_err() {
+                    tracing::debug!("Discovery stopped, no longer browsing");
+                    return;
+                }
+            }
+            tracing::error!("mDNS daemon stopped browsing");
+        }
+        .instrument(tracing::info_span!("browse", backend = "mdns-sd", message = %service_type)),
+    );
+}
+
+/// Translates an `mdns-sd` browse event, returning `None` for events that
+/// don't change which instances are resolved.
+fn to_event(event: ServiceEvent) -> Option<Event> {
+    match event {
+        ServiceEvent::ServiceResolved(info) => Some(Event::Resolved(Announcement {
+            fullname: info.get_fullname().to_string(),
+            hostname: info.get_hostname().to_string(),
+            addrs: info
+                .get_addresses()
+                .iter()
+                .map(|ip| IpAddr::V4(*ip))
+                .collect(),
+            port: info.get_port(),
+            txt: info
+                .get_properties()
+                .iter()
+                .map(|prop| (prop.key().to_string(), prop.val_str().to_string()))
+                .collect(),
+        })),
+        ServiceEvent::ServiceRemoved(_, fullname) => Some(Event::Removed { fullname }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, net::Ipv4Addr};
+
+    const SERVICE_TYPE: &str = "_http._tcp.local.";
+
+    #[test]
+    fn events() {
+        let ip = Ipv4Addr::new(192, 168, 1, 10);
+        let txt = [("secret".to_string(), "hunter2".to_string())]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let info = mdns_sd::ServiceInfo::new(
+            SERVICE_TYPE,
+            "eclss",
+            "eclss.local.",
+            ip,
+            80,
+            Some(txt.clone()),
+        )
+        .unwrap();
+        assert_eq!(
+            to_event(ServiceEvent::ServiceResolved(info)),
+            Some(Event::Resolved(Announcement {
+                fullname: "eclss._http._tcp.local.".to_string(),
+                hostname: "eclss.local.".to_string(),
+                addrs: vec![IpAddr::V4(ip)],
+                port: 80,
+                txt,
+            }))
+        );
+
+        assert_eq!(
+            to_event(ServiceEvent::ServiceRemoved(
+                SERVICE_TYPE.to_string(),
+                "eclss._http._tcp.local.".to_string()
+            )),
+            Some(Event::Removed {
+                fullname: "eclss._http._tcp.local.".to_string()
+            })
+        );
+        assert_eq!(
+            to_event(ServiceEvent::SearchStarted(SERVICE_TYPE.to_string())),
+            None
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use super::{Announcement, Event, Target};
+use crate::discover::Name;
+use anyhow::Context;
+use futures::future::BoxFuture;
+use simple_mdns::async_discovery::OneShotMdnsResolver;
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+/// How often each configured instance is queried.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many consecutive queries may go unanswered before a previously
+/// resolved instance is considered removed.
+const MAX_MISSES: usize = 3;
+
+/// The last answer for an instance that is currently resolved.
+#[derive(Debug)]
+struct Polled {
+    addr: SocketAddr,
+
+    /// The number of consecutive queries that went unanswered since `addr`
+    /// was last seen.
+    misses: usize,
+}
+
+/// Answers queries for the address of a single instance.
+///
+/// This is implemented by the `simple-mdns` resolver, and by fakes in tests.
+pub(super) trait Resolve: Send + Sync + 'static {
+    fn resolve<'a>(
+        &'a self,
+        instance: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Option<SocketAddr>>>;
+}
+
+/// Browses for `service_type` using `simple-mdns`.
+///
+/// `simple-mdns` does not expose a browse stream, so this polls for each of
+/// the configured `targets` instead, querying for its configured instance
+/// name, or else the first label of its hostname (e.g.
+/// `eclss._http._tcp.local.` for `eclss.local.`). TXT records are not
+/// available from these queries, so announcements have no TXT properties, and
+/// configs that verify TXT records are rejected for this backend.
+pub(super) fn browse(
+    service_type: &str,
+    targets: Vec<Target>,
+    tx: mpsc::Sender<Event>,
+) -> anyhow::Result<()> {
+    let resolver = OneShotMdnsResolver::new().context("failed to start simple-mdns resolver")?;
+    spawn_poll(resolver, service_type, targets, tx);
+    Ok(())
+}
+
+/// Polls `resolver` for each of `targets`, publishing an [`Event`] whenever
+/// an instance is resolved, moves, or stops answering.
+pub(super) fn spawn_poll(
+    resolver: impl Resolve,
+    service_type: &str,
+    targets: Vec<Target>,
+    tx: mpsc::Sender<Event>,
+) {
+    let instances = targets
+        .into_iter()
+        .map(|Target { hostname, instance }| {
+            let label = instance
+                .as_deref()
+                .unwrap_or_else(|| hostname.split('.').next().unwrap_or_default());
+            let instance = format!("{label}.{service_type}");
+            (instance, hostname)
+        })
+        .collect::<Vec<_>>();
+    tokio::spawn(
+        async move {
+            tracing::info!("Starting to poll...");
+            let mut polled = HashMap::new();
+            let mut interval = tokio::time::interval(POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                for (instance, hostname) in &instances {
+                    let addr = match resolver.resolve(instance).await {
+                        Ok(addr) => addr,
+                        Err(error) => {
+                            tracing::warn!(instance, %error, "Failed to query instance");
+                            continue;
+                        }
+                    };
+                    let Some(event) = poll_event(&mut polled, instance, hostname, addr) else {
+                        continue;
+                    };
+                    tracing::trace!(?event);
+                    if tx.send(event).await.is_err() {
+                        tracing::debug!("Discovery stopped, no longer polling");
+                        return;
+                    }
+                }
+            }
+        }
+        .instrument(
+            tracing::info_span!("browse", backend = "simple-mdns", message = %service_type),
+        ),
+    );
+}
+
+/// Returns the event for the answer to a query for `instance`, if it changes
+/// whether or where the instance is resolved.
+///
+/// `polled` holds the last answer for each instance that is resolved.
+fn poll_event(
+    polled: &mut HashMap<String, Polled>,
+    instance: &str,
+    hostname: &Name,
+    addr: Option<SocketAddr>,
+) -> Option<Event> {
+    match addr {
+        Some(addr) => {
+            let previous = polled.insert(instance.to_string(), Polled { addr, misses: 0 });
+            if previous.is_some_and(|previous| previous.addr == addr) {
+                return None;
+            }
+            Some(Event::Resolved(Announcement {
+                fullname: instance.to_string(),
+                hostname: hostname.to_string(),
+                addrs: vec![addr.ip()],
+                port: addr.port(),
+                txt: HashMap::new(),
+            }))
+        }
+        None => {
+            let Polled { misses, .. } = polled.get_mut(instance)?;
+            *misses += 1;
+            if *misses < MAX_MISSES {
+                return None;
+            }
+            polled.remove(instance);
+            Some(Event::Removed {
+                fullname: instance.to_string(),
+            })
+        }
+    }
+}
+
+// === impl OneShotMdnsResolver ===
+
+impl Resolve for OneShotMdnsResolver {
+    fn resolve<'a>(
+        &'a self,
+        instance: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Option<SocketAddr>>> {
+        Box::pin(async move { Ok(self.query_service_address_and_port(instance).await?) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_and_removes() {
+        let mut polled = HashMap::new();
+        let instance = "eclss._http._tcp.local.";
+        let hostname = Name::from("eclss.local.");
+        assert_eq!(
+            poll_event(&mut polled, instance, &hostname, None),
+            None,
+            "instances that were never resolved aren't removed"
+        );
+
+        let addr = SocketAddr::from(([192, 168, 1, 10], 80));
+        assert_eq!(
+            poll_event(&mut polled, instance, &hostname, Some(addr)),
+            Some(Event::Resolved(Announcement {
+                fullname: instance.to_string(),
+                hostname: hostname.to_string(),
+                addrs: vec![addr.ip()],
+                port: 80,
+                txt: HashMap::new(),
+            }))
+        );
+        assert_eq!(
+            poll_event(&mut polled, instance, &hostname, Some(addr)),
+            None,
+            "unchanged answers aren't published again"
+        );
+
+        assert_eq!(poll_event(&mut polled, instance, &hostname, None), None);
+        let moved = SocketAddr::from(([192, 168, 1, 11], 80));
+        assert_eq!(
+            poll_event(&mut polled, instance, &hostname, Some(moved)),
+            Some(Event::Resolved(Announcement {
+                fullname: instance.to_string(),
+                hostname: hostname.to_string(),
+                addrs: vec![moved.ip()],
+                port: 80,
+                txt: HashMap::new(),
+            }))
+        );
+
+        for _ in 1..MAX_MISSES {
+            assert_eq!(poll_event(&mut polled, instance, &hostname, None), None);
+        }
+        assert_eq!(
+            poll_event(&mut polled, instance, &hostname, None),
+            Some(Event::Removed {
+                fullname: instance.to_string()
+            })
+        );
+        assert_eq!(
+            poll_event(&mut polled, instance, &hostname, None),
+            None,
+            "instances are only removed once"
+        );
+        assert!(
+            poll_event(&mut polled, instance, &hostname, Some(moved)).is_some(),
+            "removed instances are published again when they reappear"
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use super::{Discovered, Name};
+use crate::config::ConflictPolicy;
+use ahash::AHashMap;
+use linkerd_app_core::metrics::{metrics, Counter, FmtLabels, FmtMetrics, Gauge};
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+metrics! {
+    mdns_conflicts_active: Gauge {
+        "Whether the sources announcing a service currently announce different addresses"
+    },
+    mdns_conflicts_detected_total: Counter {
+        "The number of times that conflicting mDNS announcements were detected for a service"
+    }
+}
+
+/// Conflict metrics for every service that has been announced, exported on
+/// the admin server.
+#[derive(Clone, Debug, Default)]
+pub struct ConflictMetrics {
+    services: Arc<Mutex<AHashMap<Name, Arc<ServiceMetrics>>>>,
+}
+
+#[derive(Debug, Default)]
+struct ServiceMetrics {
+    active: Gauge,
+    detected: Counter,
+}
+
+struct ServiceLabel<'a>(&'a str);
+
+/// Tracks every mDNS source announcing a configured service name, and selects
+/// which of them to route to according to a [`ConflictPolicy`].
+#[derive(Debug)]
+pub(super) struct Sources {
+    name: Name,
+    policy: ConflictPolicy,
+    sources: Vec<Source>,
+
+    /// Whether the sources currently announce different addresses.
+    conflicted: bool,
+
+    metrics: Arc<ServiceMetrics>,
+
+    /// Incremented for each announcement, to order sources by when they were
+    /// seen.
+    seq: u64,
+}
+
+#[derive(Debug)]
+struct Source {
+    /// The mDNS instance name of the source.
+    fullname: String,
+    discovered: Discovered,
+    first_seen: u64,
+    last_seen: u64,
+}
+
+// === impl ConflictMetrics ===
+
+impl ConflictMetrics {
+    fn service(&self, name: &Name) -> Arc<ServiceMetrics> {
+        let mut services = self.services.lock().unwrap();
+        services.entry(name.clone()).or_default().clone()
+    }
+}
+
+impl FmtMetrics for ConflictMetrics {
+    fn fmt_metrics(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let services = self.services.lock().unwrap();
+        if services.is_empty() {
+            return Ok(());
+        }
+        let mut services = services.iter().collect::<Vec<_>>();
+        services.sort_by_key(|(name, _)| *name);
+
+        mdns_conflicts_active.fmt_help(f)?;
+        mdns_conflicts_active.fmt_scopes(
+            f,
+            services
+                .iter()
+                .map(|(name, metrics)| (ServiceLabel(name), metrics.as_ref())),
+            |metrics| &metrics.active,
+        )?;
+        mdns_conflicts_detected_total.fmt_help(f)?;
+        mdns_conflicts_detected_total.fmt_scopes(
+            f,
+            services
+                .iter()
+                .map(|(name, metrics)| (ServiceLabel(name), metrics.as_ref())),
+            |metrics| &metrics.detected,
+        )
+    }
+}
+
+impl FmtLabels for ServiceLabel<'_> {
+    fn fmt_labels(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "service=\"{}\"", self.0)
+    }
+}
+
+// === impl Sources ===
+
+impl Sources {
+    pub(super) fn new(name: Name, policy: ConflictPolicy, metrics: &ConflictMetrics) -> Self {
+        Self {
+            metrics: metrics.service(&name),
+            name,
+            policy,
+            sources: Vec::new(),
+            conflicted: false,
+            seq: 0,
+        }
+    }
+
+    /// Records an announcement from `fullname`, returning the endpoint that
+    /// should now be routed to.
+    pub(super) fn resolved(
+        &mut self,
+        fullname: &str,
+        discovered: Discovered,
+    ) -> Option<Discovered> {
+        self.seq += 1;
+        let now = self.seq;
+        match self.sources.iter_mut().find(|s| s.fullname == fullname) {
+            Some(source) => {
+                source.discovered = discovered;
+                source.last_seen = now;
+            }
+            None => self.sources.push(Source {
+                fullname: fullname.to_string(),
+                discovered,
+                first_seen: now,
+                last_seen: now,
+            }),
+        }
+        self.update_conflicted();
+        self.select()
+    }
+
+    /// Records that `fullname` was removed, returning `None` if it was not a
+    /// source for this name, or the endpoint that should now be routed to.
+    pub(super) fn removed(&mut self, fullname: &str) -> Option<Option<Discovered>> {
+        let idx = self.sources.iter().position(|s| s.fullname == fullname)?;
+        self.sources.remove(idx);
+        self.update_conflicted();
+        Some(self.select())
+    }
+
+    fn is_conflicted(&self) -> bool {
+        let mut addrs = self.sources.iter().map(|s| s.discovered.addr);
+        match addrs.next() {
+            Some(first) => addrs.any(|addr| addr != first),
+            None => false,
+        }
+    }
+
+    fn update_conflicted(&mut self) {
+        let conflicted = self.is_conflicted();
+        let was_conflicted = std::mem::replace(&mut self.conflicted, conflicted);
+        if conflicted && !was_conflicted {
+            self.metrics.active.incr();
+            self.metrics.detected.incr();
+            let sources = self
+                .sources
+                .iter()
+                .map(|s| format!("{} ({})", s.fullname, s.discovered.addr))
+                .collect::<Vec<_>>();
+            tracing::warn!(
+                service = %self.name,
+                ?sources,
+                policy = ?self.policy,
+                conflicts.total = self.metrics.detected.value(),
+                "Conflicting mDNS announcements detected"
+            );
+        } else if was_conflicted && !conflicted {
+            self.metrics.active.decr();
+            tracing::info!(service = %self.name, "mDNS announcement conflict resolved");
+        }
+    }
+
+    fn select(&self) -> Option<Discovered> {
+        let first = self.sources.iter().min_by_key(|s| s.first_seen)?;
+        if !self.is_conflicted() {
+            return Some(first.discovered.clone());
+        }
+
+        match self.policy {
+            ConflictPolicy::KeepFirst => Some(first.discovered.clone()),
+            ConflictPolicy::KeepNewest => self
+                .sources
+                .iter()
+                .max_by_key(|s| s.last_seen)
+                .map(|s| s.discovered.clone()),
+            ConflictPolicy::Refuse => None,
+            ConflictPolicy::Balance => {
+                let mut discovered = first.discovered.clone();
+                let mut replicas = self
+                    .sources
+                    .iter()
+                    .map(|s| s.discovered.addr)
+                    .filter(|addr| *addr != discovered.addr)
+                    .collect::<Vec<_>>();
+                replicas.sort();
+                replicas.dedup();
+                discovered.replicas = replicas;
+                Some(discovered)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn discovered(port: u16) -> Discovered {
+        Discovered::new(
+            SocketAddr::from(([192, 168, 1, 10], port)),
+            "eclss.local".parse().unwrap(),
+        )
+    }
+
+    fn sources(policy: ConflictPolicy) -> Sources {
+        let mut sources = Sources::new("eclss.local.".into(), policy, &Default::default());
+        assert_eq!(sources.resolved("a", discovered(1)), Some(discovered(1)));
+        // Re-announcing from the same source is not a conflict.
+        assert_eq!(sources.resolved("a", discovered(1)), Some(discovered(1)));
+        assert!(!sources.conflicted);
+        sources
+    }
+
+    #[test]
+    fn keep_first() {
+        let mut sources = sources(ConflictPolicy::KeepFirst);
+        assert_eq!(sources.resolved("b", discovered(2)), Some(discovered(1)));
+        assert!(sources.conflicted);
+        assert_eq!(sources.metrics.detected.value(), 1);
+
+        assert_eq!(sources.removed("a"), Some(Some(discovered(2))));
+        assert!(!sources.conflicted);
+        assert_eq!(sources.removed("c"), None);
+    }
+
+    #[test]
+    fn keep_newest() {
+        let mut sources = sources(ConflictPolicy::KeepNewest);
+        assert_eq!(sources.resolved("b", discovered(2)), Some(discovered(2)));
+        assert_eq!(sources.resolved("a", discovered(1)), Some(discovered(1)));
+        assert_eq!(sources.metrics.detected.value(), 1);
+    }
+
+    #[test]
+    fn refuse() {
+        let mut sources = sources(ConflictPolicy::Refuse);
+        assert_eq!(sources.resolved("b", discovered(2)), None);
+        assert_eq!(sources.removed("b"), Some(Some(discovered(1))));
+    }
+
+    #[test]
+    fn balance() {
+        let mut sources = sources(ConflictPolicy::Balance);
+        let balanced = sources.resolved("b", discovered(2)).unwrap();
+        assert_eq!(balanced.addr, discovered(1).addr);
+        assert_eq!(balanced.replicas, vec![discovered(2).addr]);
+    }
+
+    #[test]
+    fn metrics() {
+        let metrics = ConflictMetrics::default();
+        let mut eclss = Sources::new("eclss.local.".into(), ConflictPolicy::KeepFirst, &metrics);
+        let _grafana = Sources::new("grafana.local.".into(), ConflictPolicy::KeepFirst, &metrics);
+        eclss.resolved("a", discovered(1));
+        eclss.resolved("b", discovered(2));
+        eclss.removed("b");
+        eclss.resolved("b", discovered(2));
+
+        let rendered = metrics.as_display().to_string();
+        assert!(rendered.contains("mdns_conflicts_active{service=\"eclss.local.\"} 1\n"));
+        assert!(rendered.contains("mdns_conflicts_active{service=\"grafana.local.\"} 0\n"));
+        assert!(
+            rendered.contains("mdns_conflicts_detected_total{service=\"eclss.local.\"} 2\n"),
+            "{rendered}"
+        );
+
+        eclss.removed("b");
+        let rendered = metrics.as_display().to_string();
+        assert!(rendered.contains("mdns_conflicts_active{service=\"eclss.local.\"} 0\n"));
+    }
+}
@@ -0,0 +1,137 @@
+use super::{Discovered, Name};
+use ahash::AHashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// The placeholder in a service name that is replaced with the label matched
+/// by a wildcard host, so that the service `{label}.local.` routes
+/// `foo.lab.example.com` to `foo.local.`.
+pub const LABEL: &str = "{label}";
+
+/// Watches for service names that match a pattern, such as `{label}.local.`.
+///
+/// Unlike configured names, these are not known ahead of time, so their
+/// watches are created when a name is first requested or announced.
+#[derive(Clone, Debug, Default)]
+pub(super) struct Dynamic {
+    patterns: Arc<[Name]>,
+    watches: Arc<Mutex<AHashMap<Name, Arc<watch::Sender<Option<Discovered>>>>>>,
+}
+
+/// Returns `true` if `name` contains the [`LABEL`] placeholder.
+pub fn is_pattern(name: &str) -> bool {
+    name.contains(LABEL)
+}
+
+/// Replaces the [`LABEL`] placeholder in `pattern` with `label`.
+pub fn expand_pattern(pattern: &str, label: &str) -> Name {
+    Name::from(pattern.replacen(LABEL, &label.to_ascii_lowercase(), 1))
+}
+
+/// Returns `true` if `name` is `pattern` with its placeholder replaced by a
+/// single DNS label.
+pub(super) fn pattern_matches(pattern: &str, name: &str) -> bool {
+    let Some((prefix, suffix)) = pattern.split_once(LABEL) else {
+        return pattern.eq_ignore_ascii_case(name);
+    };
+    if name.len() <= prefix.len() + suffix.len() {
+        return false;
+    }
+
+    let (Some(head), Some(tail)) = (
+        name.get(..prefix.len()),
+        name.get(name.len() - suffix.len()..),
+    ) else {
+        return false;
+    };
+    let label = &name[prefix.len()..name.len() - suffix.len()];
+    head.eq_ignore_ascii_case(prefix) && tail.eq_ignore_ascii_case(suffix) && !label.contains('.')
+}
+
+// === impl Dynamic ===
+
+impl Dynamic {
+    pub(super) fn new(patterns: impl IntoIterator<Item = Name>) -> Self {
+        Self {
+            patterns: patterns.into_iter().collect(),
+            watches: Default::default(),
+        }
+    }
+
+    /// Returns the watch for `name`, creating it if necessary, or `None` if
+    /// `name` doesn't match any pattern.
+    pub(super) fn watch(&self, name: &str) -> Option<Arc<watch::Sender<Option<Discovered>>>> {
+        if !self.patterns.iter().any(|p| pattern_matches(p, name)) {
+            return None;
+        }
+
+        let name = Name::from(name.to_ascii_lowercase());
+        let mut watches = self.watches.lock().unwrap();
+        if let Some(tx) = watches.get(&name) {
+            return Some(tx.clone());
+        }
+
+        // Any client can make up a host that matches a pattern, so drop the
+        // watches that nothing uses anymore rather than keeping them forever.
+        watches.retain(|name, tx| {
+            let unused = tx.receiver_count() == 0 && Arc::strong_count(tx) == 1;
+            if unused {
+                tracing::trace!(service = %name, "Dropping unused watch");
+            }
+            !unused
+        });
+        tracing::debug!(service = %name, "Watching service matching a pattern");
+        let tx = Arc::new(watch::channel(None).0);
+        watches.insert(name, tx.clone());
+        Some(tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert!(is_pattern("{label}.local."));
+        assert!(!is_pattern("eclss.local."));
+
+        assert!(pattern_matches("{label}.local.", "foo.local."));
+        assert!(pattern_matches("{label}.local.", "FOO.Local."));
+        assert!(pattern_matches("sensor-{label}.local.", "sensor-3.local."));
+        assert!(!pattern_matches("{label}.local.", ".local."));
+        assert!(!pattern_matches("{label}.local.", "foo.bar.local."));
+        assert!(!pattern_matches("sensor-{label}.local.", "foo.local."));
+
+        assert_eq!(&*expand_pattern("{label}.local.", "Foo"), "foo.local.");
+    }
+
+    #[test]
+    fn watches_are_shared() {
+        let dynamic = Dynamic::new([Name::from("{label}.local.")]);
+        assert!(dynamic.watch("eclss.example.com.").is_none());
+
+        let tx = dynamic.watch("foo.local.").expect("name matches pattern");
+        let rx = dynamic.watch("FOO.local.").unwrap().subscribe();
+        tx.send_replace(Some(Discovered::new(
+            ([192, 168, 1, 10], 80).into(),
+            "foo.local".parse().unwrap(),
+        )));
+        assert!(rx.borrow().is_some());
+    }
+
+    #[test]
+    fn unused_watches_are_dropped() {
+        let dynamic = Dynamic::new([Name::from("{label}.local.")]);
+        let rx = dynamic.watch("foo.local.").unwrap().subscribe();
+        let tx = dynamic.watch("bar.local.").unwrap();
+        drop(dynamic.watch("baz.local.").unwrap());
+
+        dynamic.watch("qux.local.").unwrap();
+        let watches = dynamic.watches.lock().unwrap();
+        assert!(watches.contains_key("foo.local."), "watched by a receiver");
+        assert!(watches.contains_key("bar.local."), "held by a sender");
+        assert!(!watches.contains_key("baz.local."));
+        drop((rx, tx));
+    }
+}
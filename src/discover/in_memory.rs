@@ -0,0 +1,174 @@
+use super::{dynamic::Dynamic, Discovered, Name, NotConfigured, Receiver};
+use crate::config::Config;
+use ahash::AHashMap;
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::sync::watch;
+
+/// A discovery implementation whose state is controlled entirely in memory.
+///
+/// This is intended for tests, and for embedders that want to drive the proxy
+/// stack from their own source of service discovery rather than mDNS. Each
+/// configured name has a watch which can be resolved, updated, or removed
+/// through the methods on this type; all clones share the same watches.
+///
+/// Names containing the [`LABEL`](super::LABEL) placeholder are patterns, and
+/// any name matching them may be resolved.
+#[derive(Clone, Debug)]
+pub struct InMemoryDiscover {
+    domains: Arc<AHashMap<Name, watch::Sender<Option<Discovered>>>>,
+    dynamic: Dynamic,
+}
+
+// === impl InMemoryDiscover ===
+
+impl InMemoryDiscover {
+    /// Returns a new `InMemoryDiscover` which will discover the provided
+    /// service names.
+    ///
+    /// All names start out unresolved.
+    pub fn new(names: impl IntoIterator<Item = Name>) -> Self {
+        let (patterns, names): (Vec<_>, Vec<_>) =
+            names.into_iter().partition(|name| super::is_pattern(name));
+        let domains = names
+            .into_iter()
+            .map(|name| {
+                let (tx, _) = watch::channel(None);
+                (name, tx)
+            })
+            .collect();
+        Self {
+            domains: Arc::new(domains),
+            dynamic: Dynamic::new(patterns),
+        }
+    }
+
+    /// Returns a new `InMemoryDiscover` for all the services in the provided
+    /// [`Config`].
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.services.keys().cloned())
+    }
+
+    /// Resolves `name` to the provided address, replacing any previously
+    /// discovered endpoint.
+    ///
+    /// The endpoint's authority is the service name, without the trailing dot.
+    pub fn resolve(&self, name: &str, addr: SocketAddr) -> Result<(), NotConfigured> {
+        let authority = name
+            .trim_end_matches('.')
+            .parse()
+            .expect("service names must be valid authorities");
+        self.set(name, Some(Discovered::new(addr, authority)))
+    }
+
+    /// Removes any endpoint discovered for `name`.
+    pub fn remove(&self, name: &str) -> Result<(), NotConfigured> {
+        self.set(name, None)
+    }
+
+    /// Sets the discovered state for `name`.
+    pub fn set(&self, name: &str, discovered: Option<Discovered>) -> Result<(), NotConfigured> {
+        tracing::debug!(service = name, ?discovered, "Updating in-memory discovery");
+        match self.domains.get(name) {
+            Some(tx) => tx.send_replace(discovered),
+            None => self
+                .dynamic
+                .watch(name)
+                .ok_or_else(|| NotConfigured(Name::from(name)))?
+                .send_replace(discovered),
+        };
+        Ok(())
+    }
+
+    /// Returns the current discovered state for `name`, if it is configured.
+    pub fn get(&self, name: &str) -> Option<Option<Discovered>> {
+        match self.domains.get(name) {
+            Some(tx) => Some(tx.borrow().clone()),
+            None => self.dynamic.watch(name).map(|tx| tx.borrow().clone()),
+        }
+    }
+}
+
+impl tower::Service<Name> for InMemoryDiscover {
+    type Response = Receiver;
+    type Error = NotConfigured;
+    type Future = futures::future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let rx = match self.domains.get(&name) {
+            Some(tx) => Some(tx.subscribe()),
+            None => self.dynamic.watch(&name).map(|tx| tx.subscribe()),
+        };
+        futures::future::ready(rx.ok_or(NotConfigured(name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn resolve_update_remove() {
+        crate::test_util::trace_init();
+
+        let discover = InMemoryDiscover::new([Name::from("eclss.local.")]);
+        let mut rx = discover
+            .clone()
+            .oneshot(Name::from("eclss.local."))
+            .await
+            .expect("eclss.local. is configured");
+        assert_eq!(*rx.borrow_and_update(), None);
+
+        let addr1 = SocketAddr::from(([192, 168, 1, 10], 80));
+        discover.resolve("eclss.local.", addr1).unwrap();
+        rx.changed().await.unwrap();
+        assert_eq!(rx.borrow_and_update().as_ref().map(|d| d.addr), Some(addr1));
+
+        let addr2 = SocketAddr::from(([192, 168, 1, 11], 8080));
+        discover.resolve("eclss.local.", addr2).unwrap();
+        rx.changed().await.unwrap();
+        assert_eq!(rx.borrow_and_update().as_ref().map(|d| d.addr), Some(addr2));
+
+        discover.remove("eclss.local.").unwrap();
+        rx.changed().await.unwrap();
+        assert_eq!(*rx.borrow_and_update(), None);
+    }
+
+    #[tokio::test]
+    async fn patterns() {
+        let discover = InMemoryDiscover::new([Name::from("{label}.local.")]);
+        let mut rx = discover
+            .clone()
+            .oneshot(Name::from("foo.local."))
+            .await
+            .expect("foo.local. matches a pattern");
+        assert_eq!(*rx.borrow_and_update(), None);
+
+        let addr = SocketAddr::from(([192, 168, 1, 10], 80));
+        discover.resolve("foo.local.", addr).unwrap();
+        rx.changed().await.unwrap();
+        assert_eq!(rx.borrow_and_update().as_ref().map(|d| d.addr), Some(addr));
+        assert!(discover.resolve("foo.bar.local.", addr).is_err());
+    }
+
+    #[tokio::test]
+    async fn not_configured() {
+        let discover = InMemoryDiscover::new([Name::from("eclss.local.")]);
+        assert!(discover
+            .clone()
+            .oneshot(Name::from("grafana.local."))
+            .await
+            .is_err());
+        assert!(discover
+            .resolve("grafana.local.", SocketAddr::from(([127, 0, 0, 1], 80)))
+            .is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use crate::config::{Discovery, IpFamily};
+use anyhow::Context;
+use ipnet::IpNet;
+use std::{fmt, net::IpAddr, sync::Arc};
+
+/// The network interfaces that discovered services are allowed to be on.
+///
+/// mDNS daemons are only bound to these interfaces' addresses, so that
+/// announcements on (for example) a WAN or VPN interface are never received.
+/// Discovered addresses that are not on the subnet of one of these interfaces
+/// are also dropped, in case an announcement names an address elsewhere.
+#[derive(Clone, Debug)]
+pub struct Interfaces {
+    ifaces: Arc<[Interface]>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Interface {
+    name: String,
+    ip: IpAddr,
+    net: IpNet,
+}
+
+// === impl Interfaces ===
+
+impl Interfaces {
+    /// Returns the host's interfaces that are allowed by `config`.
+    pub fn from_config(config: &Discovery) -> anyhow::Result<Self> {
+        let ifaces = if_addrs::get_if_addrs().context("failed to list network interfaces")?;
+        let interfaces = Self::from_if_addrs(config, ifaces);
+        anyhow::ensure!(
+            !interfaces.ifaces.is_empty(),
+            "no network interfaces are allowed for discovery"
+        );
+        Ok(interfaces)
+    }
+
+    pub(super) fn from_if_addrs(
+        config: &Discovery,
+        ifaces: impl IntoIterator<Item = if_addrs::Interface>,
+    ) -> Self {
+        let ifaces = ifaces
+            .into_iter()
+            .filter(|iface| {
+                let allowed = (config.interfaces.is_empty()
+                    || config.interfaces.contains(&iface.name))
+                    && !config.exclude_interfaces.contains(&iface.name)
+                    && config.ip_families.contains(&IpFamily::of(&iface.ip()));
+                if !allowed {
+                    tracing::debug!(iface.name = %iface.name, iface.ip = %iface.ip(), "Interface not allowed for discovery");
+                }
+                allowed
+            })
+            .filter_map(|iface| {
+                let ip = iface.ip();
+                let net = match iface.addr {
+                    if_addrs::IfAddr::V4(ref addr) => {
+                        IpNet::with_netmask(IpAddr::V4(addr.ip), IpAddr::V4(addr.netmask))
+                    }
+                    if_addrs::IfAddr::V6(ref addr) => {
+                        IpNet::with_netmask(IpAddr::V6(addr.ip), IpAddr::V6(addr.netmask))
+                    }
+                };
+                match net {
+                    Ok(net) => Some(Interface {
+                        name: iface.name,
+                        ip,
+                        net: net.trunc(),
+                    }),
+                    Err(error) => {
+                        tracing::warn!(iface.name = %iface.name, %error, "Interface has an invalid netmask");
+                        None
+                    }
+                }
+            })
+            .collect();
+        Self { ifaces }
+    }
+
+    /// Returns `true` if `ip` is on the subnet of an allowed interface.
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        self.ifaces.iter().any(|iface| iface.net.contains(ip))
+    }
+
+    /// Returns the allowed interfaces' own addresses.
+    pub(super) fn addrs(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.ifaces.iter().map(|iface| iface.ip)
+    }
+}
+
+impl fmt::Display for Interfaces {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for Interface { name, net, .. } in self.ifaces.iter() {
+            if !first {
+                f.write_str(", ")?;
+            }
+            write!(f, "{name} ({net})")?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use if_addrs::{IfAddr, Ifv4Addr, Ifv6Addr};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn ifaces() -> Vec<if_addrs::Interface> {
+        vec![
+            if_addrs::Interface {
+                name: "lan0".to_string(),
+                addr: IfAddr::V4(Ifv4Addr {
+                    ip: Ipv4Addr::new(192, 168, 1, 2),
+                    netmask: Ipv4Addr::new(255, 255, 255, 0),
+                    broadcast: None,
+                }),
+            },
+            if_addrs::Interface {
+                name: "lan0".to_string(),
+                addr: IfAddr::V6(Ifv6Addr {
+                    ip: "fd00::2".parse().unwrap(),
+                    netmask: "ffff:ffff:ffff:ffff::".parse().unwrap(),
+                    broadcast: None,
+                }),
+            },
+            if_addrs::Interface {
+                name: "docker0".to_string(),
+                addr: IfAddr::V4(Ifv4Addr {
+                    ip: Ipv4Addr::new(172, 17, 0, 1),
+                    netmask: Ipv4Addr::new(255, 255, 0, 0),
+                    broadcast: None,
+                }),
+            },
+            if_addrs::Interface {
+                name: "wg0".to_string(),
+                addr: IfAddr::V4(Ifv4Addr {
+                    ip: Ipv4Addr::new(10, 0, 0, 1),
+                    netmask: Ipv4Addr::new(255, 0, 0, 0),
+                    broadcast: None,
+                }),
+            },
+        ]
+    }
+
+    #[test]
+    fn default_allows_everything() {
+        let interfaces = Interfaces::from_if_addrs(&Discovery::default(), ifaces());
+        assert!(interfaces.allows(&IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10))));
+        assert!(interfaces.allows(&IpAddr::V4(Ipv4Addr::new(172, 17, 0, 5))));
+        assert!(interfaces.allows(&IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))));
+        assert!(interfaces.allows(&IpAddr::V6("fd00::10".parse().unwrap())));
+        assert!(!interfaces.allows(&IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))));
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let config = Discovery {
+            interfaces: vec!["lan0".to_string(), "wg0".to_string()],
+            exclude_interfaces: vec!["wg0".to_string()],
+            ..Default::default()
+        };
+        let interfaces = Interfaces::from_if_addrs(&config, ifaces());
+        assert!(interfaces.allows(&IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10))));
+        assert!(!interfaces.allows(&IpAddr::V4(Ipv4Addr::new(172, 17, 0, 5))));
+        assert!(!interfaces.allows(&IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))));
+        assert_eq!(
+            interfaces.addrs().collect::<Vec<_>>(),
+            [
+                IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)),
+                IpAddr::V6("fd00::2".parse().unwrap())
+            ],
+            "daemons are only bound to allowed interfaces"
+        );
+    }
+
+    #[test]
+    fn ip_families() {
+        let config = Discovery {
+            ip_families: vec![IpFamily::Ipv4],
+            ..Default::default()
+        };
+        let interfaces = Interfaces::from_if_addrs(&config, ifaces());
+        assert!(interfaces.allows(&IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10))));
+        assert!(!interfaces.allows(&IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x10))));
+        assert!(
+            interfaces.addrs().all(|ip| ip.is_ipv4()),
+            "daemons are only bound to allowed IP families"
+        );
+    }
+}
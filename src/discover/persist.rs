@@ -0,0 +1,268 @@
+use super::{Discovered, Name, Receiver};
+use ahash::AHashMap;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+/// The persisted discovery state.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+struct StateFile {
+    #[serde(default)]
+    services: BTreeMap<String, Entry>,
+}
+
+#[serde_with::serde_as]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    addr: SocketAddr,
+
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    name: http::uri::Authority,
+
+    #[serde(default)]
+    replicas: Vec<SocketAddr>,
+
+    /// When this entry was last updated, in seconds since the Unix epoch.
+    updated: u64,
+}
+
+/// An endpoint loaded from the state file.
+#[derive(Clone, Debug)]
+pub(super) struct Seed {
+    /// The persisted endpoint, marked as stale.
+    pub(super) discovered: Discovered,
+
+    /// How long until the endpoint is older than the maximum age, after which
+    /// it must not be routed to unless it has been announced again.
+    pub(super) expires_in: Duration,
+}
+
+/// Loads the persisted endpoints from `path`, returning those that are newer
+/// than `max_age`. All returned endpoints are marked as stale.
+///
+/// Since the state file is only an optimization, errors are logged rather
+/// than returned.
+pub(super) fn load(path: &Path, max_age: Duration) -> AHashMap<Name, Seed> {
+    let state = match read(path) {
+        Ok(state) => state,
+        Err(error) => {
+            tracing::warn!(path = %path.display(), %error, "Failed to load discovery state");
+            return AHashMap::new();
+        }
+    };
+
+    let now = unix_now();
+    state
+        .services
+        .into_iter()
+        .filter_map(|(name, entry)| {
+            let age = Duration::from_secs(now.saturating_sub(entry.updated));
+            if age > max_age {
+                tracing::debug!(service = %name, ?age, "Ignoring expired persisted endpoint");
+                return None;
+            }
+            tracing::info!(service = %name, addr = %entry.addr, ?age, "Loaded persisted endpoint");
+            let mut discovered = Discovered::new(entry.addr, entry.name);
+            discovered.replicas = entry.replicas;
+            discovered.stale = true;
+            let seed = Seed {
+                discovered,
+                expires_in: max_age - age,
+            };
+            Some((Name::from(name), seed))
+        })
+        .collect()
+}
+
+/// Spawns tasks that persist the live endpoints published to `domains` to
+/// `path`.
+///
+/// Entries already in the state file are kept, with their original
+/// timestamps, until the service is re-announced or removed.
+pub(super) fn spawn(path: PathBuf, domains: &AHashMap<Name, Receiver>) {
+    let (tx, mut rx) = mpsc::channel::<(Name, Option<Entry>)>(domains.len().max(1));
+    for (name, domain) in domains {
+        let mut domain = domain.clone();
+        let name = name.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            while domain.changed().await.is_ok() {
+                let entry = match *domain.borrow_and_update() {
+                    Some(ref discovered) if discovered.stale => continue,
+                    Some(ref discovered) => Some(Entry::new(discovered)),
+                    None => None,
+                };
+                if tx.send((name.clone(), entry)).await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    let mut state = read(&path).unwrap_or_default();
+    tokio::spawn(
+        async move {
+            while let Some((name, entry)) = rx.recv().await {
+                if !state.update(&name, entry) {
+                    continue;
+                }
+                let path = path.clone();
+                let contents = match toml::to_string(&state) {
+                    Ok(contents) => contents,
+                    Err(error) => {
+                        tracing::warn!(%error, "Failed to serialize discovery state");
+                        continue;
+                    }
+                };
+                match tokio::task::spawn_blocking(move || write(&path, &contents)).await {
+                    Ok(Ok(())) => tracing::debug!(service = %name, "Persisted discovery state"),
+                    Ok(Err(error)) => tracing::warn!(%error, "Failed to persist discovery state"),
+                    Err(error) => tracing::warn!(%error, "Discovery state writer panicked"),
+                }
+            }
+        }
+        .instrument(tracing::info_span!("persist", path = %path.display())),
+    );
+}
+
+fn read(path: &Path) -> anyhow::Result<StateFile> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            tracing::debug!(path = %path.display(), "No persisted discovery state");
+            return Ok(StateFile::default());
+        }
+        Err(error) => return Err(error).context("failed to read state file"),
+    };
+    toml::from_str(&contents).context("failed to parse state file")
+}
+
+/// Writes the state file atomically, by writing to a temporary file and
+/// renaming it.
+fn write(path: &Path, contents: &str) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents)
+        .with_context(|| format!("failed to write '{}'", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("failed to rename '{}'", tmp.display()))?;
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// === impl StateFile ===
+
+impl StateFile {
+    /// Records the endpoint that `name` now resolves to, or that it has been
+    /// removed. Returns `false` if the file doesn't need to be rewritten.
+    fn update(&mut self, name: &str, entry: Option<Entry>) -> bool {
+        match entry {
+            Some(entry) => {
+                if let Some(prev) = self.services.get(name) {
+                    if prev.is_fresh_version_of(&entry) {
+                        return false;
+                    }
+                }
+                self.services.insert(name.to_string(), entry);
+                true
+            }
+            // The service went away, so it shouldn't be restored on the next
+            // start.
+            None => self.services.remove(name).is_some(),
+        }
+    }
+}
+
+// === impl Entry ===
+
+impl Entry {
+    /// Re-announcements of an unchanged endpoint only refresh its timestamp
+    /// this often, to avoid rewriting the state file on every announcement.
+    const REFRESH_INTERVAL_SECS: u64 = 60;
+
+    fn new(discovered: &Discovered) -> Self {
+        Self {
+            addr: discovered.addr,
+            name: discovered.name.clone(),
+            replicas: discovered.replicas.clone(),
+            updated: unix_now(),
+        }
+    }
+
+    fn is_fresh_version_of(&self, next: &Self) -> bool {
+        self.addr == next.addr
+            && self.name == next.name
+            && self.replicas == next.replicas
+            && next.updated.saturating_sub(self.updated) < Self::REFRESH_INTERVAL_SECS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_respects_max_age() {
+        let dir = std::env::temp_dir().join(format!("multipass-persist-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.toml");
+
+        let fresh = Discovered::new(
+            SocketAddr::from(([192, 168, 1, 10], 80)),
+            "eclss.local".parse().unwrap(),
+        );
+        let mut old = Entry::new(&fresh);
+        old.updated -= 60 * 60;
+        let state = StateFile {
+            services: [
+                ("eclss.local.".to_string(), Entry::new(&fresh)),
+                ("grafana.local.".to_string(), old),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        write(&path, &toml::to_string(&state).unwrap()).unwrap();
+
+        let loaded = load(&path, Duration::from_secs(60));
+        assert_eq!(loaded.len(), 1);
+        let eclss = loaded.get("eclss.local.").unwrap();
+        assert_eq!(eclss.discovered.addr, fresh.addr);
+        assert!(eclss.discovered.stale);
+        assert!(eclss.expires_in <= Duration::from_secs(60));
+
+        assert_eq!(load(&dir.join("missing.toml"), Duration::MAX).len(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn update() {
+        let discovered = Discovered::new(
+            SocketAddr::from(([192, 168, 1, 10], 80)),
+            "eclss.local".parse().unwrap(),
+        );
+        let mut state = StateFile::default();
+        assert!(state.update("eclss.local.", Some(Entry::new(&discovered))));
+        assert!(
+            !state.update("eclss.local.", Some(Entry::new(&discovered))),
+            "unchanged re-announcements aren't written"
+        );
+
+        assert!(state.update("eclss.local.", None));
+        assert!(
+            state.services.is_empty(),
+            "removed services aren't restored"
+        );
+        assert!(!state.update("eclss.local.", None));
+    }
+}
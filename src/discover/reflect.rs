@@ -0,0 +1,391 @@
+use super::{
+    backend::{Announcement, Event},
+    Interfaces,
+};
+use crate::config;
+use anyhow::Context;
+use mdns_sd::{IfKind, ServiceDaemon, ServiceInfo};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+/// A TXT key added to reflected announcements, so that they are never
+/// reflected again by this or any other multipass instance.
+const REFLECTED_KEY: &str = "multipass-reflected";
+
+/// Decides which announcements to re-announce, and onto which interfaces.
+#[derive(Debug)]
+struct Reflector {
+    /// The interfaces to reflect between, by name.
+    interfaces: Vec<(String, Interfaces)>,
+    hostname_regex: Option<regex::Regex>,
+    /// Announcements that are currently reflected, by full name, with the
+    /// interfaces that they are reflected onto.
+    reflected: HashMap<String, (Announcement, Vec<String>)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Register {
+        announcement: Announcement,
+        onto: Vec<String>,
+    },
+    Unregister {
+        fullname: String,
+        from: Vec<String>,
+    },
+}
+
+/// One `mdns-sd` daemon for each reflected interface, each bound only to that
+/// interface, so that a reflection is only answered on the subnets that it is
+/// reflected onto.
+pub(super) type Daemons = Arc<HashMap<String, ServiceDaemon>>;
+
+/// Returns a daemon that browses on the reflector's interfaces, and the
+/// daemons that re-announce onto each of them.
+pub(super) fn daemons(config: &config::Reflector) -> anyhow::Result<(ServiceDaemon, Daemons)> {
+    let browser = bound_daemon(&config.interfaces)?;
+    let announcers = config
+        .interfaces
+        .iter()
+        .map(|name| {
+            let daemon = bound_daemon(std::slice::from_ref(name))?;
+            Ok((name.clone(), daemon))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok((browser, Arc::new(announcers)))
+}
+
+fn bound_daemon(interfaces: &[String]) -> anyhow::Result<ServiceDaemon> {
+    let daemon = ServiceDaemon::new()?;
+    daemon.disable_interface(IfKind::All)?;
+    for name in interfaces {
+        daemon
+            .enable_interface(IfKind::Name(name.clone()))
+            .with_context(|| format!("failed to bind mDNS daemon to {name}"))?;
+    }
+    Ok(daemon)
+}
+
+/// Spawns a task that re-announces the instances discovered by `events` onto
+/// every reflected interface other than the one they were announced on.
+pub(super) fn spawn(
+    daemons: Daemons,
+    config: &config::Reflector,
+    service_type: String,
+    mut events: mpsc::Receiver<Event>,
+) -> anyhow::Result<()> {
+    let interfaces = config
+        .interfaces
+        .iter()
+        .map(|name| {
+            let interfaces = Interfaces::from_config(&config::Discovery {
+                interfaces: vec![name.clone()],
+                ..Default::default()
+            })
+            .with_context(|| format!("failed to find interface {name} to reflect between"))?;
+            tracing::info!(%interfaces, service_type, "Reflecting mDNS announcements");
+            Ok((name.clone(), interfaces))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let mut reflector = Reflector {
+        interfaces,
+        hostname_regex: config.hostname_regex.clone(),
+        reflected: HashMap::new(),
+    };
+    tokio::spawn(
+        async move {
+            while let Some(event) = events.recv().await {
+                for action in reflector.reflect(event) {
+                    match action {
+                        Action::Register { announcement, onto } => {
+                            for iface in onto {
+                                if let Err(error) =
+                                    register(&daemons[&iface], &service_type, &announcement)
+                                {
+                                    tracing::warn!(iface, %error, "Failed to reflect announcement");
+                                }
+                            }
+                        }
+                        Action::Unregister { fullname, from } => {
+                            for iface in from {
+                                tracing::info!(fullname, iface, "Removing reflected announcement");
+                                if let Err(error) = daemons[&iface].unregister(&fullname) {
+                                    tracing::warn!(fullname, iface, %error, "Failed to remove reflected announcement");
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        .instrument(tracing::info_span!("reflect")),
+    );
+    Ok(())
+}
+
+fn register(
+    daemon: &ServiceDaemon,
+    service_type: &str,
+    announcement: &Announcement,
+) -> anyhow::Result<()> {
+    let instance = announcement
+        .fullname
+        .strip_suffix(service_type)
+        .map(|name| name.trim_end_matches('.'))
+        .with_context(|| {
+            format!(
+                "instance {} is not of type {service_type}",
+                announcement.fullname
+            )
+        })?;
+    let addrs = announcement
+        .addrs
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    let info = ServiceInfo::new(
+        service_type,
+        instance,
+        &announcement.hostname,
+        addrs.as_str(),
+        announcement.port,
+        Some(announcement.txt.clone()),
+    )?;
+    daemon.register(info)?;
+    tracing::info!(
+        fullname = announcement.fullname,
+        hostname = announcement.hostname,
+        addrs,
+        "Reflected announcement"
+    );
+    Ok(())
+}
+
+// === impl Reflector ===
+
+impl Reflector {
+    fn reflect(&mut self, event: Event) -> Vec<Action> {
+        match event {
+            Event::Resolved(mut announcement) => {
+                if announcement.txt.contains_key(REFLECTED_KEY) {
+                    tracing::trace!(
+                        fullname = announcement.fullname,
+                        "Not reflecting a reflection"
+                    );
+                    return Vec::new();
+                }
+
+                if let Some(ref regex) = self.hostname_regex {
+                    if !regex.is_match(&announcement.hostname) {
+                        tracing::trace!(hostname = announcement.hostname, "Hostname not reflected");
+                        return Vec::new();
+                    }
+                }
+
+                // Only reflect IPv4 addresses on the configured interfaces;
+                // `mdns-sd` can only announce IPv4.
+                announcement.addrs.retain(|ip| {
+                    ip.is_ipv4() && self.interfaces.iter().any(|(_, iface)| iface.allows(ip))
+                });
+                if announcement.addrs.is_empty() {
+                    tracing::trace!(
+                        fullname = announcement.fullname,
+                        "Announcement not from a reflected interface"
+                    );
+                    return Vec::new();
+                }
+
+                // Re-announcing an instance on the interface that it came
+                // from would conflict with the instance itself.
+                let onto = self
+                    .interfaces
+                    .iter()
+                    .filter(|(_, iface)| !announcement.addrs.iter().any(|ip| iface.allows(ip)))
+                    .map(|(name, _)| name.clone())
+                    .collect::<Vec<_>>();
+
+                announcement
+                    .txt
+                    .insert(REFLECTED_KEY.to_string(), "1".to_string());
+                let mut actions = Vec::new();
+                match self.reflected.get(&announcement.fullname) {
+                    Some((prev, prev_onto)) if *prev == announcement && *prev_onto == onto => {
+                        return actions;
+                    }
+                    Some((_, prev_onto)) => {
+                        let from = prev_onto
+                            .iter()
+                            .filter(|&iface| !onto.contains(iface))
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        if !from.is_empty() {
+                            actions.push(Action::Unregister {
+                                fullname: announcement.fullname.clone(),
+                                from,
+                            });
+                        }
+                    }
+                    None => {}
+                }
+                if onto.is_empty() {
+                    self.reflected.remove(&announcement.fullname);
+                    return actions;
+                }
+                self.reflected.insert(
+                    announcement.fullname.clone(),
+                    (announcement.clone(), onto.clone()),
+                );
+                actions.push(Action::Register { announcement, onto });
+                actions
+            }
+            Event::Removed { fullname } => match self.reflected.remove(&fullname) {
+                Some((_, from)) => vec![Action::Unregister { fullname, from }],
+                None => Vec::new(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use if_addrs::{IfAddr, Ifv4Addr};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const LAN: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 10);
+    const IOT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 10);
+
+    fn new_reflector(hostname_regex: Option<&str>) -> Reflector {
+        crate::test_util::trace_init();
+        let interfaces = [("lan0", LAN), ("iot0", IOT)]
+            .into_iter()
+            .map(|(name, ip)| {
+                let iface = if_addrs::Interface {
+                    name: name.to_string(),
+                    addr: IfAddr::V4(Ifv4Addr {
+                        ip,
+                        netmask: Ipv4Addr::new(255, 255, 255, 0),
+                        broadcast: None,
+                    }),
+                };
+                let config = config::Discovery {
+                    interfaces: vec![name.to_string()],
+                    ..Default::default()
+                };
+                (
+                    name.to_string(),
+                    Interfaces::from_if_addrs(&config, [iface]),
+                )
+            })
+            .collect();
+        Reflector {
+            interfaces,
+            hostname_regex: hostname_regex.map(|re| re.parse().unwrap()),
+            reflected: HashMap::new(),
+        }
+    }
+
+    fn announcement(ip: Ipv4Addr) -> Announcement {
+        Announcement {
+            fullname: "printer._ipp._tcp.local.".to_string(),
+            hostname: "printer.local.".to_string(),
+            addrs: vec![IpAddr::V4(ip)],
+            port: 631,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reflects_once_and_removes() {
+        let mut reflector = new_reflector(None);
+        let announcement = announcement(LAN);
+        let [Action::Register {
+            announcement: reflected,
+            onto,
+        }] = &reflector.reflect(Event::Resolved(announcement.clone()))[..]
+        else {
+            panic!("announcement must be reflected");
+        };
+        assert_eq!(
+            onto,
+            &["iot0".to_string()],
+            "announcements aren't reflected onto their own interface"
+        );
+        assert_eq!(
+            reflected.txt.get(REFLECTED_KEY).map(String::as_str),
+            Some("1")
+        );
+
+        // Re-announcements of the same instance aren't reflected again...
+        assert_eq!(
+            reflector.reflect(Event::Resolved(announcement.clone())),
+            vec![]
+        );
+        // ...and neither are our own reflections.
+        assert_eq!(
+            reflector.reflect(Event::Resolved(reflected.clone())),
+            vec![]
+        );
+
+        assert_eq!(
+            reflector.reflect(Event::Removed {
+                fullname: announcement.fullname.clone()
+            }),
+            vec![Action::Unregister {
+                fullname: announcement.fullname.clone(),
+                from: vec!["iot0".to_string()],
+            }]
+        );
+        assert_eq!(
+            reflector.reflect(Event::Removed {
+                fullname: announcement.fullname
+            }),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn moves_between_interfaces() {
+        let mut reflector = new_reflector(None);
+        reflector.reflect(Event::Resolved(announcement(LAN)));
+        let actions = reflector.reflect(Event::Resolved(announcement(IOT)));
+        assert_eq!(actions.len(), 2);
+        assert_eq!(
+            actions[0],
+            Action::Unregister {
+                fullname: "printer._ipp._tcp.local.".to_string(),
+                from: vec!["iot0".to_string()],
+            }
+        );
+        assert!(matches!(
+            &actions[1],
+            Action::Register { onto, .. } if onto == &["lan0".to_string()]
+        ));
+
+        // An instance on every interface has nowhere to be reflected onto.
+        let mut both = announcement(LAN);
+        both.addrs.push(IpAddr::V4(IOT));
+        assert_eq!(
+            reflector.reflect(Event::Resolved(both)),
+            vec![Action::Unregister {
+                fullname: "printer._ipp._tcp.local.".to_string(),
+                from: vec!["lan0".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn filters_hostnames_and_interfaces() {
+        let mut reflector = new_reflector(Some("^eclss"));
+        assert_eq!(
+            reflector.reflect(Event::Resolved(announcement(LAN))),
+            vec![]
+        );
+
+        let mut reflector = new_reflector(None);
+        let other = announcement(Ipv4Addr::new(203, 0, 113, 7));
+        assert_eq!(reflector.reflect(Event::Resolved(other)), vec![]);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::config::Verify;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
+
+/// The reason an mDNS announcement was rejected: because it was not on an
+/// allowed interface, because its hostname is invalid, or because it failed a
+/// service's [`Verify`] constraints.
+#[derive(Debug, Clone, thiserror::Error, Eq, PartialEq)]
+pub enum Rejected {
+    #[error("none of the announced addresses {0:?} are on an allowed interface")]
+    Interface(Vec<IpAddr>),
+
+    #[error("announced hostname {0:?} is not a valid authority")]
+    Hostname(String),
+
+    #[error("none of the announced addresses {0:?} are in an allowed CIDR")]
+    Cidr(Vec<IpAddr>),
+
+    #[error("announced port {actual} does not match expected port {expected}")]
+    Port { expected: u16, actual: u16 },
+
+    #[error("TXT record `{0}` is missing")]
+    MissingTxt(String),
+
+    // Don't include the values here, since they may be secrets.
+    #[error("TXT record `{0}` does not have the expected value")]
+    WrongTxt(String),
+}
+
+/// Selects the first of `addrs` that satisfies `verify`'s CIDR constraints.
+pub(super) fn select_addr(
+    verify: &Verify,
+    addrs: impl IntoIterator<Item = IpAddr>,
+) -> Result<IpAddr, Rejected> {
+    let addrs = addrs.into_iter().collect::<Vec<_>>();
+    if verify.allowed_cidrs.is_empty() {
+        if let Some(&addr) = addrs.first() {
+            return Ok(addr);
+        }
+    }
+
+    addrs
+        .iter()
+        .copied()
+        .find(|ip| verify.allowed_cidrs.iter().any(|cidr| cidr.contains(ip)))
+        .ok_or(Rejected::Cidr(addrs))
+}
+
+/// Checks the announced port and TXT properties of an endpoint against
+/// `verify`.
+pub(super) fn check(
+    verify: &Verify,
+    addr: SocketAddr,
+    txt: &HashMap<String, String>,
+) -> Result<(), Rejected> {
+    if let Some(expected) = verify.port {
+        if addr.port() != expected {
+            return Err(Rejected::Port {
+                expected,
+                actual: addr.port(),
+            });
+        }
+    }
+
+    for (key, expected) in &verify.txt {
+        match txt.get(key) {
+            None => return Err(Rejected::MissingTxt(key.clone())),
+            Some(actual) if actual != expected => return Err(Rejected::WrongTxt(key.clone())),
+            Some(_) => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn verify() -> Verify {
+        Verify {
+            allowed_cidrs: vec!["192.168.1.0/24".parse().unwrap()],
+            port: Some(80),
+            txt: [("secret".to_string(), "hunter2".to_string())]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn unconstrained() {
+        let verify = Verify::default();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(select_addr(&verify, [ip]), Ok(ip));
+        assert_eq!(
+            check(&verify, SocketAddr::new(ip, 8080), &HashMap::new()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn cidrs() {
+        let verify = verify();
+        let good = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        let bad = IpAddr::V4(Ipv4Addr::new(192, 168, 2, 20));
+        assert_eq!(select_addr(&verify, [bad, good]), Ok(good));
+        assert_eq!(select_addr(&verify, [bad]), Err(Rejected::Cidr(vec![bad])));
+    }
+
+    #[test]
+    fn port_and_txt() {
+        let verify = verify();
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        let mut txt = HashMap::new();
+        assert_eq!(
+            check(&verify, SocketAddr::new(ip, 80), &txt),
+            Err(Rejected::MissingTxt("secret".to_string()))
+        );
+
+        txt.insert("secret".to_string(), "password".to_string());
+        assert_eq!(
+            check(&verify, SocketAddr::new(ip, 80), &txt),
+            Err(Rejected::WrongTxt("secret".to_string()))
+        );
+
+        txt.insert("secret".to_string(), "hunter2".to_string());
+        assert_eq!(check(&verify, SocketAddr::new(ip, 80), &txt), Ok(()));
+        assert_eq!(
+            check(&verify, SocketAddr::new(ip, 8080), &txt),
+            Err(Rejected::Port {
+                expected: 80,
+                actual: 8080
+            })
+        );
+    }
+}
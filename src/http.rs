@@ -1,31 +1,70 @@
 pub use self::{
+    action::NewRouteAction,
     box_body::BoxBody,
-    client::{Connect, NewClient},
+    client::{Connect, Endpoint, NewClient, NewPickAddr, ProxyConnect, Replicas, UpstreamAddr},
+    client_addr::{ClientAddr, NewResolveClientAddr},
+    forwarded::NewForwarded,
     header_from_target::NewHeaderFromTarget,
+    headers::{NewRouteHeaders, NewUpstreamHeaders},
+    index::ServiceIndex,
+    loop_detect::{NewLoopGuard, NewVia, OwnAddrs},
+    mirror::NewMirror,
+    proxy_header::{NewProxyHeader, ProxyHeader},
+    rewrite::{NewRewritePath, NewRewriteResponse},
+    sticky::{NewStickyCookie, SetCookie},
+    timeouts::{NewRouteTimeouts, TimeoutError},
 };
 use crate::{discover, route::{self, RoutingTable}, serve, svc, Proxy};
 pub use http::*;
 use hyper::body::Incoming;
 use linkerd_app_core::{errors, proxy};
-use std::{net::SocketAddr, ops::Deref};
+use std::{net::SocketAddr, ops::Deref, sync::Arc};
 use tokio::io;
 
+mod action;
 mod box_body;
 mod client;
+mod client_addr;
 mod error_respond;
+mod files;
+mod forwarded;
 mod header_from_target;
+mod headers;
+mod index;
+mod loop_detect;
+mod mirror;
+mod proxy_header;
+mod rewrite;
+mod sticky;
+mod timeouts;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone)]
 pub struct Route<T> {
     parent: T,
     name: discover::Name,
+    rule: Arc<route::Rule>,
+    upstream: Upstream,
+    set_cookie: Option<HeaderValue>,
+}
+
+/// Which of its rule's services a [`Route`] sends requests to.
+#[derive(Copy, Clone, Debug)]
+enum Upstream {
+    /// The rule's own service.
+    Service,
+
+    /// The backend of the rule's split at this index.
+    Backend(usize),
+
+    /// The rule's mirror.
+    Mirror,
 }
 
 impl<C> Proxy<C>
 where
     C: svc::Service<SocketAddr> + Clone + Send + Sync + 'static,
-    C::Future: Send + Unpin,
-    C::Error: std::error::Error + Send + Sync,
+    C::Future: Send + Unpin + 'static,
+    C::Error: std::error::Error + Send + Sync + 'static,
     C::Response: io::AsyncRead + io::AsyncWrite + client::connect::Connection,
     C::Response: Unpin + Send + 'static,
 {
@@ -35,7 +74,7 @@ where
         impl svc::NewService<
                 discover::Discovered,
                 Service = impl svc::Service<
-                    Request<Incoming>,
+                    Request<BoxBody>,
                     Response = Response<Incoming>,
                     Error = linkerd_app_core::Error,
                     Future = impl std::future::Future + Send,
@@ -46,7 +85,8 @@ where
             > + Clone
             + Send,
     > {
-        self.map_stack(|connect, _| {
+        self.map_stack(|connect, cfg| {
+            let own = OwnAddrs::new([cfg.listeners.http, cfg.listeners.https]);
             connect
                 .push(NewClient::layer())
                 // .push_on_service(svc::util::MapResponseLayer::new(
@@ -55,9 +95,20 @@ where
                 // Convert origin form HTTP/1 URIs to absolute form for Hyper's
                 // `Client`.
                 .push(linkerd_app_core::proxy::http::NewNormalizeUri::layer())
-                .instrument(
-                    |d: &discover::Discovered| tracing::info_span!("endpoint", addr = %d.addr),
-                )
+                // Map references to the endpoint in responses back to the
+                // names that the client used.
+                .push(NewRewriteResponse::layer())
+                // Apply each route's header rules, now that the endpoint is
+                // known.
+                .push(NewUpstreamHeaders::layer())
+                // Pick which of the endpoint's replicas each request is sent
+                // to, so that the layers above know the address it's sent to.
+                .push(NewPickAddr::layer())
+                // Refuse to send requests back to the gateway itself.
+                .push(NewLoopGuard::layer(Arc::new(own)))
+                .instrument(|d: &discover::Discovered| {
+                    tracing::info_span!("endpoint", addr = %d.addr, stale = d.stale)
+                })
         })
     }
 }
@@ -67,21 +118,27 @@ where
     N: svc::NewService<discover::Discovered, Service = S>,
     N: Clone + Send + Sync + 'static,
     S: svc::Service<
-        Request<Incoming>,
+        Request<BoxBody>,
         Response = http::Response<Incoming>,
         Error = linkerd_app_core::Error,
     >,
     S: Clone + Send + Sync + 'static,
     S::Future: Send,
 {
-    pub fn push_http_discover<T>(
+    /// Pushes a stack that discovers endpoints for each route's service
+    /// [`discover::Name`].
+    ///
+    /// `discover` may be any service that resolves a name to a
+    /// [`discover::Receiver`], such as [`discover::MdnsDiscover`] or
+    /// [`discover::InMemoryDiscover`].
+    pub fn push_http_discover<T, D>(
         self,
-        discover: &discover::MdnsDiscover,
+        discover: &D,
     ) -> Proxy<
         svc::ArcNewService<
             T,
             impl svc::Service<
-                    Request<Incoming>,
+                    Request<BoxBody>,
                     Response = Response<Incoming>,
                     Error = linkerd_app_core::Error,
                     Future = impl std::future::Future + Send,
@@ -92,6 +149,10 @@ where
     >
     where
         T: svc::Param<discover::Name> + Clone + Send + Sync + 'static,
+        D: svc::Service<discover::Name, Response = discover::Receiver>,
+        D: Clone + Send + Sync + 'static,
+        D::Error: Into<linkerd_app_core::Error>,
+        D::Future: Send + Unpin,
     {
         let discover = svc::stack(discover.clone())
             .push(svc::MapErr::layer_boxed())
@@ -123,8 +184,13 @@ where
 }
 
 impl<N> Proxy<N> {
-    pub fn push_http_server<S>(
+    /// Pushes a stack that routes each request to a [`Route`].
+    ///
+    /// `discover` is used to report whether services are resolved in the
+    /// service index.
+    pub fn push_http_server<S, D>(
         self,
+        discover: &D,
     ) -> Proxy<
         impl svc::NewService<
                 serve::Accepted,
@@ -141,53 +207,55 @@ impl<N> Proxy<N> {
     where
         N: svc::NewService<Route<serve::Accepted>, Service = S> + Clone + Send,
         S: svc::Service<
-            Request<Incoming>,
+            Request<BoxBody>,
             Response = http::Response<Incoming>,
             Error = linkerd_app_core::Error,
         >,
-        S: Clone + Send,
-        S::Future: Send,
+        S: Clone + Send + 'static,
+        S::Future: Send + 'static,
         S::Response: Send,
+        D: svc::Service<discover::Name, Response = discover::Receiver>,
+        D: Clone + Send + Sync + 'static,
+        D::Error: Into<linkerd_app_core::Error>,
+        D::Future: Send,
     {
-        self.map_stack(|discover, cfg| {
+        let discover = discover.clone();
+        self.map_stack(move |stack, cfg| {
             let hostname = cfg.domain.clone();
-            discover
-                .push(NewHeaderFromTarget::layer_via(
-                    header_from_target::Which { request: true, response: false },
-                    |route: &Route<serve::Accepted>| {
-                        let client_addr = route.parent.client_addr;
-                        let listen_addr = route.parent.listen_addr;
-                        let forwarded = format!("by={listen_addr};for={client_addr};host={}", route.name)
-                            .parse::<http::HeaderValue>()
-                            .unwrap();
-                        (http::header::FORWARDED, forwarded)
-                    },
-                ))
+            let index = ServiceIndex::new(cfg.index.clone(), discover);
+            stack
+                .push(NewRouteHeaders::layer())
+                .push(NewProxyHeader::layer())
+                // Mirrored requests are copied before the forwarding headers
+                // are added, so that they get their own service's.
+                .push(NewForwarded::layer())
+                .push(NewMirror::layer())
+                .push(NewRewritePath::layer())
+                .push(NewStickyCookie::layer())
+                .push(NewRouteTimeouts::layer())
+                .push(NewRouteAction::layer(index))
                 .lift_new_with_target()
-                .check_new_new::<serve::Accepted, discover::Name>()
+                .check_new_new::<serve::Accepted, route::Selected>()
                 .push(
                     linkerd_router::NewOneshotRoute::<RoutingTable, _, _>::layer_via({
                         let routes = cfg.routes.clone();
-                        move |_: &serve::Accepted| routes.clone()
+                        move |t: &serve::Accepted| routes.for_client(t.client_addr.ip)
                     }),
                 )
                 .push_on_service(
                     svc::layers()
+                        .push(svc::MapTargetLayer::new(|req: Request<Incoming>| {
+                            req.map(box_body::boxed)
+                        }))
                         // Record when an HTTP/1 URI was in absolute form
                         .push(proxy::http::normalize_uri::MarkAbsoluteForm::layer())
                         .push(box_body::BoxResponse::layer()),
                 )
-                .push(NewHeaderFromTarget::layer_via(
-                    header_from_target::Which { request: true, response: true },
-                    move |t: &serve::Accepted| {
-                        let via = format!("HTTP/1.1 {hostname}:{}", t.listen_addr.port())
-                            .parse::<http::HeaderValue>().unwrap();
-                        (http::header::VIA, via)
-                    })
-                )
+                .push(NewVia::layer(hostname))
                 .push(ServerRescue::layer())
                 // .push(proxy::http::SetClientHandle::layer())
-                .instrument(|_: &serve::Accepted| tracing::info_span!("http"))
+                .instrument(|t: &serve::Accepted| tracing::info_span!("http", client.addr = %t.client_addr))
+                .push(NewResolveClientAddr::layer(cfg.trusted_proxies.clone()))
                 .check_clone()
                 .check_new_service::<serve::Accepted, _>()
         })
@@ -196,6 +264,58 @@ impl<N> Proxy<N> {
 
 // === impl Route ===
 
+impl<T> Route<T> {
+    /// Returns the backend that the rule's split picked, if requests are
+    /// sent to one.
+    fn backend(&self) -> Option<&route::WeightedService> {
+        match self.upstream {
+            Upstream::Backend(backend) => self.rule.split.as_ref()?.backends.get(backend),
+            Upstream::Service | Upstream::Mirror => None,
+        }
+    }
+
+    /// Returns the rule's mirror, if requests are sent to it.
+    fn mirror(&self) -> Option<&route::Mirror> {
+        match self.upstream {
+            Upstream::Mirror => self.rule.mirror.as_ref(),
+            Upstream::Service | Upstream::Backend(_) => None,
+        }
+    }
+
+    /// The header rules applied to requests and their responses.
+    fn headers(&self) -> &Arc<route::HeaderRules> {
+        self.mirror()
+            .map_or(&self.rule.headers, |mirror| &mirror.headers)
+    }
+
+    /// The forwarding headers of the service that requests are sent to.
+    fn forwarding(&self) -> &route::Forwarding {
+        if let Some(mirror) = self.mirror() {
+            return &mirror.forwarding;
+        }
+        self.backend()
+            .map_or(&self.rule.forwarding, |backend| &backend.forwarding)
+    }
+
+    /// The PROXY protocol version of the service that requests are sent to.
+    fn proxy_protocol(&self) -> Option<crate::proxy_protocol::Version> {
+        if let Some(mirror) = self.mirror() {
+            return mirror.proxy_protocol;
+        }
+        self.backend()
+            .map_or(self.rule.proxy_protocol, |backend| backend.proxy_protocol)
+    }
+
+    /// The timeouts of the service that requests are sent to.
+    fn timeouts(&self) -> route::Timeouts {
+        if let Some(mirror) = self.mirror() {
+            return mirror.timeouts;
+        }
+        self.backend()
+            .map_or(self.rule.timeouts, |backend| backend.timeouts)
+    }
+}
+
 impl<T> svc::Param<discover::Name> for Route<T> {
     fn param(&self) -> discover::Name {
         self.name.clone()
@@ -209,9 +329,29 @@ impl<T> Deref for Route<T> {
     }
 }
 
-impl<T> From<(discover::Name, T)> for Route<T> {
-    fn from((name, parent): (discover::Name, T)) -> Self {
-        Self { name, parent }
+impl<T> svc::Param<Arc<route::Rule>> for Route<T> {
+    fn param(&self) -> Arc<route::Rule> {
+        self.rule.clone()
+    }
+}
+
+impl<T> svc::Param<SetCookie> for Route<T> {
+    fn param(&self) -> SetCookie {
+        SetCookie(self.set_cookie.clone())
+    }
+}
+
+impl<T> From<(route::Selected, T)> for Route<T> {
+    fn from((selected, parent): (route::Selected, T)) -> Self {
+        Self {
+            name: selected.service,
+            rule: selected.rule,
+            upstream: selected
+                .backend
+                .map_or(Upstream::Service, Upstream::Backend),
+            set_cookie: selected.set_cookie,
+            parent,
+        }
     }
 }
 
@@ -250,6 +390,10 @@ impl error_respond::HttpRescue<linkerd_app_core::Error> for ServerRescue {
             return Ok(error_respond::SyntheticHttpResponse::gateway_timeout(error));
         }
 
+        if errors::is_caused_by::<TimeoutError>(&*error) {
+            return Ok(error_respond::SyntheticHttpResponse::gateway_timeout(error));
+        }
+
         if errors::is_caused_by::<errors::LoadShedError>(&*error) {
             return Ok(error_respond::SyntheticHttpResponse::unavailable(error));
         }
@@ -0,0 +1,127 @@
+use super::{box_body, error_respond::SyntheticHttpResponse, files, index::ServiceIndex, BoxBody};
+use crate::{discover, route, svc};
+use futures::{future, FutureExt, TryFutureExt};
+use http_body::Body;
+use linkerd_app_core::Error;
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Answers requests for routes whose [`route::Action`] is not to forward
+/// them to the route's service: redirects, fixed responses, files, and the
+/// service index.
+///
+/// Redirects and fixed responses are returned as [`SyntheticHttpResponse`]
+/// errors, so that they are rendered like the gateway's other responses.
+#[derive(Clone, Debug)]
+pub struct NewRouteAction<D, N> {
+    index: ServiceIndex<D>,
+    inner: N,
+}
+
+#[derive(Clone, Debug)]
+pub enum RouteAction<S, D> {
+    Forward(S),
+    Respond(Arc<route::Rule>, ServiceIndex<D>),
+}
+
+type ResponseFuture<F, B> = future::Either<
+    future::MapOk<F, fn(http::Response<B>) -> http::Response<BoxBody>>,
+    future::BoxFuture<'static, Result<http::Response<BoxBody>, Error>>,
+>;
+
+// === impl NewRouteAction ===
+
+impl<D: Clone, N> NewRouteAction<D, N> {
+    pub fn layer(index: ServiceIndex<D>) -> impl svc::Layer<N, Service = Self> + Clone {
+        svc::layer::mk(move |inner| Self {
+            index: index.clone(),
+            inner,
+        })
+    }
+}
+
+impl<T, D, N> svc::NewService<T> for NewRouteAction<D, N>
+where
+    T: svc::Param<Arc<route::Rule>>,
+    D: Clone,
+    N: svc::NewService<T>,
+{
+    type Service = RouteAction<N::Service, D>;
+
+    fn new_service(&self, target: T) -> Self::Service {
+        let rule: Arc<route::Rule> = target.param();
+        match rule.action {
+            route::Action::Forward => RouteAction::Forward(self.inner.new_service(target)),
+            _ => RouteAction::Respond(rule, self.index.clone()),
+        }
+    }
+}
+
+// === impl RouteAction ===
+
+impl<S, D, ReqB, RspB> svc::Service<http::Request<ReqB>> for RouteAction<S, D>
+where
+    S: svc::Service<http::Request<ReqB>, Response = http::Response<RspB>, Error = Error>,
+    D: svc::Service<discover::Name, Response = discover::Receiver>,
+    D: Clone + Send + 'static,
+    D::Error: Into<Error>,
+    D::Future: Send,
+    RspB: Body + Send + 'static,
+    RspB::Data: Send + 'static,
+    RspB::Error: Into<Error> + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Error;
+    type Future = ResponseFuture<S::Future, RspB>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self {
+            Self::Forward(inner) => inner.poll_ready(cx),
+            Self::Respond(..) => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn call(&mut self, req: http::Request<ReqB>) -> Self::Future {
+        let (rule, index) = match self {
+            Self::Forward(inner) => {
+                let boxed: fn(_) -> _ = |rsp: http::Response<RspB>| rsp.map(box_body::boxed);
+                return future::Either::Left(inner.call(req).map_ok(boxed));
+            }
+            Self::Respond(rule, index) => (rule, index),
+        };
+
+        let rsp = match rule.action {
+            route::Action::Redirect(ref redirect) => match rule.redirect_location(req.uri()) {
+                Some(Ok(location)) => {
+                    tracing::debug!(%location, "Redirecting");
+                    SyntheticHttpResponse::redirect(redirect.status, &location)
+                }
+                Some(Err(error)) => {
+                    tracing::warn!(%error, location = %redirect.location, "Invalid redirect location");
+                    SyntheticHttpResponse::internal_error("invalid redirect location")
+                }
+                None => unreachable!("rule must redirect"),
+            },
+            route::Action::Respond(ref fixed) => SyntheticHttpResponse::fixed(
+                fixed.status,
+                fixed.headers.clone(),
+                fixed.body.clone(),
+            ),
+            route::Action::Files(ref files) => {
+                let path = match rule.rewrite_path(req.uri().path()) {
+                    Some(rewritten) => rewritten.path,
+                    None => req.uri().path().to_string(),
+                };
+                let (parts, _) = req.into_parts();
+                let serve = files::serve(files.root.clone(), path, parts);
+                return future::Either::Right(serve.boxed());
+            }
+            route::Action::Index => return future::Either::Right(index.respond(&req)),
+            route::Action::Forward => unreachable!("forwarding rules have an inner service"),
+        };
+        future::Either::Right(future::ready(Err(rsp.into())).boxed())
+    }
+}
@@ -1,25 +1,112 @@
+use super::{proxy_header::ProxyHeader, BoxBody};
 use crate::svc;
-use hyper::body::Incoming;
+use ahash::AHashMap;
+use futures::{future::BoxFuture, FutureExt, TryFutureExt};
 pub use hyper_util::client::*;
 use hyper_util::rt::TokioExecutor;
 pub use legacy::Client;
+use linkerd_app_core::Error;
 use std::{
     net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
     task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{self, AsyncWriteExt},
+    time::{self, Instant},
 };
-use tokio::io;
 
 #[derive(Clone, Debug)]
 pub struct NewClient<C> {
     connect: C,
 }
 
+/// Sends requests to an endpoint on pooled connections.
+///
+/// Each request is sent to the address recorded in its [`UpstreamAddr`]
+/// extension, or else to the endpoint's own address. Requests that must be
+/// sent on a connection that starts with a [`ProxyHeader`] are only sent on
+/// connections that were opened for the same client.
+#[derive(Clone, Debug)]
+pub struct Endpoint<C> {
+    pools: Arc<[Pool<C>]>,
+}
+
+/// The connections to one of an endpoint's addresses.
+#[derive(Debug)]
+struct Pool<C> {
+    addr: SocketAddr,
+    pooled: Client<Connect<C>, BoxBody>,
+    proxied: Arc<Mutex<ProxiedClients<C>>>,
+    connect: Connect<C>,
+}
+
+/// The clients for connections that start with a PROXY header, keyed by that
+/// header.
+#[derive(Debug)]
+struct ProxiedClients<C> {
+    clients: AHashMap<Arc<[u8]>, Proxied<C>>,
+
+    /// Whether a task is sweeping idle clients out of the map. Requests only
+    /// look their client up, so idle clients are never scanned for while a
+    /// request waits on the lock.
+    sweeping: bool,
+}
+
+/// The connections to an endpoint that start with the same PROXY header, and
+/// so describe the same client.
+#[derive(Debug)]
+struct Proxied<C> {
+    client: Client<ProxyConnect<C>, BoxBody>,
+    last_used: Instant,
+}
+
 #[derive(Clone, Debug)]
 pub struct Connect<C> {
     addr: SocketAddr,
     connect: C,
 }
 
+/// Connects to an endpoint and writes a PROXY protocol header before anything
+/// else is sent.
+#[derive(Clone, Debug)]
+pub struct ProxyConnect<C> {
+    connect: Connect<C>,
+    header: Arc<[u8]>,
+}
+
+/// Additional addresses for an endpoint, which requests are balanced across
+/// in round-robin order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Replicas(pub Arc<[SocketAddr]>);
+
+/// Picks which of an endpoint's addresses each request is sent to, in
+/// round-robin order across its [`Replicas`], and records it in the request's
+/// extensions as an [`UpstreamAddr`].
+///
+/// This is pushed above the layers that refer to the endpoint's address, so
+/// that they see the address that the request is actually sent to.
+#[derive(Clone, Debug)]
+pub struct NewPickAddr<N> {
+    inner: N,
+}
+
+#[derive(Clone, Debug)]
+pub struct PickAddr<S> {
+    addrs: Arc<[SocketAddr]>,
+    next: Arc<AtomicUsize>,
+    inner: S,
+}
+
+/// The address a request is sent to, recorded in its extensions by
+/// [`PickAddr`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamAddr(pub SocketAddr);
+
 impl<C> NewClient<C> {
     pub fn layer() -> impl svc::Layer<C, Service = Self> + Clone {
         svc::layer::mk(|connect| Self { connect })
@@ -28,21 +115,189 @@ impl<C> NewClient<C> {
 
 impl<C, T, I> svc::NewService<T> for NewClient<C>
 where
-    C: svc::Service<SocketAddr, Response = I> + Clone + Send + 'static,
-    C::Future: Send + Unpin,
-    C::Error: std::error::Error + Send + Sync,
+    C: svc::Service<SocketAddr, Response = I> + Clone + Send + Sync + 'static,
+    C::Future: Send + Unpin + 'static,
+    C::Error: std::error::Error + Send + Sync + 'static,
     I: io::AsyncRead + io::AsyncWrite + connect::Connection + Unpin + Send + 'static,
-    T: svc::Param<SocketAddr>,
+    T: svc::Param<SocketAddr> + svc::Param<Replicas>,
 {
-    type Service = Client<Connect<C>, Incoming>;
+    type Service = Endpoint<C>;
 
     fn new_service(&self, target: T) -> Self::Service {
-        let addr = target.param();
-        let connect = Connect {
-            addr,
-            connect: self.connect.clone(),
+        let pools = addrs(&target)
+            .iter()
+            .map(|&addr| {
+                let connect = Connect {
+                    addr,
+                    connect: self.connect.clone(),
+                };
+                Pool {
+                    addr,
+                    pooled: Client::builder(TokioExecutor::new()).build(connect.clone()),
+                    proxied: Arc::new(Mutex::new(ProxiedClients {
+                        clients: AHashMap::new(),
+                        sweeping: false,
+                    })),
+                    connect,
+                }
+            })
+            .collect();
+        Endpoint { pools }
+    }
+}
+
+/// Returns the endpoint's own address, followed by its replicas.
+fn addrs<T>(target: &T) -> Arc<[SocketAddr]>
+where
+    T: svc::Param<SocketAddr> + svc::Param<Replicas>,
+{
+    let addr: SocketAddr = target.param();
+    let Replicas(replicas) = target.param();
+    std::iter::once(addr)
+        .chain(replicas.iter().copied())
+        .collect()
+}
+
+// === impl Pool ===
+
+impl<C, I> Pool<C>
+where
+    C: svc::Service<SocketAddr, Response = I> + Clone + Send + Sync + 'static,
+    C::Future: Send + Unpin + 'static,
+    C::Error: std::error::Error + Send + Sync + 'static,
+    I: io::AsyncRead + io::AsyncWrite + connect::Connection + Unpin + Send + 'static,
+{
+    /// How long the connections for a client that start with a PROXY header
+    /// are kept once that client stops sending requests.
+    const PROXIED_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+    /// Returns the client for connections that start with `header`, which
+    /// are only shared by requests from the same client.
+    fn proxied(&self, proxy_header: ProxyHeader) -> Client<ProxyConnect<C>, BoxBody> {
+        let now = Instant::now();
+        let mut proxied = self.proxied.lock().unwrap();
+        if !proxied.sweeping {
+            proxied.sweeping = true;
+            tokio::spawn(Self::sweep(Arc::downgrade(&self.proxied)));
+        }
+        let proxied = proxied
+            .clients
+            .entry(proxy_header.header.clone())
+            .or_insert_with(|| Proxied {
+                client: Client::builder(TokioExecutor::new())
+                    .pool_idle_timeout(Self::PROXIED_IDLE_TIMEOUT)
+                    .build(ProxyConnect {
+                        connect: self.connect.clone(),
+                        header: proxy_header.header,
+                    }),
+                last_used: now,
+            });
+        proxied.last_used = now;
+        proxied.client.clone()
+    }
+
+    /// Periodically drops the proxied clients that have been idle for
+    /// [`Self::PROXIED_IDLE_TIMEOUT`], until none are left or the pool itself
+    /// is dropped.
+    async fn sweep(weak: Weak<Mutex<ProxiedClients<C>>>) {
+        loop {
+            time::sleep(Self::PROXIED_IDLE_TIMEOUT).await;
+            let Some(proxied) = weak.upgrade() else {
+                return;
+            };
+            let mut proxied = proxied.lock().unwrap();
+            let now = Instant::now();
+            proxied.clients.retain(|_, proxied| {
+                now.saturating_duration_since(proxied.last_used) < Self::PROXIED_IDLE_TIMEOUT
+            });
+            if proxied.clients.is_empty() {
+                proxied.sweeping = false;
+                return;
+            }
+        }
+    }
+}
+
+// === impl Endpoint ===
+
+impl<C, I> svc::Service<http::Request<BoxBody>> for Endpoint<C>
+where
+    C: svc::Service<SocketAddr, Response = I> + Clone + Send + Sync + 'static,
+    C::Future: Send + Unpin + 'static,
+    C::Error: std::error::Error + Send + Sync + 'static,
+    I: io::AsyncRead + io::AsyncWrite + connect::Connection + Unpin + Send + 'static,
+{
+    type Response = http::Response<hyper::body::Incoming>;
+    type Error = Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Error>>;
+
+    #[inline]
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: http::Request<BoxBody>) -> Self::Future {
+        let picked = req.extensions().get::<UpstreamAddr>().copied();
+        let pool = picked
+            .and_then(|UpstreamAddr(addr)| self.pools.iter().find(|pool| pool.addr == addr))
+            .unwrap_or(&self.pools[0]);
+        let Some(header) = req.extensions_mut().remove::<ProxyHeader>() else {
+            return pool.pooled.request(req).err_into().boxed();
         };
-        Client::builder(TokioExecutor::new()).build(connect)
+        // A connection that starts with a PROXY header describes a single
+        // client, so it's only reused for that client's requests.
+        pool.proxied(header).request(req).err_into().boxed()
+    }
+}
+
+// === impl NewPickAddr ===
+
+impl<N> NewPickAddr<N> {
+    pub fn layer() -> impl svc::Layer<N, Service = Self> + Clone {
+        svc::layer::mk(|inner| Self { inner })
+    }
+}
+
+impl<T, N> svc::NewService<T> for NewPickAddr<N>
+where
+    T: svc::Param<SocketAddr> + svc::Param<Replicas>,
+    N: svc::NewService<T>,
+{
+    type Service = PickAddr<N::Service>;
+
+    fn new_service(&self, target: T) -> Self::Service {
+        let addrs = addrs(&target);
+        let inner = self.inner.new_service(target);
+        PickAddr {
+            addrs,
+            next: Arc::new(AtomicUsize::new(0)),
+            inner,
+        }
+    }
+}
+
+// === impl PickAddr ===
+
+impl<S, B> svc::Service<http::Request<B>> for PickAddr<S>
+where
+    S: svc::Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        let addr = match *self.addrs {
+            [addr] => addr,
+            ref addrs => addrs[self.next.fetch_add(1, Ordering::Relaxed) % addrs.len()],
+        };
+        req.extensions_mut().insert(UpstreamAddr(addr));
+        self.inner.call(req)
     }
 }
 
@@ -63,3 +318,31 @@ where
         self.connect.call(self.addr)
     }
 }
+
+// === impl ProxyConnect ===
+
+impl<C, I> svc::Service<hyper::Uri> for ProxyConnect<C>
+where
+    C: svc::Service<SocketAddr, Response = I>,
+    C::Future: Send + 'static,
+    C::Error: std::error::Error + Send + Sync + 'static,
+    I: io::AsyncWrite + Unpin + Send + 'static,
+{
+    type Error = Error;
+    type Response = I;
+    type Future = BoxFuture<'static, Result<I, Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.connect.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: hyper::Uri) -> Self::Future {
+        let connect = self.connect.call(uri);
+        let header = self.header.clone();
+        Box::pin(async move {
+            let mut io = connect.await?;
+            io.write_all(&header).await?;
+            Ok(io)
+        })
+    }
+}
@@ -0,0 +1,320 @@
+use super::{
+    forwarded::{
+        X_FORWARDED_FOR, X_FORWARDED_HOST, X_FORWARDED_PORT, X_FORWARDED_PROTO, X_REAL_IP,
+    },
+    rewrite::X_FORWARDED_PREFIX,
+};
+pub use crate::serve::ClientAddr;
+use crate::{serve, svc};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use ipnet::IpNet;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Determines the address of the client that sent each request.
+///
+/// Requests from [`Config::trusted_proxies`](crate::config::Config::trusted_proxies)
+/// are attributed to the client named by their `Forwarded` or
+/// `X-Forwarded-For` header, and the inner stack is built for that client.
+/// Forwarding headers are removed from requests sent by any other client, so
+/// that clients can't pass themselves off as someone else.
+#[derive(Clone, Debug)]
+pub struct NewResolveClientAddr<N> {
+    trusted: Arc<[IpNet]>,
+    inner: N,
+}
+
+#[derive(Clone, Debug)]
+pub struct ResolveClientAddr<N> {
+    accepted: serve::Accepted,
+    trusted: Arc<[IpNet]>,
+    inner: N,
+}
+
+/// The headers that describe how a request was forwarded, which only trusted
+/// proxies may send.
+const FORWARDING_HEADERS: [HeaderName; 7] = [
+    header::FORWARDED,
+    X_FORWARDED_FOR,
+    X_FORWARDED_PROTO,
+    X_FORWARDED_HOST,
+    X_FORWARDED_PORT,
+    X_FORWARDED_PREFIX,
+    X_REAL_IP,
+];
+
+// === impl NewResolveClientAddr ===
+
+impl<N> NewResolveClientAddr<N> {
+    pub fn layer(trusted: Arc<[IpNet]>) -> impl svc::Layer<N, Service = Self> + Clone {
+        svc::layer::mk(move |inner| Self {
+            trusted: trusted.clone(),
+            inner,
+        })
+    }
+}
+
+impl<N: Clone> svc::NewService<serve::Accepted> for NewResolveClientAddr<N> {
+    type Service = ResolveClientAddr<N>;
+
+    fn new_service(&self, accepted: serve::Accepted) -> Self::Service {
+        ResolveClientAddr {
+            accepted,
+            trusted: self.trusted.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+// === impl ResolveClientAddr ===
+
+impl<N, S, B> svc::Service<http::Request<B>> for ResolveClientAddr<N>
+where
+    N: svc::NewService<serve::Accepted, Service = S>,
+    S: svc::Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = linkerd_stack::Oneshot<S, http::Request<B>>;
+
+    #[inline]
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        let peer = self.accepted.client_addr;
+        let client_addr = if is_trusted(&self.trusted, peer.ip) {
+            let client_addr = resolve(&self.trusted, peer, req.headers_mut());
+            if client_addr != peer {
+                tracing::debug!(%client_addr, proxy.addr = %peer, "Resolved client address");
+            }
+            client_addr
+        } else {
+            for name in FORWARDING_HEADERS {
+                if let Some(value) = req.headers_mut().remove(&name) {
+                    tracing::debug!(header = %name, ?value, "Removed forwarding header from untrusted client");
+                }
+            }
+            peer
+        };
+        // The resolved client is recorded in each request's extensions.
+        req.extensions_mut().insert(client_addr);
+        let accepted = serve::Accepted {
+            client_addr,
+            ..self.accepted.clone()
+        };
+        svc::ServiceExt::oneshot(self.inner.new_service(accepted), req)
+    }
+}
+
+fn is_trusted(trusted: &[IpNet], ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    };
+    trusted.iter().any(|net| net.contains(&ip))
+}
+
+/// Returns the address of the client that a request from the trusted proxy
+/// `peer` was forwarded for.
+///
+/// The `Forwarded` header, or the `X-Forwarded-For` header if there is none,
+/// is read from the right, skipping trusted proxies, up to the first address
+/// that isn't trusted. That address, and the hops after it, are removed from
+/// the header, since they are described again when the request is forwarded.
+fn resolve(trusted: &[IpNet], peer: ClientAddr, headers: &mut HeaderMap) -> ClientAddr {
+    let (name, parse): (_, fn(&str) -> Option<ClientAddr>) =
+        if headers.contains_key(header::FORWARDED) {
+            (header::FORWARDED, parse_forwarded_element)
+        } else {
+            (X_FORWARDED_FOR, parse_forwarded_for)
+        };
+    let chain = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(split_list)
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+
+    let mut client_addr = peer;
+    let mut hops = chain.len();
+    while hops > 0 && is_trusted(trusted, client_addr.ip) {
+        match parse(&chain[hops - 1]) {
+            Some(addr) => {
+                client_addr = addr;
+                hops -= 1;
+            }
+            // An obfuscated or unknown address ends the chain.
+            None => break,
+        }
+    }
+
+    if hops < chain.len() {
+        headers.remove(&name);
+        if hops > 0 {
+            match HeaderValue::try_from(chain[..hops].join(", ")) {
+                Ok(value) => {
+                    headers.insert(name, value);
+                }
+                Err(error) => tracing::debug!(%error, "Invalid forwarding chain"),
+            }
+        }
+    }
+    client_addr
+}
+
+/// Splits a comma-separated header value, ignoring commas in quoted strings.
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    let mut quoted = false;
+    let mut escaped = false;
+    value
+        .split(move |c| {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if quoted => escaped = true,
+                '"' => quoted = !quoted,
+                ',' if !quoted => return true,
+                _ => {}
+            }
+            false
+        })
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// Parses the `for=` parameter of an RFC 7239 `Forwarded` element.
+fn parse_forwarded_element(element: &str) -> Option<ClientAddr> {
+    let node = element.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        key.eq_ignore_ascii_case("for").then_some(value.trim())
+    })?;
+    let node = node
+        .strip_prefix('"')
+        .and_then(|node| node.strip_suffix('"'))
+        .unwrap_or(node);
+    parse_node(node)
+}
+
+/// Parses an `X-Forwarded-For` address.
+fn parse_forwarded_for(addr: &str) -> Option<ClientAddr> {
+    parse_node(addr.trim())
+}
+
+/// Parses an IP address, optionally with a port. IPv6 addresses with ports
+/// must be bracketed.
+fn parse_node(node: &str) -> Option<ClientAddr> {
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.into());
+    }
+    let ip = node
+        .strip_prefix('[')
+        .and_then(|n| n.strip_suffix(']'))
+        .unwrap_or(node);
+    let ip = ip.parse::<IpAddr>().ok()?;
+    Some(ClientAddr { ip, port: None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap()]
+    }
+
+    fn addr(addr: &str) -> ClientAddr {
+        addr.parse::<SocketAddr>().unwrap().into()
+    }
+
+    fn ip(ip: &str) -> ClientAddr {
+        ClientAddr {
+            ip: ip.parse().unwrap(),
+            port: None,
+        }
+    }
+
+    #[test]
+    fn nodes() {
+        assert_eq!(
+            parse_node("192.168.1.20"),
+            Some(ip("192.168.1.20")),
+            "addresses without ports aren't given one"
+        );
+        assert_eq!(
+            parse_node("192.168.1.20:4711"),
+            Some(addr("192.168.1.20:4711"))
+        );
+        assert_eq!(
+            parse_node("[2001:db8::17]:4711"),
+            Some(addr("[2001:db8::17]:4711"))
+        );
+        assert_eq!(parse_node("2001:db8::17"), Some(ip("2001:db8::17")));
+        assert_eq!(parse_node("[2001:db8::17]"), Some(ip("2001:db8::17")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+
+        assert_eq!(
+            parse_forwarded_element("by=10.0.0.1;For=\"[2001:db8::17]:4711\";proto=https"),
+            Some(addr("[2001:db8::17]:4711"))
+        );
+        assert_eq!(
+            split_list("for=a;host=\"x,y\", for=b").collect::<Vec<_>>(),
+            ["for=a;host=\"x,y\"", "for=b"]
+        );
+
+        assert_eq!(ip("192.168.1.20").to_string(), "192.168.1.20");
+        assert_eq!(
+            addr("[2001:db8::17]:4711").to_string(),
+            "[2001:db8::17]:4711"
+        );
+    }
+
+    #[test]
+    fn resolves_x_forwarded_for() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("203.0.113.1, 198.51.100.2, 10.0.0.2"),
+        );
+        let peer = addr("10.0.0.1:51234");
+        let client_addr = resolve(&trusted(), peer, &mut headers);
+        assert_eq!(client_addr, ip("198.51.100.2"), "spoofed hops are ignored");
+        assert_eq!(headers[X_FORWARDED_FOR], "203.0.113.1");
+
+        let mut headers = HeaderMap::new();
+        let client_addr = resolve(&trusted(), peer, &mut headers);
+        assert_eq!(client_addr, peer, "the proxy itself sent the request");
+    }
+
+    #[test]
+    fn resolves_forwarded() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::FORWARDED,
+            HeaderValue::from_static("for=\"[2001:db8::17]:4711\";proto=https, for=10.0.0.2"),
+        );
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("203.0.113.1"));
+        let peer = addr("10.0.0.1:51234");
+        let client_addr = resolve(&trusted(), peer, &mut headers);
+        assert_eq!(client_addr, addr("[2001:db8::17]:4711"));
+        assert!(!headers.contains_key(header::FORWARDED));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::FORWARDED,
+            HeaderValue::from_static("for=unknown, for=10.0.0.2"),
+        );
+        let client_addr = resolve(&trusted(), peer, &mut headers);
+        assert_eq!(
+            client_addr,
+            ip("10.0.0.2"),
+            "unknown addresses end the chain"
+        );
+        assert_eq!(headers[header::FORWARDED], "for=unknown");
+    }
+}
@@ -1,5 +1,8 @@
 use crate::svc;
-use super::box_body::{self, BoxBody};
+use super::{
+    box_body::{self, BoxBody},
+    client_addr::ClientAddr,
+};
 use http::header::{HeaderValue, LOCATION};
 use linkerd_app_core::{Error, Result, proxy::http::ClientHandle};
 use linkerd_error_respond as respond;
@@ -9,6 +12,9 @@ use std::{
 };
 use tracing::{debug, info_span};
 
+/// The gateway's `Server` header value.
+pub const VERSION: &str = concat!("multipass/", env!("CARGO_PKG_VERSION"));
+
 pub fn layer<R, P: Clone, N>(
     params: P,
 ) -> impl svc::layer::Layer<N, Service = NewRespondService<R, P, N>> + Clone {
@@ -24,13 +30,22 @@ pub trait HttpRescue<E> {
     fn rescue(&self, error: E) -> Result<SyntheticHttpResponse, E>;
 }
 
-#[derive(Clone, Debug)]
+/// A response generated by the gateway itself, rather than by an upstream
+/// service.
+///
+/// This is also an error, so that a service can return it in place of
+/// forwarding a request. [`Respond`] renders it rather than treating it as a
+/// failure.
+#[derive(Clone, Debug, thiserror::Error)]
+#[error("{message}")]
 pub struct SyntheticHttpResponse {
     // grpc_status: tonic::Code,
     http_status: http::StatusCode,
     close_connection: bool,
     message: Cow<'static, str>,
     location: Option<HeaderValue>,
+    headers: http::HeaderMap,
+    body: Option<bytes::Bytes>,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -50,6 +65,7 @@ pub struct Respond<R> {
     version: http::Version,
     is_grpc: bool,
     client: Option<ClientHandle>,
+    client_addr: Option<ClientAddr>,
     accept: ContentType,
 }
 
@@ -90,6 +106,8 @@ impl SyntheticHttpResponse {
             // grpc_status: tonic::Code::Internal,
             message: msg.into(),
             location: None,
+            headers: http::HeaderMap::new(),
+            body: None,
         }
     }
 
@@ -100,6 +118,8 @@ impl SyntheticHttpResponse {
             // grpc_status: tonic::Code::Unavailable,
             message: Cow::Owned(msg.to_string()),
             location: None,
+            headers: http::HeaderMap::new(),
+            body: None,
         }
     }
 
@@ -110,6 +130,8 @@ impl SyntheticHttpResponse {
             // grpc_status: tonic::Code::Unavailable,
             message: Cow::Owned(msg.to_string()),
             location: None,
+            headers: http::HeaderMap::new(),
+            body: None,
         }
     }
 
@@ -120,6 +142,8 @@ impl SyntheticHttpResponse {
             // grpc_status: tonic::Code::Unavailable,
             message: Cow::Owned(msg.to_string()),
             location: None,
+            headers: http::HeaderMap::new(),
+            body: None,
         }
     }
 
@@ -130,6 +154,8 @@ impl SyntheticHttpResponse {
             close_connection: false,
             message: Cow::Owned(msg.to_string()),
             location: None,
+            headers: http::HeaderMap::new(),
+            body: None,
         }
     }
 
@@ -140,6 +166,8 @@ impl SyntheticHttpResponse {
             close_connection: false,
             message: Cow::Owned(msg.to_string()),
             location: None,
+            headers: http::HeaderMap::new(),
+            body: None,
         }
     }
 
@@ -150,6 +178,8 @@ impl SyntheticHttpResponse {
             close_connection: true,
             message: Cow::Owned(msg.to_string()),
             location: None,
+            headers: http::HeaderMap::new(),
+            body: None,
         }
     }
 
@@ -160,6 +190,8 @@ impl SyntheticHttpResponse {
             close_connection: false,
             message: Cow::Owned(msg.to_string()),
             location: None,
+            headers: http::HeaderMap::new(),
+            body: None,
         }
     }
 
@@ -173,6 +205,8 @@ impl SyntheticHttpResponse {
                 HeaderValue::try_from(location.to_string())
                     .expect("location must be a valid header value"),
             ),
+            headers: http::HeaderMap::new(),
+            body: None,
         }
     }
 
@@ -180,12 +214,31 @@ impl SyntheticHttpResponse {
         Self {
             http_status,
             location: None,
+            headers: http::HeaderMap::new(),
+            body: None,
             // grpc_status: tonic::Code::FailedPrecondition,
             close_connection: false,
             message: message.into(),
         }
     }
 
+    /// A response with a fixed status, headers, and body. If `body` is unset,
+    /// the response has a status page, in the client's preferred format.
+    pub fn fixed(
+        http_status: http::StatusCode,
+        headers: http::HeaderMap,
+        body: Option<bytes::Bytes>,
+    ) -> Self {
+        Self {
+            http_status,
+            location: None,
+            close_connection: false,
+            message: Cow::Borrowed(http_status.canonical_reason().unwrap_or("")),
+            headers,
+            body,
+        }
+    }
+
     // pub fn grpc(grpc_status: tonic::Code, message: impl Into<Cow<'static, str>>) -> Self {
     //     Self {
     //         grpc_status,
@@ -204,7 +257,6 @@ impl SyntheticHttpResponse {
     ) -> http::Response<super::BoxBody> {
         #![allow(clippy::declare_interior_mutable_const)]
 
-        const VERSION: &str = concat!("multipass/", env!("CARGO_PKG_VERSION"));
         const SERVER_HEADER: http::HeaderValue = http::HeaderValue::from_static(VERSION);
         const CLOSE_HEADER: http::HeaderValue = http::HeaderValue::from_static("close");
 
@@ -229,6 +281,16 @@ impl SyntheticHttpResponse {
             rsp = rsp.header(LOCATION, loc);
         }
 
+        if let Some(headers) = rsp.headers_mut() {
+            headers.extend(self.headers.clone());
+        }
+
+        if let Some(body) = &self.body {
+            return rsp
+                .body(box_body::boxed(http_body_util::Full::new(body.clone())))
+                .unwrap();
+        }
+
         let message = match content_type {
             ContentType::Plaintext => {
                 rsp = rsp.header(http::header::CONTENT_TYPE, ContentType::PLAINTEXT);
@@ -284,6 +346,7 @@ where
 
     fn new_respond(&self, req: &http::Request<B>) -> Self::Respond {
         let client = req.extensions().get::<ClientHandle>().cloned();
+        let client_addr = req.extensions().get::<ClientAddr>().copied();
         // debug_assert!(client.is_some(), "Missing client handle");
 
         let rescue = self.rescue.clone();
@@ -304,6 +367,7 @@ where
                     .unwrap_or(false);
                 Respond {
                     client,
+                    client_addr,
                     rescue,
                     is_grpc,
                     version: http::Version::HTTP_2,
@@ -313,6 +377,7 @@ where
             version => {
                 Respond {
                     client,
+                    client_addr,
                     rescue,
                     version,
                     is_grpc: false,
@@ -326,13 +391,12 @@ where
 // === impl Respond ===
 
 impl<R> Respond<R> {
-    fn client_addr(&self) -> std::net::SocketAddr {
-        self.client
-            .as_ref()
-            .map(|ClientHandle { addr, .. }| *addr)
+    fn client_addr(&self) -> ClientAddr {
+        self.client_addr
+            .or_else(|| self.client.as_ref().map(|ClientHandle { addr, .. }| (*addr).into()))
             .unwrap_or_else(|| {
                 tracing::debug!("Missing client address");
-                ([0, 0, 0, 0], 0).into()
+                ClientAddr { ip: [0, 0, 0, 0].into(), port: None }
             })
     }
 }
@@ -349,6 +413,12 @@ where
             Err(error) => error,
         };
 
+        // Routes may answer requests themselves, rather than forwarding them.
+        let error = match error.downcast::<SyntheticHttpResponse>() {
+            Ok(rsp) => return Ok(rsp.http_response(self.version, self.accept)),
+            Err(error) => error,
+        };
+
         let rsp = info_span!("rescue", client.addr = %self.client_addr()).in_scope(|| {
             if !self.is_grpc {
                 let version = self.version;
@@ -0,0 +1,393 @@
+//! Serves files from a local directory, for routes whose action is
+//! [`route::Action::Files`].
+
+use super::{box_body, error_respond::SyntheticHttpResponse, BoxBody};
+use bytes::BytesMut;
+use http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use http_body::Frame;
+use http_body_util::{Empty, StreamBody};
+use linkerd_app_core::Error;
+use std::{
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Precompressed variants of a file, in order of preference, as the
+/// `Content-Encoding` and the file extension they are stored with.
+const ENCODINGS: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+const INDEX: &str = "index.html";
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The file that a request resolved to.
+struct File {
+    path: PathBuf,
+    len: u64,
+    modified: SystemTime,
+    encoding: Option<&'static str>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Satisfiable(Range<u64>),
+    Unsatisfiable,
+}
+
+/// Responds to a request for `path` with the file it names in `root`.
+///
+/// `path` is the request's path, after the route's rewrites are applied.
+pub(super) async fn serve(
+    root: PathBuf,
+    path: String,
+    req: http::request::Parts,
+) -> Result<http::Response<BoxBody>, Error> {
+    if req.method != Method::GET && req.method != Method::HEAD {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ALLOW, HeaderValue::from_static("GET, HEAD"));
+        return Err(
+            SyntheticHttpResponse::fixed(StatusCode::METHOD_NOT_ALLOWED, headers, None).into(),
+        );
+    }
+
+    let Some(relative) = relative_path(&path) else {
+        tracing::info!(%path, "Rejecting request for a path outside of the served directory");
+        return Err(
+            SyntheticHttpResponse::response(StatusCode::BAD_REQUEST, "invalid path").into(),
+        );
+    };
+
+    let mut file_path = root.join(relative);
+    let mut metadata = self::metadata(&file_path).await?;
+    if metadata.is_dir() {
+        if !req.uri.path().ends_with('/') {
+            // Relative links in the index must resolve within the directory.
+            let location = match req.uri.query() {
+                Some(query) => format!("{}/?{query}", req.uri.path()),
+                None => format!("{}/", req.uri.path()),
+            };
+            let location = location.parse::<http::Uri>()?;
+            return Err(
+                SyntheticHttpResponse::redirect(StatusCode::MOVED_PERMANENTLY, &location).into(),
+            );
+        }
+        file_path.push(INDEX);
+        metadata = self::metadata(&file_path).await?;
+    }
+    if !metadata.is_file() {
+        return Err(SyntheticHttpResponse::not_found("file not found").into());
+    }
+
+    let content_type = mime_guess::from_path(&file_path).first_or_octet_stream();
+    let file = File::negotiate(&root, file_path, metadata, &req.headers).await?;
+    let etag = file.etag();
+    let last_modified = httpdate::fmt_http_date(file.modified);
+
+    let mut rsp = http::Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::LAST_MODIFIED, &last_modified)
+        .header(header::VARY, "accept-encoding");
+    if file.is_not_modified(&req.headers, &etag) {
+        return Ok(rsp
+            .status(StatusCode::NOT_MODIFIED)
+            .body(box_body::boxed(Empty::new()))?);
+    }
+
+    rsp = rsp
+        .header(header::CONTENT_TYPE, content_type.as_ref())
+        .header(header::ACCEPT_RANGES, "bytes");
+    if let Some(encoding) = file.encoding {
+        rsp = rsp.header(header::CONTENT_ENCODING, encoding);
+    }
+
+    let range = req
+        .headers
+        .get(header::RANGE)
+        .filter(|_| req.method == Method::GET && file.if_range(&req.headers, &etag))
+        .and_then(|range| parse_range(range.to_str().ok()?, file.len));
+    let (status, range) = match range {
+        None => (StatusCode::OK, 0..file.len),
+        Some(ByteRange::Satisfiable(range)) => {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, file.len);
+            rsp = rsp.header(header::CONTENT_RANGE, content_range);
+            (StatusCode::PARTIAL_CONTENT, range)
+        }
+        Some(ByteRange::Unsatisfiable) => {
+            return Ok(rsp
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", file.len))
+                .body(box_body::boxed(Empty::new()))?);
+        }
+    };
+    rsp = rsp
+        .status(status)
+        .header(header::CONTENT_LENGTH, range.end - range.start);
+
+    if req.method == Method::HEAD {
+        return Ok(rsp.body(box_body::boxed(Empty::new()))?);
+    }
+    let body = file.read(range).await?;
+    Ok(rsp.body(body)?)
+}
+
+/// Converts a request path to a path relative to the served directory.
+///
+/// Returns `None` if the path could refer to anything outside of it.
+fn relative_path(path: &str) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for segment in path.split('/') {
+        let segment = percent_encoding::percent_decode_str(segment)
+            .decode_utf8()
+            .ok()?;
+        match &*segment {
+            "" | "." => {}
+            ".." => return None,
+            segment if segment.contains(['/', '\\', '\0', ':']) => return None,
+            segment => relative.push(segment),
+        }
+    }
+    Some(relative)
+}
+
+/// Resolves the symlinks in `path`, returning `None` if it resolves to
+/// somewhere outside of `root`.
+async fn resolve_within(root: &Path, path: &Path) -> io::Result<Option<PathBuf>> {
+    let root = tokio::fs::canonicalize(root).await?;
+    let path = tokio::fs::canonicalize(path).await?;
+    Ok(path.starts_with(&root).then_some(path))
+}
+
+async fn metadata(path: &Path) -> Result<std::fs::Metadata, Error> {
+    tokio::fs::metadata(path)
+        .await
+        .map_err(|error| match error.kind() {
+            io::ErrorKind::NotFound => SyntheticHttpResponse::not_found("file not found").into(),
+            io::ErrorKind::PermissionDenied => {
+                SyntheticHttpResponse::permission_denied("permission denied").into()
+            }
+            _ => error.into(),
+        })
+}
+
+/// Parses a `Range` header with a single range of bytes.
+///
+/// Returns `None` if the header should be ignored, in which case the whole
+/// file is sent.
+fn parse_range(range: &str, len: u64) -> Option<ByteRange> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    if range.contains(',') {
+        // Multipart responses aren't supported.
+        return None;
+    }
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        let suffix = end.parse::<u64>().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        return Some(ByteRange::Satisfiable(len.saturating_sub(suffix)..len));
+    }
+
+    let start = start.parse::<u64>().ok()?;
+    if start >= len {
+        return Some(ByteRange::Unsatisfiable);
+    }
+    let end = match end {
+        "" => len - 1,
+        end => end.parse::<u64>().ok()?.min(len - 1),
+    };
+    if end < start {
+        return None;
+    }
+    Some(ByteRange::Satisfiable(start..end + 1))
+}
+
+/// Returns `true` if the request's `Accept-Encoding` allows `encoding`.
+fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let q = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            name.eq_ignore_ascii_case(encoding) && q > 0.0
+        })
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+// === impl File ===
+
+impl File {
+    /// Picks the precompressed variant of `path` that the client prefers, if
+    /// there is one.
+    ///
+    /// Files are opened by their canonical paths, and those that symlinks
+    /// lead outside of `root` are never served.
+    async fn negotiate(
+        root: &Path,
+        path: PathBuf,
+        metadata: std::fs::Metadata,
+        headers: &HeaderMap,
+    ) -> Result<Self, Error> {
+        for (encoding, extension) in ENCODINGS {
+            if !accepts_encoding(headers, encoding) {
+                continue;
+            }
+            let mut variant = path.clone().into_os_string();
+            variant.push(".");
+            variant.push(extension);
+            let Ok(Some(variant)) = resolve_within(root, Path::new(&variant)).await else {
+                continue;
+            };
+            match tokio::fs::metadata(&variant).await {
+                Ok(metadata) if metadata.is_file() => {
+                    return Ok(Self {
+                        len: metadata.len(),
+                        modified: metadata.modified()?,
+                        path: variant,
+                        encoding: Some(encoding),
+                    });
+                }
+                _ => continue,
+            }
+        }
+
+        let Some(resolved) = resolve_within(root, &path).await? else {
+            tracing::info!(path = %path.display(), "Rejecting request for a file outside of the served directory");
+            return Err(SyntheticHttpResponse::not_found("file not found").into());
+        };
+        Ok(Self {
+            len: metadata.len(),
+            modified: metadata.modified()?,
+            path: resolved,
+            encoding: None,
+        })
+    }
+
+    fn etag(&self) -> String {
+        let secs = unix_secs(self.modified);
+        match self.encoding {
+            Some(encoding) => format!("\"{:x}-{secs:x}-{encoding}\"", self.len),
+            None => format!("\"{:x}-{secs:x}\"", self.len),
+        }
+    }
+
+    /// Returns `true` if the client's cached copy of the file is current.
+    fn is_not_modified(&self, headers: &HeaderMap, etag: &str) -> bool {
+        if let Some(tags) = headers.get(header::IF_NONE_MATCH) {
+            let Ok(tags) = tags.to_str() else {
+                return false;
+            };
+            return tags
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+        }
+
+        headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|since| httpdate::parse_http_date(since.to_str().ok()?).ok())
+            .is_some_and(|since| unix_secs(self.modified) <= unix_secs(since))
+    }
+
+    /// Returns `true` if a `Range` request should be honored, because it has
+    /// no `If-Range` condition or the condition holds.
+    fn if_range(&self, headers: &HeaderMap, etag: &str) -> bool {
+        let Some(condition) = headers.get(header::IF_RANGE) else {
+            return true;
+        };
+        let Ok(condition) = condition.to_str() else {
+            return false;
+        };
+        if condition.starts_with('"') {
+            return condition == etag;
+        }
+        httpdate::parse_http_date(condition)
+            .is_ok_and(|date| unix_secs(date) == unix_secs(self.modified))
+    }
+
+    async fn read(&self, range: Range<u64>) -> io::Result<BoxBody> {
+        let mut file = tokio::fs::File::open(&self.path).await?;
+        file.seek(io::SeekFrom::Start(range.start)).await?;
+        let file = file.take(range.end - range.start);
+        let chunks = futures::stream::try_unfold(file, |mut file| async move {
+            let mut buf = BytesMut::with_capacity(CHUNK_SIZE);
+            if file.read_buf(&mut buf).await? == 0 {
+                return Ok(None);
+            }
+            Ok(Some((Frame::data(buf.freeze()), file)))
+        });
+        Ok(box_body::boxed(StreamBody::new(chunks)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(relative_path("/"), Some(PathBuf::new()));
+        assert_eq!(
+            relative_path("/css/./site.css"),
+            Some(PathBuf::from("css/site.css"))
+        );
+        assert_eq!(
+            relative_path("/hello%20world.txt"),
+            Some(PathBuf::from("hello world.txt"))
+        );
+
+        assert_eq!(relative_path("/../etc/passwd"), None);
+        assert_eq!(relative_path("/css/../../etc/passwd"), None);
+        assert_eq!(relative_path("/%2e%2e/etc/passwd"), None);
+        assert_eq!(relative_path("/..%2fetc/passwd"), None);
+        assert_eq!(relative_path("/..%5cetc"), None);
+        assert_eq!(relative_path("/index.html%00.txt"), None);
+    }
+
+    #[test]
+    fn ranges() {
+        use ByteRange::*;
+        assert_eq!(parse_range("bytes=0-9", 100), Some(Satisfiable(0..10)));
+        assert_eq!(parse_range("bytes=90-", 100), Some(Satisfiable(90..100)));
+        assert_eq!(parse_range("bytes=90-200", 100), Some(Satisfiable(90..100)));
+        assert_eq!(parse_range("bytes=-10", 100), Some(Satisfiable(90..100)));
+        assert_eq!(parse_range("bytes=-200", 100), Some(Satisfiable(0..100)));
+        assert_eq!(parse_range("bytes=100-", 100), Some(Unsatisfiable));
+        assert_eq!(parse_range("bytes=-0", 100), Some(Unsatisfiable));
+
+        assert_eq!(parse_range("bytes=10-5", 100), None);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("items=0-1", 100), None);
+    }
+
+    #[test]
+    fn encodings() {
+        let mut headers = HeaderMap::new();
+        assert!(!accepts_encoding(&headers, "gzip"));
+
+        headers.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip, deflate;q=0.5, br;q=0"),
+        );
+        assert!(accepts_encoding(&headers, "gzip"));
+        assert!(accepts_encoding(&headers, "deflate"));
+        assert!(!accepts_encoding(&headers, "br"));
+    }
+}
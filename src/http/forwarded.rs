@@ -0,0 +1,265 @@
+use super::Route;
+use crate::{
+    route::{ForwardedHeader, Forwarding, ForwardingMode},
+    serve, svc,
+};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use std::{
+    net::{IpAddr, SocketAddr},
+    task::{Context, Poll},
+};
+
+/// Adds the headers that tell a route's service how each request was
+/// forwarded to it, as configured by the route's [`Forwarding`].
+#[derive(Clone, Debug)]
+pub struct NewForwarded<N> {
+    inner: N,
+}
+
+#[derive(Clone, Debug)]
+pub struct Forwarded<S> {
+    forwarding: Forwarding,
+    client_addr: serve::ClientAddr,
+    local_addr: SocketAddr,
+    inner: S,
+}
+
+pub const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+pub const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+pub const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+pub const X_FORWARDED_PORT: HeaderName = HeaderName::from_static("x-forwarded-port");
+pub const X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+/// How the client addressed a request.
+#[derive(Debug)]
+struct Origin {
+    proto: String,
+    host: Option<String>,
+}
+
+// === impl NewForwarded ===
+
+impl<N> NewForwarded<N> {
+    pub fn layer() -> impl svc::Layer<N, Service = Self> + Clone {
+        svc::layer::mk(|inner| Self { inner })
+    }
+}
+
+impl<N> svc::NewService<Route<serve::Accepted>> for NewForwarded<N>
+where
+    N: svc::NewService<Route<serve::Accepted>>,
+{
+    type Service = Forwarded<N::Service>;
+
+    fn new_service(&self, route: Route<serve::Accepted>) -> Self::Service {
+        let forwarding = route.forwarding().clone();
+        let client_addr = route.parent.client_addr;
+        let local_addr = route.parent.local_addr;
+        let inner = self.inner.new_service(route);
+        Forwarded {
+            forwarding,
+            client_addr,
+            local_addr,
+            inner,
+        }
+    }
+}
+
+// === impl Forwarded ===
+
+impl<S, B> svc::Service<http::Request<B>> for Forwarded<S>
+where
+    S: svc::Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        let origin = Origin::from_request(&req);
+        let values = self
+            .forwarding
+            .headers
+            .iter()
+            .flat_map(|header| self.values(*header, &origin))
+            .collect::<Vec<_>>();
+
+        let headers = req.headers_mut();
+        if self.forwarding.mode == ForwardingMode::Replace {
+            for name in [
+                header::FORWARDED,
+                X_FORWARDED_FOR,
+                X_FORWARDED_PROTO,
+                X_FORWARDED_HOST,
+                X_FORWARDED_PORT,
+                X_REAL_IP,
+            ] {
+                headers.remove(name);
+            }
+        }
+        for (name, value) in values {
+            set(headers, name, value);
+        }
+        self.inner.call(req)
+    }
+}
+
+impl<S> Forwarded<S> {
+    fn values(&self, header: ForwardedHeader, origin: &Origin) -> Vec<(HeaderName, String)> {
+        let client_ip = self.client_addr.ip;
+        match header {
+            ForwardedHeader::Forwarded => {
+                let mut element = format!(
+                    "for={};by={}",
+                    forwarded_node(client_ip, None),
+                    forwarded_node(self.local_addr.ip(), Some(self.local_addr.port())),
+                );
+                if let Some(ref host) = origin.host {
+                    element.push_str(";host=");
+                    element.push_str(&forwarded_value(host));
+                }
+                element.push_str(";proto=");
+                element.push_str(&origin.proto);
+                vec![(header::FORWARDED, element)]
+            }
+            ForwardedHeader::XForwarded => {
+                let mut values = vec![
+                    (X_FORWARDED_FOR, client_ip.to_string()),
+                    (X_FORWARDED_PROTO, origin.proto.to_string()),
+                    (X_FORWARDED_PORT, self.local_addr.port().to_string()),
+                ];
+                if let Some(ref host) = origin.host {
+                    values.push((X_FORWARDED_HOST, host.clone()));
+                }
+                values
+            }
+            ForwardedHeader::XRealIp => vec![(X_REAL_IP, client_ip.to_string())],
+        }
+    }
+}
+
+/// Adds a forwarding header. The `Forwarded` and `X-Forwarded-For` chains are
+/// extended by this hop; other headers keep any value set by a previous proxy,
+/// since that value describes the original request.
+fn set(headers: &mut HeaderMap, name: HeaderName, value: String) {
+    let is_chain = name == header::FORWARDED || name == X_FORWARDED_FOR;
+    let value = if is_chain {
+        headers
+            .get_all(&name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .chain(Some(value.as_str()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    } else {
+        value
+    };
+    let value = match HeaderValue::try_from(value) {
+        Ok(value) => value,
+        Err(error) => {
+            tracing::debug!(%error, header = %name, "Invalid forwarding header value");
+            return;
+        }
+    };
+    if is_chain {
+        headers.insert(name, value);
+    } else {
+        headers.entry(name).or_insert(value);
+    }
+}
+
+/// Formats an address as an RFC 7239 node. IPv6 addresses, and addresses
+/// with ports, are bracketed and quoted as the RFC requires.
+fn forwarded_node(ip: IpAddr, port: Option<u16>) -> String {
+    match (ip, port) {
+        (IpAddr::V4(ip), None) => ip.to_string(),
+        (IpAddr::V4(ip), Some(port)) => format!("\"{ip}:{port}\""),
+        (IpAddr::V6(ip), None) => format!("\"[{ip}]\""),
+        (IpAddr::V6(ip), Some(port)) => format!("\"[{ip}]:{port}\""),
+    }
+}
+
+/// Formats an RFC 7239 value as a token, or as a quoted string if it contains
+/// characters that a token can't.
+fn forwarded_value(value: &str) -> String {
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if !value.is_empty() && value.chars().all(is_tchar) {
+        return value.to_string();
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+// === impl Origin ===
+
+impl Origin {
+    fn from_request<B>(req: &http::Request<B>) -> Self {
+        let proto = req.uri().scheme_str().unwrap_or("http");
+        let host = req.uri().authority().map(|a| a.as_str()).or_else(|| {
+            req.headers()
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+        });
+        Self {
+            proto: proto.to_string(),
+            host: host.map(str::to_string),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwarded_values() {
+        assert_eq!(
+            forwarded_node([192, 168, 1, 20].into(), None),
+            "192.168.1.20"
+        );
+        assert_eq!(
+            forwarded_node([192, 168, 1, 1].into(), Some(8080)),
+            "\"192.168.1.1:8080\""
+        );
+        let ip = "2001:db8:cafe::17".parse().unwrap();
+        assert_eq!(forwarded_node(ip, None), "\"[2001:db8:cafe::17]\"");
+        assert_eq!(
+            forwarded_node(ip, Some(4711)),
+            "\"[2001:db8:cafe::17]:4711\""
+        );
+
+        assert_eq!(forwarded_value("example.com"), "example.com");
+        assert_eq!(forwarded_value("example.com:8080"), "\"example.com:8080\"");
+        assert_eq!(forwarded_value("a\"b"), "\"a\\\"b\"");
+    }
+
+    #[test]
+    fn chains() {
+        let mut headers = HeaderMap::new();
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_static("203.0.113.1"));
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_static("198.51.100.2"));
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("https"));
+        set(&mut headers, X_FORWARDED_FOR, "192.168.1.20".to_string());
+        set(&mut headers, X_FORWARDED_PROTO, "http".to_string());
+        set(&mut headers, X_REAL_IP, "192.168.1.20".to_string());
+        assert_eq!(
+            headers[X_FORWARDED_FOR],
+            "203.0.113.1, 198.51.100.2, 192.168.1.20"
+        );
+        assert_eq!(headers[X_FORWARDED_PROTO], "https");
+        assert_eq!(headers[X_REAL_IP], "192.168.1.20");
+    }
+}
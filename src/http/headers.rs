@@ -0,0 +1,221 @@
+use super::{Route, UpstreamAddr};
+use crate::{route, serve, svc};
+use futures::TryFuture;
+use http::header;
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Records the [`route::HeaderRules`] of the rule that each request matched,
+/// along with the attributes that their templates may refer to, so that they
+/// can be applied by [`NewUpstreamHeaders`] once an endpoint is chosen.
+#[derive(Clone, Debug)]
+pub struct NewRouteHeaders<N> {
+    inner: N,
+}
+
+#[derive(Clone, Debug)]
+pub struct RouteHeaders<S> {
+    target: Option<Arc<RouteTarget>>,
+    inner: S,
+}
+
+/// Applies the [`route::HeaderRules`] recorded by [`RouteHeaders`] to
+/// requests sent to an endpoint, and to the endpoint's responses.
+///
+/// `{upstream_addr}` refers to the [`UpstreamAddr`] that each request is sent
+/// to, which may be one of the endpoint's replicas.
+#[derive(Clone, Debug)]
+pub struct NewUpstreamHeaders<N> {
+    inner: N,
+}
+
+#[derive(Clone, Debug)]
+pub struct UpstreamHeaders<S> {
+    upstream_addr: SocketAddr,
+    inner: S,
+}
+
+#[pin_project::pin_project]
+pub struct ResponseFuture<F> {
+    #[pin]
+    future: F,
+    rules: Option<(RouteRequest, SocketAddr)>,
+}
+
+/// The attributes of a route that are known when it is built.
+#[derive(Debug)]
+struct RouteTarget {
+    rules: Arc<route::HeaderRules>,
+    client_addr: serve::ClientAddr,
+    route: crate::discover::Name,
+}
+
+/// Recorded in a request's extensions by [`RouteHeaders`].
+#[derive(Clone, Debug)]
+struct RouteRequest {
+    target: Arc<RouteTarget>,
+    host: Option<String>,
+}
+
+// === impl NewRouteHeaders ===
+
+impl<N> NewRouteHeaders<N> {
+    pub fn layer() -> impl svc::Layer<N, Service = Self> + Clone {
+        svc::layer::mk(|inner| Self { inner })
+    }
+}
+
+impl<N> svc::NewService<Route<serve::Accepted>> for NewRouteHeaders<N>
+where
+    N: svc::NewService<Route<serve::Accepted>>,
+{
+    type Service = RouteHeaders<N::Service>;
+
+    fn new_service(&self, route: Route<serve::Accepted>) -> Self::Service {
+        let target = (!route.headers().is_empty()).then(|| {
+            Arc::new(RouteTarget {
+                rules: route.headers().clone(),
+                client_addr: route.parent.client_addr,
+                route: route.name.clone(),
+            })
+        });
+        let inner = self.inner.new_service(route);
+        RouteHeaders { target, inner }
+    }
+}
+
+// === impl RouteHeaders ===
+
+impl<S, B> svc::Service<http::Request<B>> for RouteHeaders<S>
+where
+    S: svc::Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        if let Some(ref target) = self.target {
+            let host = request_host(&req);
+            req.extensions_mut().insert(RouteRequest {
+                target: target.clone(),
+                host,
+            });
+        }
+        self.inner.call(req)
+    }
+}
+
+fn request_host<B>(req: &http::Request<B>) -> Option<String> {
+    let authority = match req.uri().authority() {
+        Some(authority) => authority.clone(),
+        None => req
+            .headers()
+            .get(header::HOST)?
+            .to_str()
+            .ok()?
+            .parse::<http::uri::Authority>()
+            .ok()?,
+    };
+    Some(authority.host().trim_end_matches('.').to_ascii_lowercase())
+}
+
+// === impl NewUpstreamHeaders ===
+
+impl<N> NewUpstreamHeaders<N> {
+    pub fn layer() -> impl svc::Layer<N, Service = Self> + Clone {
+        svc::layer::mk(|inner| Self { inner })
+    }
+}
+
+impl<T, N> svc::NewService<T> for NewUpstreamHeaders<N>
+where
+    T: svc::Param<SocketAddr>,
+    N: svc::NewService<T>,
+{
+    type Service = UpstreamHeaders<N::Service>;
+
+    fn new_service(&self, target: T) -> Self::Service {
+        let upstream_addr = target.param();
+        let inner = self.inner.new_service(target);
+        UpstreamHeaders {
+            upstream_addr,
+            inner,
+        }
+    }
+}
+
+// === impl UpstreamHeaders ===
+
+impl<S, ReqB, RspB> svc::Service<http::Request<ReqB>> for UpstreamHeaders<S>
+where
+    S: svc::Service<http::Request<ReqB>, Response = http::Response<RspB>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<ReqB>) -> Self::Future {
+        let rules = req.extensions_mut().remove::<RouteRequest>();
+        let upstream_addr = req
+            .extensions()
+            .get::<UpstreamAddr>()
+            .map_or(self.upstream_addr, |&UpstreamAddr(addr)| addr);
+        if let Some(ref rules) = rules {
+            let cx = rules.context(upstream_addr);
+            rules.target.rules.request.apply(req.headers_mut(), &cx);
+            tracing::trace!(headers = ?req.headers(), "Applied request header rules");
+        }
+        ResponseFuture {
+            future: self.inner.call(req),
+            rules: rules.map(|rules| (rules, upstream_addr)),
+        }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F, B> Future for ResponseFuture<F>
+where
+    F: TryFuture<Ok = http::Response<B>>,
+{
+    type Output = Result<F::Ok, F::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let mut rsp = futures::ready!(this.future.try_poll(cx))?;
+        if let Some((rules, upstream_addr)) = this.rules.take() {
+            let cx = rules.context(upstream_addr);
+            rules.target.rules.response.apply(rsp.headers_mut(), &cx);
+        }
+        Poll::Ready(Ok(rsp))
+    }
+}
+
+// === impl RouteRequest ===
+
+impl RouteRequest {
+    fn context(&self, upstream_addr: SocketAddr) -> route::TemplateContext<'_> {
+        route::TemplateContext {
+            client_addr: self.target.client_addr,
+            host: self.host.as_deref(),
+            route: &self.target.route,
+            upstream_addr,
+        }
+    }
+}
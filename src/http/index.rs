@@ -0,0 +1,182 @@
+use super::{box_body, error_respond, BoxBody};
+use crate::{config::ListedService, discover, svc};
+use futures::future::{self, BoxFuture};
+use http::header;
+use linkerd_app_core::Error;
+use std::{fmt::Write, sync::Arc};
+
+/// Lists the services in [`Config::index`](crate::config::Config::index),
+/// with their public URLs and whether they are currently resolved, as HTML
+/// or JSON. Services resolved only from the persisted discovery state, which
+/// haven't been announced since the gateway started, are marked as stale.
+#[derive(Clone, Debug)]
+pub struct ServiceIndex<D> {
+    services: Arc<[ListedService]>,
+    discover: D,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Entry<'a> {
+    name: &'a str,
+    url: &'a str,
+    resolved: bool,
+    stale: bool,
+}
+
+// === impl ServiceIndex ===
+
+impl<D> ServiceIndex<D>
+where
+    D: svc::Service<discover::Name, Response = discover::Receiver>,
+    D: Clone + Send + 'static,
+    D::Error: Into<Error>,
+    D::Future: Send,
+{
+    pub fn new(services: Arc<[ListedService]>, discover: D) -> Self {
+        Self { services, discover }
+    }
+
+    pub(super) fn respond<B>(
+        &self,
+        req: &http::Request<B>,
+    ) -> BoxFuture<'static, Result<http::Response<BoxBody>, Error>> {
+        let json = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("application/json"));
+        let services = self.services.clone();
+        let discover = self.discover.clone();
+        Box::pin(async move {
+            let statuses = future::join_all(
+                services
+                    .iter()
+                    .map(|listed| status(discover.clone(), listed.service.clone())),
+            )
+            .await;
+            let entries = services
+                .iter()
+                .zip(statuses)
+                .map(|(listed, status)| Entry {
+                    name: &listed.name,
+                    url: &listed.url,
+                    resolved: status.is_some(),
+                    stale: status.unwrap_or(false),
+                })
+                .collect::<Vec<_>>();
+
+            let (content_type, body) = if json {
+                let body = serde_json::json!({ "services": entries });
+                ("application/json", body.to_string())
+            } else {
+                ("text/html", render_html(&entries))
+            };
+            Ok(http::Response::builder()
+                .header(header::SERVER, error_respond::VERSION)
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CACHE_CONTROL, "no-cache")
+                .body(box_body::boxed(http_body_util::Full::new(
+                    bytes::Bytes::from(body),
+                )))?)
+        })
+    }
+}
+
+/// Returns `None` if the service is not resolved, or whether its endpoint is
+/// stale if it is.
+async fn status<D>(discover: D, name: discover::Name) -> Option<bool>
+where
+    D: svc::Service<discover::Name, Response = discover::Receiver>,
+    D::Error: Into<Error>,
+{
+    match svc::ServiceExt::oneshot(discover, name.clone()).await {
+        Ok(rx) => rx.borrow().as_ref().map(|discovered| discovered.stale),
+        Err(error) => {
+            let error: Error = error.into();
+            tracing::debug!(%name, %error, "Failed to discover service for the index");
+            None
+        }
+    }
+}
+
+fn render_html(entries: &[Entry<'_>]) -> String {
+    let mut services = String::new();
+    for Entry {
+        name,
+        url,
+        resolved,
+        stale,
+    } in entries
+    {
+        let (class, status) = match (*resolved, *stale) {
+            (true, false) => ("resolved", "resolved"),
+            (true, true) => ("stale", "stale"),
+            (false, _) => ("unresolved", "not resolved"),
+        };
+        let _ = writeln!(
+            services,
+            "                <li><a href=\"{url}\">{name}</a> <span class=\"{class}\">{status}</span></li>",
+            url = escape(url),
+            name = escape(name),
+        );
+    }
+    format!(
+        include_str!("../html/index.html"),
+        services = services,
+        version = error_respond::VERSION,
+    )
+}
+
+/// Escapes text to be included in HTML.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_html() {
+        let html = render_html(&[
+            Entry {
+                name: "eclss",
+                url: "http://eclss.example.com/",
+                resolved: true,
+                stale: false,
+            },
+            Entry {
+                name: "grafana",
+                url: "http://grafana.example.com/",
+                resolved: true,
+                stale: true,
+            },
+            Entry {
+                name: "<script>",
+                url: "http://example.com/?a=1&b=2",
+                resolved: false,
+                stale: false,
+            },
+        ]);
+        assert!(html.contains(
+            "<li><a href=\"http://eclss.example.com/\">eclss</a> <span class=\"resolved\">resolved</span></li>"
+        ));
+        assert!(html.contains(
+            "<li><a href=\"http://grafana.example.com/\">grafana</a> <span class=\"stale\">stale</span></li>"
+        ));
+        assert!(html.contains(
+            "<a href=\"http://example.com/?a=1&amp;b=2\">&lt;script&gt;</a> <span class=\"unresolved\">not resolved</span>"
+        ));
+    }
+}
@@ -0,0 +1,304 @@
+use super::error_respond::SyntheticHttpResponse;
+use crate::{discover, serve, svc};
+use futures::{future, TryFuture};
+use http::header::{self, HeaderMap, HeaderValue};
+use linkerd_app_core::Error;
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Adds the gateway to the `Via` header of requests and responses, and fails
+/// requests that have already passed through it with a
+/// [`SyntheticHttpResponse::loop_detected`] response.
+#[derive(Clone, Debug)]
+pub struct NewVia<N> {
+    hostname: discover::Name,
+    inner: N,
+}
+
+#[derive(Clone, Debug)]
+pub struct Via<S> {
+    /// The name that the gateway gives itself in `Via` headers.
+    pseudonym: Arc<str>,
+    value: HeaderValue,
+    listen_addr: SocketAddr,
+    inner: S,
+}
+
+#[pin_project::pin_project]
+pub struct ResponseFuture<F> {
+    #[pin]
+    future: F,
+    value: Option<HeaderValue>,
+}
+
+/// Fails requests with a [`SyntheticHttpResponse::loop_detected`] response if
+/// the endpoint they would be sent to is the gateway itself, such as when a
+/// service's name resolves to the gateway.
+#[derive(Clone, Debug)]
+pub struct NewLoopGuard<N> {
+    own: Arc<OwnAddrs>,
+    inner: N,
+}
+
+#[derive(Clone, Debug)]
+pub struct LoopGuard<S> {
+    endpoints: Arc<[SocketAddr]>,
+    own: Arc<OwnAddrs>,
+    inner: S,
+}
+
+/// The addresses that the gateway listens on.
+#[derive(Debug, Default)]
+pub struct OwnAddrs {
+    listeners: Vec<SocketAddr>,
+
+    /// The host's own IP addresses, which listeners on unspecified addresses
+    /// accept connections on.
+    local_ips: Vec<IpAddr>,
+}
+
+/// The address of the listener that accepted a request. Recorded in each
+/// request's extensions by [`Via`].
+#[derive(Copy, Clone, Debug)]
+struct ListenAddr(SocketAddr);
+
+// === impl NewVia ===
+
+impl<N> NewVia<N> {
+    pub fn layer(hostname: discover::Name) -> impl svc::Layer<N, Service = Self> + Clone {
+        svc::layer::mk(move |inner| Self {
+            hostname: hostname.clone(),
+            inner,
+        })
+    }
+}
+
+impl<N> svc::NewService<serve::Accepted> for NewVia<N>
+where
+    N: svc::NewService<serve::Accepted>,
+{
+    type Service = Via<N::Service>;
+
+    fn new_service(&self, accepted: serve::Accepted) -> Self::Service {
+        let pseudonym = format!("{}:{}", self.hostname, accepted.listen_addr.port());
+        let value = HeaderValue::try_from(format!("HTTP/1.1 {pseudonym}"))
+            .expect("hostname must be a valid header value");
+        let listen_addr = accepted.listen_addr;
+        Via {
+            pseudonym: pseudonym.into(),
+            value,
+            listen_addr,
+            inner: self.inner.new_service(accepted),
+        }
+    }
+}
+
+// === impl Via ===
+
+impl<S, ReqB, RspB> svc::Service<http::Request<ReqB>> for Via<S>
+where
+    S: svc::Service<http::Request<ReqB>, Response = http::Response<RspB>, Error = Error>,
+{
+    type Response = S::Response;
+    type Error = Error;
+    type Future =
+        future::Either<future::Ready<Result<Self::Response, Error>>, ResponseFuture<S::Future>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<ReqB>) -> Self::Future {
+        if has_passed_through(req.headers(), &self.pseudonym) {
+            tracing::info!(via = ?req.headers().get_all(header::VIA), "Request loop detected");
+            let rsp = SyntheticHttpResponse::loop_detected(
+                "request has already passed through the gateway",
+            );
+            return future::Either::Left(future::ready(Err(rsp.into())));
+        }
+        req.headers_mut().append(header::VIA, self.value.clone());
+        req.extensions_mut().insert(ListenAddr(self.listen_addr));
+        future::Either::Right(ResponseFuture {
+            future: self.inner.call(req),
+            value: Some(self.value.clone()),
+        })
+    }
+}
+
+/// Returns `true` if the `Via` header names `pseudonym` as one of the
+/// proxies that a message was received by.
+fn has_passed_through(headers: &HeaderMap, pseudonym: &str) -> bool {
+    headers
+        .get_all(header::VIA)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| entry.split_whitespace().nth(1))
+        .any(|received_by| received_by.eq_ignore_ascii_case(pseudonym))
+}
+
+// === impl ResponseFuture ===
+
+impl<F, B> Future for ResponseFuture<F>
+where
+    F: TryFuture<Ok = http::Response<B>>,
+{
+    type Output = Result<F::Ok, F::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let mut rsp = futures::ready!(this.future.try_poll(cx))?;
+        if let Some(value) = this.value.take() {
+            rsp.headers_mut().append(header::VIA, value);
+        }
+        Poll::Ready(Ok(rsp))
+    }
+}
+
+// === impl NewLoopGuard ===
+
+impl<N> NewLoopGuard<N> {
+    pub fn layer(own: Arc<OwnAddrs>) -> impl svc::Layer<N, Service = Self> + Clone {
+        svc::layer::mk(move |inner| Self {
+            own: own.clone(),
+            inner,
+        })
+    }
+}
+
+impl<N> svc::NewService<discover::Discovered> for NewLoopGuard<N>
+where
+    N: svc::NewService<discover::Discovered>,
+{
+    type Service = LoopGuard<N::Service>;
+
+    fn new_service(&self, discovered: discover::Discovered) -> Self::Service {
+        let endpoints = std::iter::once(discovered.addr)
+            .chain(discovered.replicas.iter().copied())
+            .collect();
+        LoopGuard {
+            endpoints,
+            own: self.own.clone(),
+            inner: self.inner.new_service(discovered),
+        }
+    }
+}
+
+// === impl LoopGuard ===
+
+impl<S, B> svc::Service<http::Request<B>> for LoopGuard<S>
+where
+    S: svc::Service<http::Request<B>, Error = Error>,
+{
+    type Response = S::Response;
+    type Error = Error;
+    type Future = future::Either<future::Ready<Result<S::Response, Error>>, S::Future>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let listen_addr = req
+            .extensions()
+            .get::<ListenAddr>()
+            .map(|&ListenAddr(addr)| addr);
+        if let Some(&endpoint) = self
+            .endpoints
+            .iter()
+            .find(|&&endpoint| self.own.contains(endpoint, listen_addr))
+        {
+            tracing::info!(%endpoint, "Endpoint is the gateway itself");
+            let rsp = SyntheticHttpResponse::loop_detected(format!(
+                "service endpoint {endpoint} is the gateway itself"
+            ));
+            return future::Either::Left(future::ready(Err(rsp.into())));
+        }
+        future::Either::Right(self.inner.call(req))
+    }
+}
+
+// === impl OwnAddrs ===
+
+impl OwnAddrs {
+    /// Returns the addresses of `listeners`, on any of the host's interfaces.
+    pub fn new(listeners: impl IntoIterator<Item = SocketAddr>) -> Self {
+        let local_ips = match if_addrs::get_if_addrs() {
+            Ok(ifaces) => ifaces.iter().map(if_addrs::Interface::ip).collect(),
+            Err(error) => {
+                tracing::warn!(%error, "Failed to list network interfaces; only loopback endpoints are detected as the gateway");
+                Vec::new()
+            }
+        };
+        Self {
+            listeners: listeners.into_iter().collect(),
+            local_ips,
+        }
+    }
+
+    /// Returns `true` if `endpoint` is one of the configured listeners, or
+    /// the listener that accepted the request.
+    fn contains(&self, endpoint: SocketAddr, listen_addr: Option<SocketAddr>) -> bool {
+        self.listeners
+            .iter()
+            .chain(&listen_addr)
+            .any(|&listener| self.is_listener(listener, endpoint))
+    }
+
+    fn is_listener(&self, listener: SocketAddr, endpoint: SocketAddr) -> bool {
+        if listener.port() != endpoint.port() {
+            return false;
+        }
+        if listener.ip() == endpoint.ip() {
+            return true;
+        }
+        listener.ip().is_unspecified()
+            && (endpoint.ip().is_loopback() || self.local_ips.contains(&endpoint.ip()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn via() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::VIA,
+            HeaderValue::from_static("1.0 fred, 1.1 p.example.net"),
+        );
+        assert!(!has_passed_through(&headers, "example.com:80"));
+        headers.append(
+            header::VIA,
+            HeaderValue::from_static("HTTP/1.1 Example.com:80 (multipass)"),
+        );
+        assert!(has_passed_through(&headers, "example.com:80"));
+        assert!(!has_passed_through(&headers, "example.com:8080"));
+    }
+
+    #[test]
+    fn own_addrs() {
+        let own = OwnAddrs {
+            listeners: vec![([0, 0, 0, 0], 80).into(), ([192, 168, 1, 1], 443).into()],
+            local_ips: vec![[192, 168, 1, 1].into()],
+        };
+        assert!(own.contains(([192, 168, 1, 1], 80).into(), None));
+        assert!(own.contains(([127, 0, 0, 1], 80).into(), None));
+        assert!(own.contains(([192, 168, 1, 1], 443).into(), None));
+        assert!(!own.contains(([192, 168, 1, 10], 80).into(), None));
+        assert!(!own.contains(([127, 0, 0, 1], 443).into(), None));
+        assert!(!own.contains(([192, 168, 1, 1], 8080).into(), None));
+        assert!(own.contains(
+            ([192, 168, 1, 1], 8080).into(),
+            Some(([192, 168, 1, 1], 8080).into())
+        ));
+    }
+}
@@ -0,0 +1,282 @@
+use super::{box_body, BoxBody, Route, Upstream};
+use crate::{route, svc};
+use bytes::{Buf, Bytes, BytesMut};
+use http_body::{Body, Frame, SizeHint};
+use http_body_util::{BodyExt, Full};
+use rand::Rng;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+/// Copies a sample of each route's requests to the route's
+/// [`route::Mirror`] service, if it has one.
+///
+/// Mirrored requests are sent in the background once the original request's
+/// body has been read, and their responses are discarded. Failures of the
+/// mirror never affect the original request. At most
+/// [`route::Mirror::in_flight`] mirrored requests are in flight at once, and
+/// each is abandoned if it takes longer than the mirrored service's request
+/// timeout, or [`DEFAULT_TIMEOUT`] if it has none.
+#[derive(Clone, Debug)]
+pub struct NewMirror<N> {
+    inner: N,
+}
+
+#[derive(Clone, Debug)]
+pub struct Mirror<S> {
+    inner: S,
+    mirror: Option<(route::Mirror, S)>,
+}
+
+/// A request body that copies its data into a buffer as it is read, and sends
+/// the buffer to a mirrored request once the body ends.
+#[pin_project::pin_project]
+pub struct TeeBody<B> {
+    #[pin]
+    inner: B,
+    tee: Option<Tee>,
+}
+
+struct Tee {
+    buf: BytesMut,
+    limit: usize,
+    tx: oneshot::Sender<Bytes>,
+}
+
+/// How long a mirrored request may take, if the mirrored service has no
+/// request timeout of its own.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// === impl NewMirror ===
+
+impl<N> NewMirror<N> {
+    pub fn layer() -> impl svc::Layer<N, Service = Self> + Clone {
+        svc::layer::mk(|inner| Self { inner })
+    }
+}
+
+impl<T, N> svc::NewService<Route<T>> for NewMirror<N>
+where
+    T: Clone,
+    N: svc::NewService<Route<T>>,
+{
+    type Service = Mirror<N::Service>;
+
+    fn new_service(&self, route: Route<T>) -> Self::Service {
+        let mirror = route.rule.mirror.clone().map(|mirror| {
+            let target = Route {
+                name: mirror.service.clone(),
+                upstream: Upstream::Mirror,
+                set_cookie: None,
+                ..route.clone()
+            };
+            (mirror, self.inner.new_service(target))
+        });
+        let inner = self.inner.new_service(route);
+        Mirror { inner, mirror }
+    }
+}
+
+// === impl Mirror ===
+
+impl<S, B> svc::Service<http::Request<BoxBody>> for Mirror<S>
+where
+    S: svc::Service<http::Request<BoxBody>, Response = http::Response<B>>,
+    S: Clone + Send + 'static,
+    S::Error: Into<linkerd_app_core::Error>,
+    S::Future: Send,
+    B: Body + Send + 'static,
+    B::Data: Send,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<BoxBody>) -> Self::Future {
+        let Some((ref mirror, ref svc)) = self.mirror else {
+            return self.inner.call(req);
+        };
+        if !rand::thread_rng().gen_bool(mirror.percent / 100.0) {
+            return self.inner.call(req);
+        }
+
+        if req.body().size_hint().lower() > mirror.max_body_bytes as u64 {
+            tracing::debug!(
+                service = %mirror.service,
+                limit = mirror.max_body_bytes,
+                "Request body is too large to mirror"
+            );
+            return self.inner.call(req);
+        }
+
+        let Ok(permit) = mirror.in_flight.clone().try_acquire_owned() else {
+            tracing::debug!(
+                service = %mirror.service,
+                "Too many mirrored requests in flight"
+            );
+            return self.inner.call(req);
+        };
+
+        let (parts, body) = req.into_parts();
+        let mut copy = http::Request::new(());
+        *copy.method_mut() = parts.method.clone();
+        *copy.uri_mut() = parts.uri.clone();
+        *copy.version_mut() = parts.version;
+        *copy.headers_mut() = parts.headers.clone();
+
+        let (tx, rx) = oneshot::channel();
+        let body = if body.is_end_stream() {
+            // An empty body may never be polled, so don't wait for it to end.
+            let _ = tx.send(Bytes::new());
+            body
+        } else {
+            box_body::boxed(TeeBody::new(body, mirror.max_body_bytes, tx))
+        };
+        let req = http::Request::from_parts(parts, body);
+
+        let service = mirror.service.clone();
+        let timeout = mirror.timeouts.request.unwrap_or(DEFAULT_TIMEOUT);
+        let svc = svc.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            // If the original body was too large or failed, the sender is
+            // dropped without sending.
+            let Ok(body) = rx.await else {
+                tracing::debug!(%service, "Not mirroring request");
+                return;
+            };
+            let req = copy.map(|()| box_body::boxed(Full::new(body)));
+            let mirrored = tokio::time::timeout(timeout, async move {
+                let rsp = svc::ServiceExt::oneshot(svc, req).await?;
+                tracing::trace!(status = %rsp.status(), "Mirrored request");
+                // Read the response so the connection may be reused.
+                let _ = rsp.into_body().collect().await;
+                Ok::<_, S::Error>(())
+            });
+            match mirrored.await {
+                Ok(Ok(())) => {}
+                Ok(Err(error)) => {
+                    let error: linkerd_app_core::Error = error.into();
+                    tracing::debug!(%service, %error, "Mirrored request failed");
+                }
+                Err(_) => tracing::debug!(%service, ?timeout, "Mirrored request timed out"),
+            }
+        });
+
+        self.inner.call(req)
+    }
+}
+
+// === impl TeeBody ===
+
+impl<B> TeeBody<B> {
+    fn new(inner: B, limit: usize, tx: oneshot::Sender<Bytes>) -> Self {
+        Self {
+            inner,
+            tee: Some(Tee {
+                buf: BytesMut::new(),
+                limit,
+                tx,
+            }),
+        }
+    }
+}
+
+impl<B> Body for TeeBody<B>
+where
+    B: Body,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let frame = match futures::ready!(this.inner.poll_frame(cx)) {
+            Some(Ok(frame)) => frame,
+            Some(Err(error)) => {
+                this.tee.take();
+                return Poll::Ready(Some(Err(error)));
+            }
+            None => {
+                if let Some(tee) = this.tee.take() {
+                    tee.send();
+                }
+                return Poll::Ready(None);
+            }
+        };
+
+        let frame = match frame.into_data() {
+            Ok(mut data) => {
+                let data = data.copy_to_bytes(data.remaining());
+                if let Some(tee) = this.tee.as_mut() {
+                    if tee.buf.len() + data.len() > tee.limit {
+                        this.tee.take();
+                    } else {
+                        tee.buf.extend_from_slice(&data);
+                    }
+                }
+                Frame::data(data)
+            }
+            Err(frame) => {
+                // Trailers are the last frame of a body.
+                if let Some(tee) = this.tee.take() {
+                    tee.send();
+                }
+                frame.map_data(|mut data| data.copy_to_bytes(data.remaining()))
+            }
+        };
+        if this.inner.is_end_stream() {
+            if let Some(tee) = this.tee.take() {
+                tee.send();
+            }
+        }
+        Poll::Ready(Some(Ok(frame)))
+    }
+
+    #[inline]
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Tee {
+    fn send(self) {
+        let _ = self.tx.send(self.buf.freeze());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn tees_body() {
+        let (tx, rx) = oneshot::channel();
+        let body = TeeBody::new(Full::new(Bytes::from("hello")), 16, tx);
+        let data = body.collect().await.unwrap().to_bytes();
+        assert_eq!(data, "hello");
+        assert_eq!(rx.await.unwrap(), "hello");
+
+        let (tx, rx) = oneshot::channel();
+        let body = TeeBody::new(Full::new(Bytes::from("hello")), 4, tx);
+        let data = body.collect().await.unwrap().to_bytes();
+        assert_eq!(data, "hello", "the original body is not truncated");
+        assert!(rx.await.is_err(), "bodies over the limit are not mirrored");
+    }
+}
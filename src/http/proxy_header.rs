@@ -0,0 +1,85 @@
+use super::Route;
+use crate::{proxy_protocol, serve, svc};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Marks requests for routes with a [`route::Rule::proxy_protocol`] version,
+/// so that the endpoint stack sends them on a connection that starts with a
+/// PROXY protocol header describing the client.
+///
+/// [`route::Rule::proxy_protocol`]: crate::route::Rule::proxy_protocol
+#[derive(Clone, Debug)]
+pub struct NewProxyHeader<N> {
+    inner: N,
+}
+
+#[derive(Clone, Debug)]
+pub struct SetProxyHeader<S> {
+    header: Option<ProxyHeader>,
+    inner: S,
+}
+
+/// An encoded PROXY protocol header, recorded in a request's extensions.
+///
+/// PROXY headers must name a source port, so a client whose port is unknown
+/// is described with the port of the connection its request arrived on.
+#[derive(Clone, Debug)]
+pub struct ProxyHeader {
+    pub header: Arc<[u8]>,
+}
+
+// === impl NewProxyHeader ===
+
+impl<N> NewProxyHeader<N> {
+    pub fn layer() -> impl svc::Layer<N, Service = Self> + Clone {
+        svc::layer::mk(|inner| Self { inner })
+    }
+}
+
+impl<N> svc::NewService<Route<serve::Accepted>> for NewProxyHeader<N>
+where
+    N: svc::NewService<Route<serve::Accepted>>,
+{
+    type Service = SetProxyHeader<N::Service>;
+
+    fn new_service(&self, route: Route<serve::Accepted>) -> Self::Service {
+        let header = route.proxy_protocol().map(|version| {
+            let serve::ClientAddr { ip, port } = route.parent.client_addr;
+            let addrs = proxy_protocol::Addresses {
+                source: SocketAddr::new(ip, port.unwrap_or(route.parent.peer_addr.port())),
+                destination: route.parent.local_addr,
+            };
+            ProxyHeader {
+                header: proxy_protocol::encode(version, addrs).into(),
+            }
+        });
+        let inner = self.inner.new_service(route);
+        SetProxyHeader { header, inner }
+    }
+}
+
+// === impl SetProxyHeader ===
+
+impl<S, B> svc::Service<http::Request<B>> for SetProxyHeader<S>
+where
+    S: svc::Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        if let Some(ref header) = self.header {
+            req.extensions_mut().insert(header.clone());
+        }
+        self.inner.call(req)
+    }
+}
@@ -0,0 +1,463 @@
+use super::Replicas;
+use crate::route;
+use futures::TryFuture;
+use http::{
+    header::{self, HeaderName, HeaderValue},
+    uri,
+};
+use linkerd_app_core::proxy::http::normalize_uri::DefaultAuthority;
+use linkerd_stack::{layer, NewService, Param};
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Rewrites the paths of requests according to the [`route::Rule`] they
+/// matched, before they are forwarded to the rule's service.
+#[derive(Clone, Debug)]
+pub struct NewRewritePath<N> {
+    inner: N,
+}
+
+#[derive(Clone, Debug)]
+pub struct RewritePath<S> {
+    rule: Arc<route::Rule>,
+    inner: S,
+}
+
+/// Rewrites references to an upstream endpoint in its responses' `Location`,
+/// `Content-Location`, and `Set-Cookie` headers, so that they refer to the
+/// host, scheme, and path prefix that the client used instead.
+#[derive(Clone, Debug)]
+pub struct NewRewriteResponse<N> {
+    inner: N,
+}
+
+#[derive(Clone, Debug)]
+pub struct RewriteResponse<S> {
+    upstream: Arc<Upstream>,
+    inner: S,
+}
+
+#[pin_project::pin_project]
+pub struct ResponseFuture<F> {
+    #[pin]
+    future: F,
+    upstream: Arc<Upstream>,
+    origin: Option<PublicOrigin>,
+}
+
+/// How the client addressed a request, before it was rewritten.
+///
+/// This is recorded in the request's extensions by [`RewritePath`], so that
+/// responses can be mapped back by [`RewriteResponse`].
+#[derive(Clone, Debug)]
+struct PublicOrigin {
+    scheme: uri::Scheme,
+    authority: uri::Authority,
+    prefix: Option<String>,
+}
+
+/// The names an upstream endpoint may use to refer to itself.
+#[derive(Debug)]
+struct Upstream {
+    host: Option<String>,
+
+    /// The IPs of the endpoint and each of its replicas, any of which a
+    /// request may have been sent to.
+    ips: Vec<IpAddr>,
+}
+
+pub const X_FORWARDED_PREFIX: HeaderName = HeaderName::from_static("x-forwarded-prefix");
+
+// === impl NewRewritePath ===
+
+impl<N> NewRewritePath<N> {
+    pub fn layer() -> impl layer::Layer<N, Service = Self> + Clone {
+        layer::mk(|inner| Self { inner })
+    }
+}
+
+impl<T, N> NewService<T> for NewRewritePath<N>
+where
+    T: Param<Arc<route::Rule>>,
+    N: NewService<T>,
+{
+    type Service = RewritePath<N::Service>;
+
+    fn new_service(&self, target: T) -> Self::Service {
+        let rule = target.param();
+        let inner = self.inner.new_service(target);
+        RewritePath { rule, inner }
+    }
+}
+
+// === impl RewritePath ===
+
+impl<S, B> tower::Service<http::Request<B>> for RewritePath<S>
+where
+    S: tower::Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        let mut origin = PublicOrigin::from_request(&req);
+        if let Some(rewritten) = self.rule.rewrite_path(req.uri().path()) {
+            if let Some(ref mut origin) = origin {
+                origin.prefix = rewritten.stripped.clone();
+            }
+            rewrite(&mut req, rewritten);
+        }
+        if let Some(origin) = origin {
+            req.extensions_mut().insert(origin);
+        }
+        self.inner.call(req)
+    }
+}
+
+// === impl NewRewriteResponse ===
+
+impl<N> NewRewriteResponse<N> {
+    pub fn layer() -> impl layer::Layer<N, Service = Self> + Clone {
+        layer::mk(|inner| Self { inner })
+    }
+}
+
+impl<T, N> NewService<T> for NewRewriteResponse<N>
+where
+    T: Param<DefaultAuthority> + Param<SocketAddr> + Param<Replicas>,
+    N: NewService<T>,
+{
+    type Service = RewriteResponse<N::Service>;
+
+    fn new_service(&self, target: T) -> Self::Service {
+        let DefaultAuthority(authority) = target.param();
+        let addr: SocketAddr = target.param();
+        let Replicas(replicas) = target.param();
+        let upstream = Arc::new(Upstream {
+            host: authority.map(|a| a.host().trim_end_matches('.').to_ascii_lowercase()),
+            ips: std::iter::once(addr)
+                .chain(replicas.iter().copied())
+                .map(|addr| addr.ip())
+                .collect(),
+        });
+        let inner = self.inner.new_service(target);
+        RewriteResponse { upstream, inner }
+    }
+}
+
+// === impl RewriteResponse ===
+
+impl<S, ReqB, RspB> tower::Service<http::Request<ReqB>> for RewriteResponse<S>
+where
+    S: tower::Service<http::Request<ReqB>, Response = http::Response<RspB>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqB>) -> Self::Future {
+        let origin = req.extensions().get::<PublicOrigin>().cloned();
+        ResponseFuture {
+            future: self.inner.call(req),
+            upstream: self.upstream.clone(),
+            origin,
+        }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F, B> Future for ResponseFuture<F>
+where
+    F: TryFuture<Ok = http::Response<B>>,
+{
+    type Output = Result<F::Ok, F::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let mut rsp = futures::ready!(this.future.try_poll(cx))?;
+        if let Some(origin) = this.origin.take() {
+            this.upstream.rewrite(&origin, rsp.headers_mut());
+        }
+        Poll::Ready(Ok(rsp))
+    }
+}
+
+// === impl PublicOrigin ===
+
+impl PublicOrigin {
+    fn from_request<B>(req: &http::Request<B>) -> Option<Self> {
+        let authority = match req.uri().authority() {
+            Some(authority) => authority.clone(),
+            None => req
+                .headers()
+                .get(header::HOST)?
+                .to_str()
+                .ok()?
+                .parse()
+                .ok()?,
+        };
+        Some(Self {
+            scheme: req.uri().scheme().cloned().unwrap_or(uri::Scheme::HTTP),
+            authority,
+            prefix: None,
+        })
+    }
+}
+
+// === impl Upstream ===
+
+impl Upstream {
+    fn rewrite(&self, origin: &PublicOrigin, headers: &mut http::HeaderMap) {
+        for name in [header::LOCATION, header::CONTENT_LOCATION] {
+            let rewritten = headers
+                .get(&name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| self.rewrite_url(origin, v));
+            if let Some(rewritten) = rewritten.and_then(|v| HeaderValue::try_from(v).ok()) {
+                tracing::debug!(header = %name, value = ?rewritten, "Rewrote response header");
+                headers.insert(name, rewritten);
+            }
+        }
+
+        if let header::Entry::Occupied(mut cookies) = headers.entry(header::SET_COOKIE) {
+            for value in cookies.iter_mut() {
+                let rewritten = value
+                    .to_str()
+                    .ok()
+                    .and_then(|v| self.rewrite_cookie(origin, v));
+                if let Some(rewritten) = rewritten.and_then(|v| HeaderValue::try_from(v).ok()) {
+                    tracing::debug!(value = ?rewritten, "Rewrote Set-Cookie header");
+                    *value = rewritten;
+                }
+            }
+        }
+    }
+
+    /// Returns `true` if `host` refers to this upstream.
+    fn is_upstream(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.');
+        if let Ok(ip) = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+        {
+            return self.ips.contains(&ip);
+        }
+        self.host
+            .as_deref()
+            .is_some_and(|upstream| upstream.eq_ignore_ascii_case(host))
+    }
+
+    fn rewrite_url(&self, origin: &PublicOrigin, url: &str) -> Option<String> {
+        // `http::Uri` doesn't handle fragments, so set them aside.
+        let (url, fragment) = match url.split_once('#') {
+            Some((url, fragment)) => (url, Some(fragment)),
+            None => (url, None),
+        };
+        let prefix = origin.prefix.as_deref().unwrap_or_default();
+
+        let rewritten = if url.starts_with('/') && !url.starts_with("//") {
+            // A path relative to the upstream's root.
+            if prefix.is_empty() {
+                return None;
+            }
+            format!("{prefix}{url}")
+        } else {
+            let uri = url.parse::<uri::Uri>().ok()?;
+            if !self.is_upstream(uri.authority()?.host()) {
+                return None;
+            }
+            let path = uri.path_and_query().map_or("/", uri::PathAndQuery::as_str);
+            format!("{}://{}{prefix}{path}", origin.scheme, origin.authority)
+        };
+
+        Some(match fragment {
+            Some(fragment) => format!("{rewritten}#{fragment}"),
+            None => rewritten,
+        })
+    }
+
+    fn rewrite_cookie(&self, origin: &PublicOrigin, cookie: &str) -> Option<String> {
+        let mut rewritten = false;
+        // The first part is the cookie's name and value, which is never
+        // rewritten.
+        let mut parts = cookie.split(';');
+        let mut cookie = parts.next()?.to_string();
+        for part in parts {
+            let attr = match part.split_once('=') {
+                Some((name, value)) if name.trim().eq_ignore_ascii_case("domain") => {
+                    let domain = value.trim().trim_start_matches('.');
+                    if self.is_upstream(domain) {
+                        rewritten = true;
+                        format!(" Domain={}", origin.authority.host())
+                    } else {
+                        part.to_string()
+                    }
+                }
+                Some((name, value)) if name.trim().eq_ignore_ascii_case("path") => {
+                    match origin.prefix {
+                        Some(ref prefix) => {
+                            rewritten = true;
+                            match value.trim() {
+                                "" | "/" => format!(" Path={prefix}"),
+                                path => format!(" Path={prefix}{path}"),
+                            }
+                        }
+                        None => part.to_string(),
+                    }
+                }
+                _ => part.to_string(),
+            };
+            cookie.push(';');
+            cookie.push_str(&attr);
+        }
+        rewritten.then_some(cookie)
+    }
+}
+
+fn rewrite<B>(req: &mut http::Request<B>, rewritten: route::RewrittenPath) {
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{query}", rewritten.path),
+        None => rewritten.path,
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = match path_and_query.parse::<uri::PathAndQuery>() {
+        Ok(path_and_query) => Some(path_and_query),
+        Err(error) => {
+            tracing::warn!(%error, %path_and_query, "Rewritten path is invalid, not rewriting");
+            return;
+        }
+    };
+    let uri = match uri::Uri::from_parts(parts) {
+        Ok(uri) => uri,
+        Err(error) => {
+            tracing::warn!(%error, "Rewritten URI is invalid, not rewriting");
+            return;
+        }
+    };
+    tracing::debug!(from = %req.uri(), to = %uri, "Rewriting request URI");
+    *req.uri_mut() = uri;
+
+    if let Some(stripped) = rewritten.stripped {
+        // Prefixes stripped by trusted proxies in front of us come first;
+        // `ResolveClientAddr` removes the header from anyone else's requests.
+        let prefix = match req.headers().get(&X_FORWARDED_PREFIX) {
+            Some(prev) => format!(
+                "{}{stripped}",
+                prev.to_str().unwrap_or_default().trim_end_matches('/')
+            ),
+            None => stripped,
+        };
+        match http::HeaderValue::try_from(prefix) {
+            Ok(prefix) => {
+                req.headers_mut().insert(X_FORWARDED_PREFIX, prefix);
+            }
+            Err(error) => tracing::warn!(%error, "Invalid X-Forwarded-Prefix"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream() -> Upstream {
+        Upstream {
+            host: Some("eclss.local".to_string()),
+            ips: vec![[192, 168, 1, 10].into(), [192, 168, 1, 11].into()],
+        }
+    }
+
+    fn origin(prefix: Option<&str>) -> PublicOrigin {
+        PublicOrigin {
+            scheme: uri::Scheme::HTTPS,
+            authority: "example.com".parse().unwrap(),
+            prefix: prefix.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn rewrites_urls() {
+        let upstream = upstream();
+        let rewrite = |prefix, url| upstream.rewrite_url(&origin(prefix), url);
+        assert_eq!(
+            rewrite(None, "http://eclss.local:8080/login?next=/").as_deref(),
+            Some("https://example.com/login?next=/")
+        );
+        assert_eq!(
+            rewrite(Some("/eclss"), "http://ECLSS.local./login#top").as_deref(),
+            Some("https://example.com/eclss/login#top")
+        );
+        assert_eq!(
+            rewrite(Some("/eclss"), "http://192.168.1.10/").as_deref(),
+            Some("https://example.com/eclss/")
+        );
+        assert_eq!(
+            rewrite(None, "http://192.168.1.11/").as_deref(),
+            Some("https://example.com/"),
+            "replicas refer to the same upstream"
+        );
+        assert_eq!(rewrite(None, "http://192.168.1.12/"), None);
+        assert_eq!(
+            rewrite(Some("/eclss"), "/login").as_deref(),
+            Some("/eclss/login")
+        );
+        assert_eq!(rewrite(None, "/login"), None);
+        assert_eq!(rewrite(Some("/eclss"), "https://github.com/"), None);
+    }
+
+    #[test]
+    fn rewrites_cookies() {
+        let upstream = upstream();
+        let rewrite = |prefix, cookie| upstream.rewrite_cookie(&origin(prefix), cookie);
+        assert_eq!(
+            rewrite(None, "session=abc; Domain=eclss.local; HttpOnly").as_deref(),
+            Some("session=abc; Domain=example.com; HttpOnly")
+        );
+        assert_eq!(
+            rewrite(Some("/eclss"), "session=abc; Path=/; Secure").as_deref(),
+            Some("session=abc; Path=/eclss; Secure")
+        );
+        assert_eq!(
+            rewrite(Some("/eclss"), "session=abc; path=/api").as_deref(),
+            Some("session=abc; Path=/eclss/api")
+        );
+        assert_eq!(rewrite(None, "domain=eclss.local; Path=/"), None);
+        assert_eq!(rewrite(None, "session=abc; Domain=other.local"), None);
+    }
+
+    #[test]
+    fn rewrites_uri_and_sets_prefix() {
+        let mut req = http::Request::builder()
+            .uri("http://example.com/eclss/index.html?lang=en")
+            .header(X_FORWARDED_PREFIX, "/outer/")
+            .body(())
+            .unwrap();
+        rewrite(
+            &mut req,
+            route::RewrittenPath {
+                path: "/index.html".to_string(),
+                stripped: Some("/eclss".to_string()),
+            },
+        );
+        assert_eq!(req.uri(), "http://example.com/index.html?lang=en");
+        assert_eq!(req.headers()[X_FORWARDED_PREFIX], "/outer/eclss");
+    }
+}
@@ -0,0 +1,99 @@
+use futures::TryFuture;
+use http::header::{self, HeaderValue};
+use linkerd_stack::{layer, NewService, Param};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Adds the `Set-Cookie` header that pins a client to the backend a split
+/// route selected for it, if the route is sticky by cookie.
+#[derive(Clone, Debug)]
+pub struct NewStickyCookie<N> {
+    inner: N,
+}
+
+#[derive(Clone, Debug)]
+pub struct StickyCookie<S> {
+    cookie: Option<HeaderValue>,
+    inner: S,
+}
+
+/// The `Set-Cookie` value to add to a route's responses, if any.
+#[derive(Clone, Debug, Default)]
+pub struct SetCookie(pub Option<HeaderValue>);
+
+#[pin_project::pin_project]
+pub struct ResponseFuture<F> {
+    #[pin]
+    future: F,
+    cookie: Option<HeaderValue>,
+}
+
+// === impl NewStickyCookie ===
+
+impl<N> NewStickyCookie<N> {
+    pub fn layer() -> impl layer::Layer<N, Service = Self> + Clone {
+        layer::mk(|inner| Self { inner })
+    }
+}
+
+impl<T, N> NewService<T> for NewStickyCookie<N>
+where
+    T: Param<SetCookie>,
+    N: NewService<T>,
+{
+    type Service = StickyCookie<N::Service>;
+
+    fn new_service(&self, target: T) -> Self::Service {
+        let SetCookie(cookie) = target.param();
+        let inner = self.inner.new_service(target);
+        StickyCookie { cookie, inner }
+    }
+}
+
+// === impl StickyCookie ===
+
+impl<S, ReqB, RspB> tower::Service<http::Request<ReqB>> for StickyCookie<S>
+where
+    S: tower::Service<http::Request<ReqB>, Response = http::Response<RspB>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    #[inline]
+    fn call(&mut self, req: http::Request<ReqB>) -> Self::Future {
+        ResponseFuture {
+            future: self.inner.call(req),
+            cookie: self.cookie.clone(),
+        }
+    }
+}
+
+// === impl ResponseFuture ===
+
+impl<F, B> Future for ResponseFuture<F>
+where
+    F: TryFuture<Ok = http::Response<B>>,
+{
+    type Output = Result<F::Ok, F::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let rsp = futures::ready!(this.future.try_poll(cx));
+        Poll::Ready(rsp.map(|mut rsp| {
+            if let Some(cookie) = this.cookie.take() {
+                // Append, so that cookies set by the upstream are kept.
+                rsp.headers_mut().append(header::SET_COOKIE, cookie);
+            }
+            rsp
+        }))
+    }
+}
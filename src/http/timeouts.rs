@@ -0,0 +1,399 @@
+use super::Route;
+use crate::{route, serve, svc};
+use futures::future::BoxFuture;
+use http_body::{Body, Frame, SizeHint};
+use http_body_util::BodyExt;
+use linkerd_app_core::Error;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::{self, Instant, Sleep};
+
+/// Enforces each route's [`route::Timeouts`] on the requests that it
+/// forwards.
+///
+/// Requests whose response headers don't arrive in time fail with a
+/// [`TimeoutError`], which [`ServerRescue`](super::ServerRescue) answers with
+/// a `504 Gateway Timeout`. If a timeout expires while the response body is
+/// being sent, the body ends with that error instead.
+#[derive(Clone, Debug)]
+pub struct NewRouteTimeouts<N> {
+    inner: N,
+}
+
+#[derive(Clone, Debug)]
+pub struct RouteTimeouts<S> {
+    timeouts: route::Timeouts,
+    inner: S,
+}
+
+#[pin_project::pin_project]
+pub struct TimeoutBody<B> {
+    /// The upstream body, until it ends or a timeout expires.
+    inner: Option<Pin<Box<B>>>,
+    #[pin]
+    deadline: Option<Sleep>,
+    #[pin]
+    idle: Option<Sleep>,
+    timeouts: route::Timeouts,
+}
+
+/// How long a timed out response is drained for, if its route has no request
+/// timeout, before its connection is closed instead.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, thiserror::Error)]
+pub enum TimeoutError {
+    #[error("request did not complete within {0:?}")]
+    Request(Duration),
+
+    #[error("service did not send response headers within {0:?}")]
+    ResponseHeaders(Duration),
+
+    #[error("service did not send any of the response body for {0:?}")]
+    BodyIdle(Duration),
+}
+
+// === impl NewRouteTimeouts ===
+
+impl<N> NewRouteTimeouts<N> {
+    pub fn layer() -> impl svc::Layer<N, Service = Self> + Clone {
+        svc::layer::mk(|inner| Self { inner })
+    }
+}
+
+impl<N> svc::NewService<Route<serve::Accepted>> for NewRouteTimeouts<N>
+where
+    N: svc::NewService<Route<serve::Accepted>>,
+{
+    type Service = RouteTimeouts<N::Service>;
+
+    fn new_service(&self, route: Route<serve::Accepted>) -> Self::Service {
+        let timeouts = route.timeouts();
+        RouteTimeouts {
+            timeouts,
+            inner: self.inner.new_service(route),
+        }
+    }
+}
+
+// === impl RouteTimeouts ===
+
+impl<S, ReqB, RspB> svc::Service<http::Request<ReqB>> for RouteTimeouts<S>
+where
+    S: svc::Service<http::Request<ReqB>, Response = http::Response<RspB>, Error = Error>,
+    S::Future: Send + 'static,
+    RspB: Body + Send + 'static,
+    RspB::Data: Send,
+    RspB::Error: Into<Error> + Send,
+{
+    type Response = http::Response<TimeoutBody<RspB>>;
+    type Error = Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Error>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqB>) -> Self::Future {
+        let timeouts = self.timeouts;
+        let start = Instant::now();
+        let deadline = timeouts.request.map(|timeout| start + timeout);
+
+        // The response headers must arrive before either timeout expires.
+        let headers_deadline = [
+            timeouts
+                .request
+                .map(|timeout| (start + timeout, TimeoutError::Request(timeout))),
+            timeouts
+                .response_headers
+                .map(|timeout| (start + timeout, TimeoutError::ResponseHeaders(timeout))),
+        ]
+        .into_iter()
+        .flatten()
+        .min_by_key(|(at, _)| *at);
+
+        let mut future = Box::pin(self.inner.call(req));
+        Box::pin(async move {
+            let rsp = match headers_deadline {
+                None => future.await?,
+                Some((at, error)) => match time::timeout_at(at, &mut future).await {
+                    Ok(rsp) => rsp?,
+                    Err(_) => {
+                        tracing::info!(%error, "Response timed out");
+                        if !timeouts.closes_upstream() {
+                            tokio::spawn(drain_response(future, timeouts));
+                        }
+                        return Err(error.into());
+                    }
+                },
+            };
+            Ok(rsp.map(|body| TimeoutBody::new(body, deadline, timeouts)))
+        })
+    }
+}
+
+/// Reads and discards a response that has timed out, so that its connection
+/// can be reused once it completes.
+async fn drain_response<F, B>(future: F, timeouts: route::Timeouts)
+where
+    F: Future<Output = Result<http::Response<B>, Error>>,
+    B: Body,
+{
+    let read = async move {
+        match future.await {
+            Ok(rsp) => read_to_end(Box::pin(rsp.into_body()), timeouts.body_idle).await,
+            Err(_) => false,
+        }
+    };
+    drain(read, timeouts).await
+}
+
+async fn drain_body<B: Body>(body: Pin<Box<B>>, timeouts: route::Timeouts) {
+    drain(read_to_end(body, timeouts.body_idle), timeouts).await
+}
+
+/// Waits for a timed out response to be read, for no longer than the route's
+/// request timeout (or [`DRAIN_TIMEOUT`]). If it isn't read in time, it's
+/// dropped, which closes its connection.
+async fn drain(read: impl Future<Output = bool>, timeouts: route::Timeouts) {
+    let timeout = timeouts.request.unwrap_or(DRAIN_TIMEOUT);
+    match time::timeout(timeout, read).await {
+        Ok(true) => tracing::debug!("Drained timed out response"),
+        Ok(false) => tracing::debug!("Timed out response failed while it was drained"),
+        Err(_) => tracing::debug!(?timeout, "Timed out response took too long to drain"),
+    }
+}
+
+/// Reads `body` to its end, returning `false` if it fails or no frames arrive
+/// for longer than `idle`.
+async fn read_to_end<B: Body>(mut body: Pin<Box<B>>, idle: Option<Duration>) -> bool {
+    loop {
+        let frame = match idle {
+            Some(idle) => match time::timeout(idle, body.frame()).await {
+                Ok(frame) => frame,
+                Err(_) => return false,
+            },
+            None => body.frame().await,
+        };
+        match frame {
+            Some(Ok(_)) => {}
+            Some(Err(_)) => return false,
+            None => return true,
+        }
+    }
+}
+
+// === impl TimeoutBody ===
+
+impl<B> TimeoutBody<B> {
+    fn new(inner: B, deadline: Option<Instant>, timeouts: route::Timeouts) -> Self {
+        Self {
+            inner: Some(Box::pin(inner)),
+            deadline: deadline.map(time::sleep_until),
+            idle: timeouts.body_idle.map(time::sleep),
+            timeouts,
+        }
+    }
+}
+
+impl<B> Body for TimeoutBody<B>
+where
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Error> + Send,
+{
+    type Data = B::Data;
+    type Error = Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+        let Some(inner) = this.inner.as_mut() else {
+            return Poll::Ready(None);
+        };
+        if let Poll::Ready(frame) = inner.as_mut().poll_frame(cx) {
+            if frame.is_none() {
+                *this.inner = None;
+            }
+            if let (Some(timeout), Some(idle)) =
+                (this.timeouts.body_idle, this.idle.as_mut().as_pin_mut())
+            {
+                idle.reset(Instant::now() + timeout);
+            }
+            return Poll::Ready(frame.map(|frame| frame.map_err(Into::into)));
+        }
+
+        let error = if let (Some(timeout), Some(deadline)) =
+            (this.timeouts.request, this.deadline.as_pin_mut())
+        {
+            deadline.poll(cx).map(|()| TimeoutError::Request(timeout))
+        } else {
+            Poll::Pending
+        };
+        let error = match error {
+            Poll::Pending => match (this.timeouts.body_idle, this.idle.as_pin_mut()) {
+                (Some(timeout), Some(idle)) => {
+                    idle.poll(cx).map(|()| TimeoutError::BodyIdle(timeout))
+                }
+                _ => Poll::Pending,
+            },
+            ready => ready,
+        };
+        let Poll::Ready(error) = error else {
+            return Poll::Pending;
+        };
+
+        tracing::info!(%error, "Response body timed out");
+        if let Some(inner) = this.inner.take() {
+            if !this.timeouts.closes_upstream() {
+                tokio::spawn(drain_body(inner, *this.timeouts));
+            }
+        }
+        Poll::Ready(Some(Err(error.into())))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner
+            .as_ref()
+            .is_none_or(|inner| inner.is_end_stream())
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.inner {
+            Some(ref inner) => inner.size_hint(),
+            None => SizeHint::with_exact(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use tokio::sync::mpsc;
+
+    /// A body whose frames are sent by the test.
+    struct ChannelBody(mpsc::Receiver<Bytes>);
+
+    impl Body for ChannelBody {
+        type Data = Bytes;
+        type Error = Error;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, Error>>> {
+            self.0
+                .poll_recv(cx)
+                .map(|data| data.map(|data| Ok(Frame::data(data))))
+        }
+    }
+
+    fn timeout_body(
+        request: Option<u64>,
+        body_idle: Option<u64>,
+    ) -> (mpsc::Sender<Bytes>, Pin<Box<TimeoutBody<ChannelBody>>>) {
+        let timeouts = route::Timeouts {
+            request: request.map(Duration::from_millis),
+            body_idle: body_idle.map(Duration::from_millis),
+            ..Default::default()
+        };
+        let deadline = timeouts.request.map(|timeout| Instant::now() + timeout);
+        let (tx, rx) = mpsc::channel(4);
+        let body = TimeoutBody::new(ChannelBody(rx), deadline, timeouts);
+        (tx, Box::pin(body))
+    }
+
+    async fn send_after(tx: &mpsc::Sender<Bytes>, millis: u64) {
+        time::sleep(Duration::from_millis(millis)).await;
+        tx.send(Bytes::from_static(b"eclss")).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn body_idle() {
+        let (tx, mut body) = timeout_body(None, Some(100));
+        send_after(&tx, 60).await;
+        assert!(body.frame().await.unwrap().is_ok());
+        send_after(&tx, 60).await;
+        assert!(
+            body.frame().await.unwrap().is_ok(),
+            "frames reset the idle timeout"
+        );
+        let error = body.frame().await.unwrap().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TimeoutError>(),
+            Some(TimeoutError::BodyIdle(_))
+        ));
+        assert!(
+            body.frame().await.is_none(),
+            "the body ends after a timeout"
+        );
+
+        let (tx, mut body) = timeout_body(None, Some(100));
+        send_after(&tx, 0).await;
+        drop(tx);
+        assert!(body.frame().await.unwrap().is_ok());
+        assert!(body.frame().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn body_deadline() {
+        let (tx, mut body) = timeout_body(Some(150), Some(100));
+        for _ in 0..3 {
+            send_after(&tx, 60).await;
+            assert!(body.frame().await.unwrap().is_ok());
+        }
+        let error = body.frame().await.unwrap().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TimeoutError>(),
+            Some(TimeoutError::Request(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_is_bounded() {
+        let timeouts = route::Timeouts {
+            request: Some(Duration::from_millis(150)),
+            body_idle: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let drain = |rx| {
+            let start = Instant::now();
+            let drain = tokio::spawn(drain_body(Box::pin(ChannelBody(rx)), timeouts));
+            async move {
+                drain.await.unwrap();
+                start.elapsed()
+            }
+        };
+
+        let (tx, rx) = mpsc::channel(4);
+        let drained = drain(rx);
+        send_after(&tx, 60).await;
+        send_after(&tx, 60).await;
+        assert!(drained.await >= Duration::from_millis(150));
+        assert!(
+            tx.is_closed(),
+            "bodies are dropped after the request timeout"
+        );
+
+        let (tx, rx) = mpsc::channel(4);
+        assert!(drain(rx).await < Duration::from_millis(150));
+        assert!(tx.is_closed(), "idle bodies are dropped");
+
+        let (tx, rx) = mpsc::channel(4);
+        let drained = drain(rx);
+        send_after(&tx, 60).await;
+        drop(tx);
+        assert!(
+            drained.await < Duration::from_millis(100),
+            "complete bodies are drained"
+        );
+    }
+}
@@ -1,7 +1,9 @@
 #![allow(opaque_hidden_inferred_bound)]
+pub mod admin;
 pub mod config;
 pub mod discover;
 pub mod http;
+pub mod proxy_protocol;
 pub mod route;
 pub mod serve;
 // pub mod svc;
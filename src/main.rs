@@ -46,7 +46,7 @@ async fn main() -> anyhow::Result<()> {
     let config = Config::load(&args.config)?;
     tracing::debug!(config = format_args!("{config:#?}"));
 
-    let listeners = config.listeners;
+    let listeners = &config.listeners;
     tracing::info!(
         listeners.http = %listeners.http,
         listeners.https = %listeners.https,
@@ -64,12 +64,28 @@ async fn main() -> anyhow::Result<()> {
         let http = Proxy::new(config.clone(), connect)
             .push_http_endpoint()
             .push_http_discover(&discover)
-            .push_http_server()
+            .push_http_server(&discover)
             .into_inner();
-        let serve = serve::serve(listeners.http, sock, tokio::signal::ctrl_c(), http)
-            .instrument(tracing::info_span!("serve_http", addr = %listeners.http));
+        let serve = serve::serve(
+            listeners.http,
+            sock,
+            listeners.proxy_protocol.http.clone().into(),
+            tokio::signal::ctrl_c(),
+            http,
+        )
+        .instrument(tracing::info_span!("serve_http", addr = %listeners.http));
         tokio::spawn(serve)
     };
+    if let Some(addr) = config.admin {
+        let admin =
+            multipass::admin::serve(addr, discover.conflict_metrics(), tokio::signal::ctrl_c())
+                .instrument(tracing::info_span!("serve_admin", %addr));
+        tokio::spawn(async move {
+            if let Err(error) = admin.await {
+                tracing::error!(%error, "Admin server failed");
+            }
+        });
+    }
     http_server.await?;
     Ok(())
 }
@@ -0,0 +1,268 @@
+//! The [PROXY protocol], which load balancers use to tell the servers behind
+//! them the addresses of the clients whose connections they relay.
+//!
+//! [PROXY protocol]: https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Version {
+    /// The human-readable version 1 header.
+    V1,
+
+    /// The binary version 2 header.
+    V2,
+}
+
+/// The addresses of a relayed connection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Addresses {
+    /// The address of the client.
+    pub source: SocketAddr,
+
+    /// The address that the client connected to.
+    pub destination: SocketAddr,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("connection did not start with a PROXY protocol header")]
+    Missing,
+
+    #[error("invalid PROXY protocol header: {0}")]
+    Invalid(&'static str),
+
+    #[error("failed to read PROXY protocol header: {0}")]
+    Io(#[from] io::Error),
+}
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest possible version 1 header, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Reads a PROXY protocol header of either version from the start of `io`.
+///
+/// No more bytes than the header are read, so `io` may be used for the
+/// relayed connection afterwards. Returns `None` if the header doesn't
+/// describe a relayed connection, such as a load balancer's health check.
+pub async fn read<I: AsyncRead + Unpin>(io: &mut I) -> Result<Option<Addresses>, Error> {
+    // Both versions' headers are at least this long.
+    let mut buf = vec![0; V2_SIGNATURE.len()];
+    io.read_exact(&mut buf).await?;
+
+    if buf == V2_SIGNATURE {
+        let mut header = [0; 4];
+        io.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut body = vec![0; len];
+        io.read_exact(&mut body).await?;
+        return parse_v2(header[0], header[1], &body);
+    }
+
+    if !buf.starts_with(b"PROXY ") {
+        return Err(Error::Missing);
+    }
+    while !buf.ends_with(b"\r\n") {
+        if buf.len() == V1_MAX_LEN {
+            return Err(Error::Invalid("version 1 header is too long"));
+        }
+        buf.push(io.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&buf[..buf.len() - 2])
+        .map_err(|_| Error::Invalid("version 1 header is not ASCII"))?;
+    parse_v1(line)
+}
+
+fn parse_v1(line: &str) -> Result<Option<Addresses>, Error> {
+    let mut fields = line.split(' ').skip(1);
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(Error::Invalid("unknown version 1 protocol")),
+    }
+    let mut next = || {
+        fields
+            .next()
+            .ok_or(Error::Invalid("missing version 1 field"))
+    };
+    let invalid_addr = |_| Error::Invalid("invalid version 1 address");
+    let invalid_port = |_| Error::Invalid("invalid version 1 port");
+    let source_ip = next()?.parse::<IpAddr>().map_err(invalid_addr)?;
+    let destination_ip = next()?.parse::<IpAddr>().map_err(invalid_addr)?;
+    let source_port = next()?.parse::<u16>().map_err(invalid_port)?;
+    let destination_port = next()?.parse::<u16>().map_err(invalid_port)?;
+    Ok(Some(Addresses {
+        source: SocketAddr::new(source_ip, source_port),
+        destination: SocketAddr::new(destination_ip, destination_port),
+    }))
+}
+
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> Result<Option<Addresses>, Error> {
+    if version_command >> 4 != 2 {
+        return Err(Error::Invalid("unknown version 2 version"));
+    }
+    match version_command & 0xf {
+        // LOCAL: the connection was made by the proxy itself.
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(Error::Invalid("unknown version 2 command")),
+    }
+    let port = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+    match family {
+        // TCP or UDP over IPv4.
+        0x11 | 0x12 => {
+            let body = body
+                .get(..12)
+                .ok_or(Error::Invalid("version 2 addresses are too short"))?;
+            let ip = |bytes: &[u8]| IpAddr::from(<[u8; 4]>::try_from(bytes).unwrap());
+            Ok(Some(Addresses {
+                source: SocketAddr::new(ip(&body[0..4]), port(&body[8..10])),
+                destination: SocketAddr::new(ip(&body[4..8]), port(&body[10..12])),
+            }))
+        }
+        // TCP or UDP over IPv6.
+        0x21 | 0x22 => {
+            let body = body
+                .get(..36)
+                .ok_or(Error::Invalid("version 2 addresses are too short"))?;
+            let ip = |bytes: &[u8]| IpAddr::from(<[u8; 16]>::try_from(bytes).unwrap());
+            Ok(Some(Addresses {
+                source: SocketAddr::new(ip(&body[0..16]), port(&body[32..34])),
+                destination: SocketAddr::new(ip(&body[16..32]), port(&body[34..36])),
+            }))
+        }
+        // Unspecified or Unix sockets, which can't be described by an IP
+        // address.
+        _ => Ok(None),
+    }
+}
+
+/// Encodes a PROXY protocol header describing a connection from `source` to
+/// `destination`.
+///
+/// If the addresses are of different families, both are sent as IPv6.
+pub fn encode(version: Version, addrs: Addresses) -> Vec<u8> {
+    let (source, destination) = match (addrs.source, addrs.destination) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {
+            (addrs.source, addrs.destination)
+        }
+        (source, destination) => (to_ipv6(source), to_ipv6(destination)),
+    };
+    match version {
+        Version::V1 => {
+            let protocol = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {protocol} {} {} {} {}\r\n",
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port(),
+            )
+            .into_bytes()
+        }
+        Version::V2 => {
+            let mut buf = V2_SIGNATURE.to_vec();
+            // Version 2, PROXY command.
+            buf.push(0x21);
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                    buf.push(0x11);
+                    buf.extend_from_slice(&12u16.to_be_bytes());
+                    buf.extend_from_slice(&source_ip.octets());
+                    buf.extend_from_slice(&destination_ip.octets());
+                }
+                (source_ip, destination_ip) => {
+                    let octets = |ip: IpAddr| match ip {
+                        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+                        IpAddr::V6(ip) => ip.octets(),
+                    };
+                    buf.push(0x21);
+                    buf.extend_from_slice(&36u16.to_be_bytes());
+                    buf.extend_from_slice(&octets(source_ip));
+                    buf.extend_from_slice(&octets(destination_ip));
+                }
+            }
+            buf.extend_from_slice(&source.port().to_be_bytes());
+            buf.extend_from_slice(&destination.port().to_be_bytes());
+            buf
+        }
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(source: &str, destination: &str) -> Addresses {
+        Addresses {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    async fn roundtrip(version: Version, addrs: Addresses) -> Option<Addresses> {
+        let mut buf = encode(version, addrs);
+        buf.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let mut io = &buf[..];
+        let read = read(&mut io).await.unwrap();
+        assert_eq!(io, b"GET / HTTP/1.1\r\n", "only the header is read");
+        read
+    }
+
+    #[tokio::test]
+    async fn roundtrips() {
+        for version in [Version::V1, Version::V2] {
+            let v4 = addrs("192.168.1.20:51234", "192.168.1.1:80");
+            assert_eq!(roundtrip(version, v4).await, Some(v4));
+
+            let v6 = addrs("[2001:db8::17]:51234", "[2001:db8::1]:443");
+            assert_eq!(roundtrip(version, v6).await, Some(v6));
+
+            let mixed = addrs("192.168.1.20:51234", "[2001:db8::1]:443");
+            assert_eq!(
+                roundtrip(version, mixed).await,
+                Some(addrs("[::ffff:192.168.1.20]:51234", "[2001:db8::1]:443"))
+            );
+        }
+        assert_eq!(
+            encode(Version::V1, addrs("192.168.1.20:51234", "192.168.1.1:80")),
+            b"PROXY TCP4 192.168.1.20 192.168.1.1 51234 80\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid() {
+        async fn read_all(mut buf: &[u8]) -> Result<Option<Addresses>, Error> {
+            read(&mut buf).await
+        }
+        assert!(matches!(
+            read_all(b"GET / HTTP/1.1\r\n").await,
+            Err(Error::Missing)
+        ));
+        assert!(matches!(read_all(b"PROXY UNKNOWN\r\n").await, Ok(None)));
+        assert!(matches!(
+            read_all(b"PROXY TCP4 192.168.1.20\r\n").await,
+            Err(Error::Invalid(_))
+        ));
+        let mut long = b"PROXY TCP4 ".to_vec();
+        long.extend_from_slice(&[b'1'; 200]);
+        assert!(matches!(read_all(&long).await, Err(Error::Invalid(_))));
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert!(
+            matches!(read_all(&local).await, Ok(None)),
+            "LOCAL connections"
+        );
+    }
+}
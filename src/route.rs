@@ -1,22 +1,409 @@
-use crate::discover::Name;
+use crate::discover::{self, Name};
 use http::uri;
-use std::sync::Arc;
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+mod headers;
+
+pub use self::headers::{HeaderActions, HeaderRules, InvalidTemplate, Template, TemplateContext};
 
 #[derive(Debug, Clone)]
 pub struct RoutingTable {
-    routes: Arc<[(Recognize, Name)]>,
+    routes: Arc<[Arc<Rule>]>,
+
+    /// The rule that requests matching no other rule are routed by, if any.
+    fallback: Option<Arc<Rule>>,
+
+    /// The address of the client whose requests are being routed, for rules
+    /// that match on [`Recognize::source_cidrs`].
+    client_addr: Option<IpAddr>,
+}
+
+/// A single route: the rules a request must match, and the service that
+/// matching requests are sent to.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub recognize: Recognize,
+    pub service: Name,
+
+    /// Rules with a higher priority are matched before those with a lower
+    /// priority.
+    pub priority: i32,
+
+    /// How to rewrite the paths of matching requests.
+    pub rewrite: Option<Rewrite>,
+
+    /// If set, requests are split between several services, rather than all
+    /// being sent to `service`.
+    pub split: Option<Split>,
+
+    /// If set, a sample of requests is also copied to another service.
+    pub mirror: Option<Mirror>,
+
+    /// Whether matching requests are forwarded to `service` or answered by
+    /// the gateway itself.
+    pub action: Action,
+
+    /// Changes to the headers of matching requests and their responses.
+    pub headers: Arc<HeaderRules>,
+
+    /// The headers that tell the service how requests were forwarded to it.
+    /// Requests that a `split` sends to one of its backends use that
+    /// backend's instead, as do its `proxy_protocol` and `timeouts`.
+    pub forwarding: Forwarding,
+
+    /// If set, connections that requests are forwarded on start with a PROXY
+    /// protocol header of this version.
+    pub proxy_protocol: Option<crate::proxy_protocol::Version>,
+
+    /// How long forwarded requests may take.
+    pub timeouts: Timeouts,
+}
+
+/// What a [`Rule`] does with the requests that match it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Action {
+    /// Forwards requests to the rule's service.
+    #[default]
+    Forward,
+
+    /// Redirects clients to another location.
+    Redirect(Redirect),
+
+    /// Responds with a fixed status, headers, and body.
+    Respond(FixedResponse),
+
+    /// Serves files from a local directory.
+    Files(Files),
+
+    /// Lists the gateway's services.
+    Index,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub status: http::StatusCode,
+
+    /// The `Location` to redirect to. References to the capture groups of
+    /// the rule's `path_regex`, such as `$1` or `$name`, are replaced with
+    /// the text they matched.
+    pub location: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Files {
+    /// The directory that request paths are resolved in, after the rule's
+    /// rewrites are applied.
+    pub root: std::path::PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FixedResponse {
+    pub status: http::StatusCode,
+    pub headers: http::HeaderMap,
+
+    /// If unset, the response has the gateway's own status page as its body.
+    pub body: Option<bytes::Bytes>,
+}
+
+/// The result of routing a request: the service to send it to, and the rule
+/// that it matched.
+#[derive(Debug, Clone)]
+pub struct Selected {
+    pub service: Name,
+    pub rule: Arc<Rule>,
+
+    /// The index of the backend that the rule's split picked, if it has one.
+    pub backend: Option<usize>,
+
+    /// A `Set-Cookie` header that pins the client to `service`, if the rule's
+    /// split assigned it by cookie.
+    pub set_cookie: Option<http::HeaderValue>,
+}
+
+/// Splits a route's requests between services, in proportion to their
+/// weights.
+#[derive(Debug, Clone)]
+pub struct Split {
+    pub backends: Vec<WeightedService>,
+    pub sticky: Option<Sticky>,
+}
+
+/// A backend of a [`Split`]. Requests sent to it use its own service's
+/// forwarding headers, PROXY protocol version, and timeouts, rather than the
+/// rule's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WeightedService {
+    pub service: Name,
+    pub weight: u32,
+    pub forwarding: Forwarding,
+    pub proxy_protocol: Option<crate::proxy_protocol::Version>,
+    pub timeouts: Timeouts,
+}
+
+/// How a client is kept on the same service of a [`Split`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Sticky {
+    /// Assigns clients to a service by setting a cookie with this name.
+    ///
+    /// The cookie's value is the index of the backend in the split, rather
+    /// than the service's mDNS name, so that internal names aren't exposed to
+    /// clients. Reordering a split's backends reassigns its pinned clients.
+    Cookie(String),
+
+    /// Assigns clients to a service by a hash of their address.
+    ///
+    /// The hash is 64-bit FNV-1a of the address's octets, so that clients
+    /// keep their assignment across restarts and upgrades as long as the
+    /// split's backends and weights don't change.
+    ClientAddr,
+}
+
+/// Copies a sample of a route's requests to another service, discarding its
+/// responses.
+///
+/// Mirrored requests use the mirrored service's own header rules, forwarding
+/// headers, PROXY protocol version, and timeouts, rather than the rule's.
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    pub service: Name,
+
+    /// The percentage of requests, from 0 to 100, that are mirrored.
+    pub percent: f64,
+
+    /// Requests with larger bodies than this are not mirrored, so that at
+    /// most this many bytes are buffered for each mirrored request.
+    pub max_body_bytes: usize,
+
+    /// Limits how many mirrored requests may be in flight at once. Requests
+    /// aren't mirrored while every permit is taken.
+    pub in_flight: Arc<tokio::sync::Semaphore>,
+
+    pub headers: Arc<HeaderRules>,
+    pub forwarding: Forwarding,
+    pub proxy_protocol: Option<crate::proxy_protocol::Version>,
+    pub timeouts: Timeouts,
+}
+
+/// The headers that tell a service how a request was forwarded to it.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Forwarding {
+    #[serde(default = "Forwarding::default_headers")]
+    pub headers: Vec<ForwardedHeader>,
+
+    /// Whether forwarding headers set by a previous proxy are extended or
+    /// replaced.
+    #[serde(default)]
+    pub mode: ForwardingMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    /// The RFC 7239 `Forwarded` header.
+    Forwarded,
+
+    /// The `X-Forwarded-For`, `-Proto`, `-Host`, and `-Port` headers.
+    XForwarded,
+
+    /// The `X-Real-IP` header.
+    XRealIp,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardingMode {
+    /// Adds this hop to the `Forwarded` and `X-Forwarded-For` chains, and
+    /// keeps the original `X-Forwarded-Proto`, `-Host`, `-Port`, and
+    /// `X-Real-IP` values if a previous proxy set them.
+    #[default]
+    Append,
+
+    /// Discards any forwarding headers set by a previous proxy.
+    Replace,
 }
 
+/// How long the requests forwarded to a service may take. Timeouts are given
+/// in seconds, and are unlimited if unset.
 #[serde_with::serde_as]
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Timeouts {
+    /// The time from receiving a request to the end of its response body.
+    #[serde_as(as = "Option<serde_with::DurationSecondsWithFrac<f64>>")]
+    #[serde(default)]
+    pub request: Option<Duration>,
+
+    /// The time from receiving a request to receiving the service's response
+    /// headers.
+    #[serde_as(as = "Option<serde_with::DurationSecondsWithFrac<f64>>")]
+    #[serde(default)]
+    pub response_headers: Option<Duration>,
+
+    /// The time that the service may wait between frames of a response
+    /// body.
+    #[serde_as(as = "Option<serde_with::DurationSecondsWithFrac<f64>>")]
+    #[serde(default)]
+    pub body_idle: Option<Duration>,
+
+    /// Whether the connection to the service is closed when a timeout
+    /// expires. Otherwise, the rest of the response is read and discarded in
+    /// the background, so that the connection can be reused, for up to the
+    /// request and body idle timeouts again. Defaults to `true`.
+    #[serde(default)]
+    pub close_upstream: Option<bool>,
+}
+
+/// Rewrites the path of a request before it is forwarded to a service.
+///
+/// If a prefix is removed from the path, it is sent upstream in the
+/// `X-Forwarded-Prefix` header.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Rewrite {
+    /// Removes this prefix from the path, if the path starts with it.
+    #[serde(default)]
+    pub strip_prefix: Option<String>,
+
+    /// Replaces the part of the path matched by the rule's `path_regex` with
+    /// this, which may refer to the regex's capture groups as `$1` or
+    /// `$name`.
+    #[serde(default)]
+    pub path: Option<String>,
+
+    /// Adds this prefix to the path, after any other rewrites.
+    #[serde(default)]
+    pub add_prefix: Option<String>,
+}
+
+/// A path produced by a [`Rewrite`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RewrittenPath {
+    pub path: String,
+
+    /// The prefix that was removed from the original path, if any.
+    pub stripped: Option<String>,
+}
+
+/// Rules that a request must match.
+///
+/// A request matches only if *all* of the configured rules match it. An
+/// empty `Recognize` matches every request.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
 pub struct Recognize {
     #[serde_as(as = "Option<serde_with::DisplayFromStr>")]
     #[serde(default)]
-    pub host: Option<uri::Authority>,
+    pub host: Option<HostMatch>,
 
     #[serde_as(as = "Option<serde_with::DisplayFromStr>")]
     #[serde(default)]
     pub path_regex: Option<regex::Regex>,
+
+    #[serde_as(as = "Option<serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    pub method: Option<http::Method>,
+
+    #[serde(default)]
+    pub headers: Vec<HeaderMatch>,
+
+    #[serde(default)]
+    pub query: Vec<QueryMatch>,
+
+    /// If non-empty, the client's address must be in one of these CIDRs.
+    #[serde(default)]
+    pub source_cidrs: Vec<ipnet::IpNet>,
+
+    /// If non-empty, at least one of these rules must also match.
+    #[serde(default)]
+    pub any: Vec<Recognize>,
+}
+
+/// Matches the host a request was sent to.
+///
+/// Hosts are compared case-insensitively, ignoring any port and trailing dot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostMatch {
+    /// Matches exactly this host, such as `eclss.example.com`.
+    Exact(String),
+
+    /// Matches any single label followed by this suffix, such as
+    /// `.lab.example.com` for `*.lab.example.com`. The matched label is
+    /// captured.
+    Wildcard(String),
+
+    /// Matches any host, written as `*`.
+    Any,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid host: {0}")]
+pub struct InvalidHost(String);
+
+/// The parts of a request captured by matching it against a [`Recognize`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Captures {
+    /// The label matched by a [`HostMatch::Wildcard`], such as `foo` for
+    /// `foo.lab.example.com`.
+    pub label: Option<String>,
+}
+
+/// Matches a request header by name.
+///
+/// If neither a `value` nor a `regex` is configured, the header only has to be
+/// present.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct HeaderMatch {
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    pub name: http::header::HeaderName,
+
+    #[serde(flatten)]
+    pub value: ValueMatch,
+}
+
+/// Matches a query parameter by name.
+///
+/// If neither a `value` nor a `regex` is configured, the parameter only has to
+/// be present.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct QueryMatch {
+    pub name: String,
+
+    #[serde(flatten)]
+    pub value: ValueMatch,
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct ValueMatch {
+    /// The value must be exactly this.
+    #[serde(default)]
+    pub value: Option<String>,
+
+    /// The value must match this regex.
+    #[serde_as(as = "Option<serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    pub regex: Option<regex::Regex>,
+}
+
+/// How specific a route's match rules are. Routes are tried from the most to
+/// the least specific.
+///
+/// Fields are compared in order, so an explicit priority always wins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Specificity {
+    priority: i32,
+    host: HostSpecificity,
+    has_path: bool,
+    path_prefix_len: usize,
+    /// The number of method, header, query, and source address rules.
+    conditions: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum HostSpecificity {
+    Any,
+    Wildcard,
+    Exact,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -26,16 +413,18 @@ pub struct NoService(());
 // === impl RoutingTable ===
 
 impl<B> linkerd_router::SelectRoute<http::Request<B>> for RoutingTable {
-    type Key = Name;
+    type Key = Selected;
     type Error = NoService;
 
     /// Given a a request, returns the key matching this request.
     ///
-    /// If no route matches the request, this method returns an error.
+    /// If no route matches the request, it is routed by the fallback rule. If
+    /// there is none, this method returns an error.
     fn select(&self, req: &http::Request<B>) -> Result<Self::Key, Self::Error> {
         self.routes
             .iter()
-            .find_map(|(recognize, name)| recognize.matches(req).then(|| name.clone()))
+            .chain(&self.fallback)
+            .find_map(|rule| rule.select(req, self.client_addr))
             .ok_or_else(|| {
                 tracing::info!(uri = ?req.uri(), headers = ?req.headers(), "no service for request");
                 NoService(())
@@ -43,10 +432,383 @@ impl<B> linkerd_router::SelectRoute<http::Request<B>> for RoutingTable {
     }
 }
 
-impl FromIterator<(Recognize, Name)> for RoutingTable {
-    fn from_iter<T: IntoIterator<Item = (Recognize, Name)>>(iter: T) -> Self {
+impl RoutingTable {
+    /// Returns this routing table, as used to route requests from
+    /// `client_addr`.
+    pub fn for_client(&self, client_addr: IpAddr) -> Self {
+        Self {
+            routes: self.routes.clone(),
+            fallback: self.fallback.clone(),
+            client_addr: Some(client_addr),
+        }
+    }
+
+    /// Returns this routing table, routing requests that match no other
+    /// rule by `fallback`.
+    pub fn with_fallback(self, fallback: Rule) -> Self {
+        Self {
+            fallback: Some(Arc::new(fallback)),
+            ..self
+        }
+    }
+
+    /// Returns pairs of rules that route to different services, are equally
+    /// specific, and may match the same requests.
+    ///
+    /// Which of these rules a request is routed by depends only on the
+    /// services' names, which is probably not what was intended.
+    pub fn ambiguous(&self) -> impl Iterator<Item = (&Rule, &Rule)> + '_ {
+        self.routes.iter().enumerate().flat_map(move |(i, a)| {
+            self.routes[i + 1..]
+                .iter()
+                .take_while(move |b| a.specificity() == b.specificity())
+                .filter(move |b| a.service != b.service && a.recognize.overlaps(&b.recognize))
+                .map(move |b| (a, b))
+        })
+    }
+}
+
+impl FromIterator<Rule> for RoutingTable {
+    /// Builds a routing table, ordering rules from the most to the least
+    /// specific. Equally specific rules are ordered by service name, so the
+    /// order does not depend on the order of `iter`.
+    fn from_iter<T: IntoIterator<Item = Rule>>(iter: T) -> Self {
+        let mut routes = iter.into_iter().collect::<Vec<_>>();
+        routes.sort_by(|a, b| {
+            b.specificity()
+                .cmp(&a.specificity())
+                .then_with(|| a.service.cmp(&b.service))
+        });
+        Self {
+            routes: routes.into_iter().map(Arc::new).collect(),
+            fallback: None,
+            client_addr: None,
+        }
+    }
+}
+
+// === impl Rule ===
+
+impl Rule {
+    pub fn new(recognize: Recognize, service: Name) -> Self {
+        Self {
+            recognize,
+            service,
+            priority: 0,
+            rewrite: None,
+            split: None,
+            mirror: None,
+            action: Action::Forward,
+            headers: Default::default(),
+            forwarding: Forwarding::default(),
+            proxy_protocol: None,
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    /// Returns a rule that answers the requests it matches with `action`.
+    ///
+    /// The rule's service is named after the action, so that it can be told
+    /// apart from other rules in logs.
+    pub fn synthetic(recognize: Recognize, action: Action) -> Self {
+        Self {
+            action: action.clone(),
+            ..Self::new(recognize, Name::from(action.to_string()))
+        }
+    }
+
+    /// Returns the service to route `req` to, if it matches this rule.
+    ///
+    /// If the service's name is a pattern, its placeholder is replaced with
+    /// the label matched by a wildcard host.
+    fn select<B>(
+        self: &Arc<Self>,
+        req: &http::Request<B>,
+        client_addr: Option<IpAddr>,
+    ) -> Option<Selected> {
+        let captures = self.recognize.capture(req, client_addr)?;
+        let (service, backend, set_cookie) = match self.split {
+            Some(ref split) => {
+                let (backend, set_cookie) = split.select(req, client_addr);
+                (
+                    split.backends[backend].service.clone(),
+                    Some(backend),
+                    set_cookie,
+                )
+            }
+            None => (self.service.clone(), None, None),
+        };
+        let service = if discover::is_pattern(&service) {
+            let label = captures.label?;
+            let expanded = discover::expand_pattern(&service, &label);
+            tracing::debug!(service = %expanded, pattern = %service, "Expanded service name");
+            expanded
+        } else {
+            service
+        };
+        Some(Selected {
+            service,
+            rule: self.clone(),
+            backend,
+            set_cookie,
+        })
+    }
+
+    /// Returns the path that `path` should be rewritten to, or `None` if it
+    /// should not be rewritten.
+    pub fn rewrite_path(&self, path: &str) -> Option<RewrittenPath> {
+        let rewrite = self.rewrite.as_ref()?;
+        let rewritten = rewrite.apply(path, self.recognize.path_regex.as_ref());
+        (rewritten.path != path).then_some(rewritten)
+    }
+
+    /// Returns the `Location` that `uri` is redirected to, if this rule
+    /// redirects requests.
+    ///
+    /// If the location doesn't have a query of its own, `uri`'s query is
+    /// kept.
+    pub fn redirect_location(&self, uri: &http::Uri) -> Option<Result<http::Uri, uri::InvalidUri>> {
+        let Action::Redirect(ref redirect) = self.action else {
+            return None;
+        };
+        let mut location = match self.recognize.path_regex {
+            Some(ref regex) => match regex.captures(uri.path()) {
+                Some(captures) => {
+                    let mut location = String::new();
+                    captures.expand(&redirect.location, &mut location);
+                    location
+                }
+                None => redirect.location.clone(),
+            },
+            None => redirect.location.clone(),
+        };
+        if let Some(query) = uri.query().filter(|_| !location.contains('?')) {
+            location.push('?');
+            location.push_str(query);
+        }
+        Some(location.parse())
+    }
+
+    /// Returns the URL at which this rule's service can be reached, if its
+    /// requests must be for a single host.
+    pub fn public_url(&self) -> Option<String> {
+        let Some(HostMatch::Exact(ref host)) = self.recognize.host else {
+            return None;
+        };
+        let prefix = match self.recognize.path_regex {
+            Some(ref regex) => literal_prefix(regex),
+            None => String::new(),
+        };
+        let prefix = if prefix.starts_with('/') {
+            prefix
+        } else {
+            format!("/{prefix}")
+        };
+        Some(format!("http://{host}{prefix}"))
+    }
+
+    pub fn specificity(&self) -> Specificity {
+        let path_prefix_len = self
+            .recognize
+            .path_regex
+            .as_ref()
+            .map(|re| literal_prefix(re).len())
+            .unwrap_or(0);
+        Specificity {
+            priority: self.priority,
+            host: match self.recognize.host {
+                Some(HostMatch::Exact(_)) => HostSpecificity::Exact,
+                Some(HostMatch::Wildcard(_)) => HostSpecificity::Wildcard,
+                Some(HostMatch::Any) | None => HostSpecificity::Any,
+            },
+            has_path: self.recognize.path_regex.is_some(),
+            path_prefix_len,
+            conditions: self.recognize.conditions(),
+        }
+    }
+}
+
+// === impl Split ===
+
+impl Split {
+    pub fn total_weight(&self) -> u64 {
+        self.backends.iter().map(|b| u64::from(b.weight)).sum()
+    }
+
+    /// Selects the index of the backend to send `req` to, and returns a
+    /// `Set-Cookie` header value if the client should be pinned to it.
+    fn select<B>(
+        &self,
+        req: &http::Request<B>,
+        client_addr: Option<IpAddr>,
+    ) -> (usize, Option<http::HeaderValue>) {
+        match (&self.sticky, client_addr) {
+            (Some(Sticky::Cookie(cookie)), _) => {
+                let pinned = cookie_value(req.headers(), cookie)
+                    .and_then(|value| value.parse::<usize>().ok())
+                    .filter(|&backend| self.backends.get(backend).is_some_and(|b| b.weight > 0));
+                if let Some(backend) = pinned {
+                    tracing::trace!(service = %self.backends[backend].service, %cookie, "Sticky cookie");
+                    return (backend, None);
+                }
+
+                let backend = self.pick(self.random());
+                let set_cookie =
+                    http::HeaderValue::try_from(format!("{cookie}={backend}; Path=/; HttpOnly"))
+                        .ok();
+                (backend, set_cookie)
+            }
+            (Some(Sticky::ClientAddr), Some(addr)) => {
+                let hash = match addr {
+                    IpAddr::V4(ip) => fnv1a(&ip.octets()),
+                    IpAddr::V6(ip) => fnv1a(&ip.octets()),
+                };
+                (self.pick(hash % self.total_weight()), None)
+            }
+            _ => (self.pick(self.random()), None),
+        }
+    }
+
+    fn random(&self) -> u64 {
+        use rand::Rng;
+        rand::thread_rng().gen_range(0..self.total_weight())
+    }
+
+    /// Returns the index of the backend whose share of the total weight
+    /// includes `point`.
+    fn pick(&self, mut point: u64) -> usize {
+        for (i, backend) in self.backends.iter().enumerate() {
+            let weight = u64::from(backend.weight);
+            if point < weight {
+                return i;
+            }
+            point -= weight;
+        }
+        unreachable!("point must be less than the total weight")
+    }
+}
+
+/// Hashes `bytes` with 64-bit FNV-1a.
+///
+/// Unlike `std`'s `DefaultHasher`, whose algorithm may change between Rust
+/// releases, FNV-1a's output is fixed by its specification, so assignments
+/// based on it survive restarts and upgrades.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    bytes.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
+}
+
+fn cookie_value<'h>(headers: &'h http::HeaderMap, name: &str) -> Option<&'h str> {
+    headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find_map(|(k, v)| (k == name).then_some(v))
+}
+
+// === impl Action ===
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Forward => f.write_str("forward"),
+            Self::Redirect(Redirect { location, .. }) => write!(f, "redirect to {location}"),
+            Self::Respond(FixedResponse { status, .. }) => write!(f, "respond with {status}"),
+            Self::Files(Files { root }) => write!(f, "serve files from {}", root.display()),
+            Self::Index => f.write_str("list services"),
+        }
+    }
+}
+
+// === impl Forwarding ===
+
+impl Default for Forwarding {
+    fn default() -> Self {
         Self {
-            routes: iter.into_iter().collect(),
+            headers: Self::default_headers(),
+            mode: ForwardingMode::default(),
+        }
+    }
+}
+
+impl Forwarding {
+    fn default_headers() -> Vec<ForwardedHeader> {
+        vec![ForwardedHeader::Forwarded]
+    }
+}
+
+// === impl Timeouts ===
+
+impl Timeouts {
+    /// Returns these timeouts, with any that are unset taken from
+    /// `defaults`.
+    pub fn or(self, defaults: Self) -> Self {
+        Self {
+            request: self.request.or(defaults.request),
+            response_headers: self.response_headers.or(defaults.response_headers),
+            body_idle: self.body_idle.or(defaults.body_idle),
+            close_upstream: self.close_upstream.or(defaults.close_upstream),
+        }
+    }
+
+    pub fn closes_upstream(&self) -> bool {
+        self.close_upstream.unwrap_or(true)
+    }
+}
+
+// === impl Rewrite ===
+
+impl Rewrite {
+    pub fn apply(&self, path: &str, path_regex: Option<&regex::Regex>) -> RewrittenPath {
+        let mut stripped = None;
+        let mut rewritten = match (&self.path, path_regex) {
+            (Some(replacement), Some(regex)) => {
+                let rewritten = regex.replace(path, replacement.as_str()).into_owned();
+                // If the rewrite only removed the start of the path, that was
+                // the prefix.
+                if rewritten.len() < path.len() && path.ends_with(&rewritten) {
+                    stripped = Some(path[..path.len() - rewritten.len()].to_string());
+                }
+                rewritten
+            }
+            (Some(replacement), None) => replacement.clone(),
+            (None, _) => match self.strip_prefix.as_deref() {
+                Some(prefix) => {
+                    let prefix = prefix.trim_end_matches('/');
+                    match path.strip_prefix(prefix) {
+                        Some(rest)
+                            if !prefix.is_empty() && (rest.is_empty() || rest.starts_with('/')) =>
+                        {
+                            stripped = Some(prefix.to_string());
+                            rest.to_string()
+                        }
+                        _ => path.to_string(),
+                    }
+                }
+                None => path.to_string(),
+            },
+        };
+
+        if let Some(ref prefix) = self.add_prefix {
+            let rest = rewritten.trim_start_matches('/');
+            let prefix = prefix.trim_end_matches('/');
+            rewritten = format!("{prefix}/{rest}");
+        }
+
+        if !rewritten.starts_with('/') {
+            rewritten.insert(0, '/');
+        }
+
+        RewrittenPath {
+            path: rewritten,
+            stripped: stripped
+                .map(|prefix| prefix.trim_end_matches('/').to_string())
+                .filter(|prefix| !prefix.is_empty()),
         }
     }
 }
@@ -55,48 +817,297 @@ impl FromIterator<(Recognize, Name)> for RoutingTable {
 
 impl Recognize {
     pub fn is_empty(&self) -> bool {
-        self.path_regex.is_none() && self.host.is_none()
+        self.path_regex.is_none() && self.host.is_none() && self.conditions() == 0
     }
 
-    pub fn matches<B>(&self, req: &http::Request<B>) -> bool {
-        if let Some(ref authority) = self.host {
-            let host = authority.host();
-            if req.uri().authority().map(uri::Authority::host) == Some(host) {
-                tracing::debug!(host, "request `:authority` matches");
-                return true;
-            } else {
-                tracing::trace!(host, "request `:authority` does not match");
-            }
+    fn conditions(&self) -> usize {
+        usize::from(self.method.is_some())
+            + self.headers.len()
+            + self.query.len()
+            + usize::from(!self.source_cidrs.is_empty())
+            + usize::from(!self.any.is_empty())
+    }
 
-            let host_header = req
-                .headers()
-                .get(http::header::HOST)
-                .and_then(|val| val.to_str().ok());
-            if host_header == Some(host) {
-                tracing::debug!(host, "request `Host` header matches");
-                return true;
-            } else {
-                tracing::trace!(host, ?host_header, "request `Host` header does not match");
+    /// Returns `true` if some request could plausibly match both `self` and
+    /// `other`.
+    ///
+    /// Path regexes are compared by their literal prefixes, and only methods
+    /// are compared among the other rules, so this is a heuristic rather than
+    /// an exact answer.
+    fn overlaps(&self, other: &Self) -> bool {
+        let hosts = match (&self.host, &other.host) {
+            (Some(a), Some(b)) => a == b,
+            (None, None) => true,
+            _ => false,
+        };
+        let paths = match (&self.path_regex, &other.path_regex) {
+            (Some(a), Some(b)) => {
+                let (a, b) = (literal_prefix(a), literal_prefix(b));
+                a.starts_with(&b) || b.starts_with(&a)
             }
+            (None, None) => true,
+            _ => false,
+        };
+        let methods = match (&self.method, &other.method) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        hosts && paths && methods
+    }
+
+    /// Returns `true` if the request, sent by `client_addr`, matches all of
+    /// these rules.
+    pub fn matches<B>(&self, req: &http::Request<B>, client_addr: Option<IpAddr>) -> bool {
+        self.capture(req, client_addr).is_some()
+    }
+
+    /// Matches the request, sent by `client_addr`, against all of these
+    /// rules, returning what was captured if it matches.
+    pub fn capture<B>(
+        &self,
+        req: &http::Request<B>,
+        client_addr: Option<IpAddr>,
+    ) -> Option<Captures> {
+        let mut captures = Captures::default();
+        if let Some(ref host) = self.host {
+            captures.label = host.capture(req)?;
         }
 
         if let Some(ref path_regex) = self.path_regex {
-            if let Some(path) = req
-                .uri()
-                .path_and_query()
-                .map(http::uri::PathAndQuery::path)
-            {
-                if path_regex.is_match(path) {
-                    tracing::debug!(%path_regex, path, "request path matches");
-                    return true;
-                } else {
-                    tracing::trace!(%path_regex, path, "request path does not match");
+            if !Self::path_matches(path_regex, req) {
+                return None;
+            }
+        }
+
+        if let Some(ref method) = self.method {
+            if req.method() != method {
+                tracing::trace!(%method, "request method does not match");
+                return None;
+            }
+        }
+
+        if !self.headers.iter().all(|h| h.matches(req.headers())) {
+            return None;
+        }
+
+        if !self.query.iter().all(|q| q.matches(req.uri().query())) {
+            return None;
+        }
+
+        if !self.source_matches(client_addr) {
+            return None;
+        }
+
+        if !self.any.is_empty() {
+            let any = self.any.iter().find_map(|r| r.capture(req, client_addr))?;
+            captures.label = captures.label.or(any.label);
+        }
+
+        Some(captures)
+    }
+
+    fn path_matches<B>(path_regex: &regex::Regex, req: &http::Request<B>) -> bool {
+        let path = req.uri().path();
+        if path_regex.is_match(path) {
+            tracing::debug!(%path_regex, path, "request path matches");
+            true
+        } else {
+            tracing::trace!(%path_regex, path, "request path does not match");
+            false
+        }
+    }
+
+    fn source_matches(&self, client_addr: Option<IpAddr>) -> bool {
+        if self.source_cidrs.is_empty() {
+            return true;
+        }
+        let matches = client_addr
+            .is_some_and(|addr| self.source_cidrs.iter().any(|cidr| cidr.contains(&addr)));
+        tracing::trace!(?client_addr, matches, "request source address");
+        matches
+    }
+}
+
+// === impl HostMatch ===
+
+impl HostMatch {
+    /// Returns `None` if the request's host does not match, or the label
+    /// matched by a wildcard.
+    ///
+    /// Both the URI's authority and the `Host` header are checked.
+    fn capture<B>(&self, req: &http::Request<B>) -> Option<Option<String>> {
+        if *self == Self::Any {
+            return Some(None);
+        }
+        let authority = req.uri().authority().map(uri::Authority::host);
+        let host_header = req
+            .headers()
+            .get(http::header::HOST)
+            .and_then(|val| val.to_str().ok())
+            .and_then(|val| val.parse::<uri::Authority>().ok());
+        let captured = [authority, host_header.as_ref().map(uri::Authority::host)]
+            .into_iter()
+            .flatten()
+            .find_map(|host| self.capture_host(host));
+        match captured {
+            Some(ref label) => tracing::debug!(host = %self, ?label, "request host matches"),
+            None => tracing::trace!(
+                host = %self,
+                ?authority,
+                ?host_header,
+                "request host does not match"
+            ),
+        }
+        captured
+    }
+
+    fn capture_host(&self, host: &str) -> Option<Option<String>> {
+        let host = host.trim_end_matches('.');
+        match self {
+            Self::Exact(expected) => host.eq_ignore_ascii_case(expected).then_some(None),
+            Self::Any => Some(None),
+            Self::Wildcard(suffix) => {
+                let split = host.len().checked_sub(suffix.len())?;
+                let (label, tail) = (host.get(..split)?, host.get(split..)?);
+                if label.is_empty() || label.contains('.') || !tail.eq_ignore_ascii_case(suffix) {
+                    return None;
                 }
+                Some(Some(label.to_ascii_lowercase()))
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for HostMatch {
+    type Err = InvalidHost;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(Self::Any);
+        }
+        let (wildcard, host) = match s.strip_prefix("*.") {
+            Some(suffix) => (true, suffix),
+            None => (false, s),
+        };
+        let authority = host
+            .parse::<uri::Authority>()
+            .map_err(|_| InvalidHost(s.to_string()))?;
+        let host = authority.host().trim_end_matches('.').to_ascii_lowercase();
+        if host.is_empty() || host.contains('*') {
+            return Err(InvalidHost(s.to_string()));
+        }
+        Ok(if wildcard {
+            Self::Wildcard(format!(".{host}"))
+        } else {
+            Self::Exact(host)
+        })
+    }
+}
+
+impl std::fmt::Display for HostMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exact(host) => f.write_str(host),
+            Self::Wildcard(suffix) => write!(f, "*{suffix}"),
+            Self::Any => f.write_str("*"),
+        }
+    }
+}
+
+// === impl HeaderMatch ===
+
+impl HeaderMatch {
+    fn matches(&self, headers: &http::HeaderMap) -> bool {
+        let matches = headers
+            .get_all(&self.name)
+            .iter()
+            .any(|value| value.to_str().is_ok_and(|v| self.value.matches(v)));
+        tracing::trace!(header = %self.name, matches, "request header");
+        matches
+    }
+}
+
+// === impl QueryMatch ===
+
+impl QueryMatch {
+    fn matches(&self, query: Option<&str>) -> bool {
+        let matches = form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+            .any(|(name, value)| name == self.name && self.value.matches(&value));
+        tracing::trace!(param = %self.name, matches, "request query parameter");
+        matches
+    }
+}
+
+// === impl ValueMatch ===
+
+impl ValueMatch {
+    fn matches(&self, value: &str) -> bool {
+        if let Some(ref expected) = self.value {
+            if expected != value {
+                return false;
+            }
+        }
+
+        match self.regex {
+            Some(ref regex) => regex.is_match(value),
+            None => true,
+        }
+    }
+}
+
+/// Returns the literal text that every match of `regex` must start with,
+/// such as `/eclss/` for `^/eclss/.*`.
+fn literal_prefix(regex: &regex::Regex) -> String {
+    let pattern = regex.as_str();
+    // The branches of a top-level alternation may not share a prefix.
+    if has_top_level_alternation(pattern) {
+        return String::new();
+    }
+
+    let mut prefix = String::new();
+    let mut chars = pattern
+        .strip_prefix('^')
+        .unwrap_or(pattern)
+        .chars()
+        .peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c) if !c.is_ascii_alphanumeric() => prefix.push(c),
+                // A character class like `\d`, rather than an escaped literal.
+                _ => break,
+            },
+            '.' | '+' | '*' | '?' | '(' | ')' | '[' | ']' | '{' | '}' | '^' | '$' => break,
+            c => prefix.push(c),
+        }
+        match chars.peek() {
+            // The previous character is optional.
+            Some('*' | '?' | '{') => {
+                prefix.pop();
+                break;
             }
+            Some('+') => break,
+            _ => {}
         }
+    }
+    prefix
+}
 
-        false
+fn has_top_level_alternation(pattern: &str) -> bool {
+    let mut depth = 0usize;
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            '|' if depth == 0 => return true,
+            _ => {}
+        }
     }
+    false
 }
 
 #[cfg(test)]
@@ -109,7 +1120,7 @@ mod tests {
 
         let recognize = Recognize {
             host: Some("test.example.com".parse().unwrap()),
-            path_regex: None,
+            ..Default::default()
         };
 
         recognize.assert_match(
@@ -173,15 +1184,489 @@ mod tests {
         );
     }
 
+    fn req(host: &str, path_and_query: &str) -> http::Request<()> {
+        http::Request::builder()
+            .header("host", host)
+            .uri(path_and_query)
+            .body(())
+            .unwrap()
+    }
+
+    fn split(sticky: Option<Sticky>) -> Split {
+        let backend = |service: &str, weight| WeightedService {
+            service: Name::from(service),
+            weight,
+            forwarding: Forwarding::default(),
+            proxy_protocol: None,
+            timeouts: Timeouts::default(),
+        };
+        Split {
+            backends: vec![backend("old.local.", 3), backend("new.local.", 1)],
+            sticky,
+        }
+    }
+
+    #[test]
+    fn redirects() {
+        let redirect = |path_regex: Option<&str>, location: &str| Rule {
+            recognize: Recognize {
+                path_regex: path_regex.map(|r| r.parse().unwrap()),
+                ..Default::default()
+            },
+            ..Rule::synthetic(
+                Recognize::default(),
+                Action::Redirect(Redirect {
+                    status: http::StatusCode::MOVED_PERMANENTLY,
+                    location: location.to_string(),
+                }),
+            )
+        };
+        let location = |rule: &Rule, uri: &str| {
+            rule.redirect_location(&uri.parse().unwrap())
+                .unwrap()
+                .unwrap()
+                .to_string()
+        };
+
+        let rule = redirect(Some("^(.*)$"), "https://www.example.com$1");
+        assert_eq!(&*rule.service, "redirect to https://www.example.com$1");
+        assert_eq!(
+            location(&rule, "/eclss/?unit=c"),
+            "https://www.example.com/eclss/?unit=c"
+        );
+
+        let rule = redirect(Some("^/old/(?P<page>.*)$"), "/new/$page");
+        assert_eq!(location(&rule, "/old/sensors"), "/new/sensors");
+
+        let rule = redirect(None, "/moved?from=old");
+        assert_eq!(location(&rule, "/old?x=1"), "/moved?from=old");
+
+        assert!(Rule::new(Recognize::default(), Name::from("eclss.local."))
+            .redirect_location(&"/".parse().unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn fallback() {
+        let table = [rule("eclss.local.", Some("eclss.example.com"), None)]
+            .into_iter()
+            .collect::<RoutingTable>();
+        let select = |table: &RoutingTable, host| {
+            linkerd_router::SelectRoute::select(table, &req(host, "/"))
+                .ok()
+                .map(|selected| selected.service)
+        };
+        assert_eq!(select(&table, "grafana.example.com"), None);
+
+        let table = table.with_fallback(Rule::synthetic(Recognize::default(), Action::Index));
+        assert_eq!(
+            select(&table, "eclss.example.com").as_deref(),
+            Some("eclss.local.")
+        );
+        assert_eq!(
+            select(&table, "grafana.example.com").as_deref(),
+            Some("list services")
+        );
+    }
+
+    #[test]
+    fn public_urls() {
+        let url = |host, path| rule("eclss.local.", host, path).public_url();
+        assert_eq!(
+            url(Some("eclss.example.com"), None).as_deref(),
+            Some("http://eclss.example.com/")
+        );
+        assert_eq!(
+            url(Some("example.com"), Some("^/eclss(/|$)")).as_deref(),
+            Some("http://example.com/eclss")
+        );
+        assert_eq!(url(Some("*.lab.example.com"), None), None);
+        assert_eq!(url(None, Some("^/eclss/")), None);
+    }
+
+    #[test]
+    fn split_by_weight() {
+        let split = split(None);
+        assert_eq!(split.total_weight(), 4);
+        let picks = (0..4)
+            .map(|p| &*split.backends[split.pick(p)].service)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            picks,
+            ["old.local.", "old.local.", "old.local.", "new.local."]
+        );
+
+        let req = req("example.com", "/");
+        let mut new = 0;
+        for _ in 0..1000 {
+            let (backend, cookie) = split.select(&req, None);
+            assert!(cookie.is_none());
+            if &*split.backends[backend].service == "new.local." {
+                new += 1;
+            }
+        }
+        assert!(
+            (150..350).contains(&new),
+            "{new} of 1000 requests split to new"
+        );
+    }
+
+    #[test]
+    fn split_sticky() {
+        let split = split(Some(Sticky::ClientAddr));
+        let req = req("example.com", "/");
+        let client = Some(IpAddr::from([192, 168, 1, 20]));
+        let (first, _) = split.select(&req, client);
+        for _ in 0..10 {
+            assert_eq!(split.select(&req, client).0, first);
+        }
+
+        let split = self::split(Some(Sticky::Cookie("canary".to_string())));
+        let (backend, set_cookie) = split.select(&req, client);
+        let set_cookie = set_cookie.expect("new clients must be assigned a cookie");
+        assert_eq!(
+            set_cookie.to_str().unwrap(),
+            format!("canary={backend}; Path=/; HttpOnly")
+        );
+
+        let pinned = http::Request::builder()
+            .header("cookie", "theme=dark; canary=1")
+            .body(())
+            .unwrap();
+        for _ in 0..10 {
+            let (backend, set_cookie) = split.select(&pinned, client);
+            assert_eq!(&*split.backends[backend].service, "new.local.");
+            assert!(set_cookie.is_none());
+        }
+
+        for invalid in ["canary=new.local.", "canary=2"] {
+            let req = http::Request::builder()
+                .header("cookie", invalid)
+                .body(())
+                .unwrap();
+            let (_, set_cookie) = split.select(&req, client);
+            assert!(
+                set_cookie.is_some(),
+                "clients with unknown backends are reassigned"
+            );
+        }
+    }
+
+    #[test]
+    fn fnv1a() {
+        // Test vectors from the FNV reference implementation.
+        assert_eq!(super::fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(super::fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(super::fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn rewrites() {
+        let strip = Rewrite {
+            strip_prefix: Some("/eclss/".to_string()),
+            ..Default::default()
+        };
+        let rewritten = |path: &str, stripped: Option<&str>| RewrittenPath {
+            path: path.to_string(),
+            stripped: stripped.map(str::to_string),
+        };
+        assert_eq!(
+            strip.apply("/eclss/index.html", None),
+            rewritten("/index.html", Some("/eclss"))
+        );
+        assert_eq!(strip.apply("/eclss", None), rewritten("/", Some("/eclss")));
+        assert_eq!(strip.apply("/eclssfoo", None), rewritten("/eclssfoo", None));
+
+        let add = Rewrite {
+            add_prefix: Some("/api/".to_string()),
+            ..strip
+        };
+        assert_eq!(
+            add.apply("/eclss/sensors", None),
+            rewritten("/api/sensors", Some("/eclss"))
+        );
+
+        let regex = regex::Regex::new("^/devices/(?P<id>[^/]+)").unwrap();
+        let substitute = Rewrite {
+            path: Some("/sensor/$id".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            substitute.apply("/devices/3/metrics", Some(&regex)),
+            rewritten("/sensor/3/metrics", None)
+        );
+
+        let regex = regex::Regex::new("^/eclss").unwrap();
+        let strip_matched = Rewrite {
+            path: Some(String::new()),
+            ..Default::default()
+        };
+        assert_eq!(
+            strip_matched.apply("/eclss/index.html", Some(&regex)),
+            rewritten("/index.html", Some("/eclss"))
+        );
+    }
+
+    #[test]
+    fn wildcard_host() {
+        crate::test_util::trace_init();
+
+        let host = "*.lab.example.com".parse::<HostMatch>().unwrap();
+        assert_eq!(host, HostMatch::Wildcard(".lab.example.com".to_string()));
+        assert_eq!(host.to_string(), "*.lab.example.com");
+
+        let capture = |h: &str| host.capture(&req(h, "/"));
+        assert_eq!(
+            capture("foo.lab.example.com"),
+            Some(Some("foo".to_string()))
+        );
+        assert_eq!(
+            capture("FOO.Lab.Example.com."),
+            Some(Some("foo".to_string()))
+        );
+        assert_eq!(
+            capture("foo.lab.example.com:8080"),
+            Some(Some("foo".to_string()))
+        );
+        assert_eq!(capture("lab.example.com"), None);
+        assert_eq!(capture(".lab.example.com"), None);
+        assert_eq!(capture("a.b.lab.example.com"), None);
+        assert_eq!(capture("foo.example.com"), None);
+
+        let exact = "Eclss.Example.com.".parse::<HostMatch>().unwrap();
+        assert_eq!(exact, HostMatch::Exact("eclss.example.com".to_string()));
+        assert_eq!(exact.capture(&req("ECLSS.example.com.", "/")), Some(None));
+
+        let any = "*".parse::<HostMatch>().unwrap();
+        assert_eq!(any, HostMatch::Any);
+        assert_eq!(any.to_string(), "*");
+        assert_eq!(any.capture(&req("anything.example.com", "/")), Some(None));
+
+        assert!("*.".parse::<HostMatch>().is_err());
+        assert!("foo.*.example.com".parse::<HostMatch>().is_err());
+    }
+
+    #[test]
+    fn expands_pattern_services() {
+        let table = [
+            rule("{label}.local.", Some("*.lab.example.com"), None),
+            rule("gateway.local.", Some("gw.lab.example.com"), None),
+        ]
+        .into_iter()
+        .collect::<RoutingTable>();
+        let select = |host: &str| {
+            linkerd_router::SelectRoute::select(&table, &req(host, "/"))
+                .ok()
+                .map(|selected| selected.service)
+        };
+        assert_eq!(
+            select("foo.lab.example.com"),
+            Some(Name::from("foo.local."))
+        );
+        // Exact hosts are more specific than wildcards.
+        assert_eq!(
+            select("gw.lab.example.com"),
+            Some(Name::from("gateway.local."))
+        );
+        assert_eq!(select("lab.example.com"), None);
+    }
+
+    #[test]
+    fn all_rules_must_match() {
+        crate::test_util::trace_init();
+
+        let recognize = Recognize {
+            host: Some("example.com".parse().unwrap()),
+            path_regex: Some("^/eclss/".parse().unwrap()),
+            ..Default::default()
+        };
+        recognize.assert_match(&req("example.com", "/eclss/metrics"));
+        recognize.assert_not_match(&req("grafana.example.com", "/eclss/metrics"));
+        recognize.assert_not_match(&req("example.com", "/grafana"));
+
+        assert!(Recognize::default().is_empty());
+        Recognize::default().assert_match(&req("example.com", "/"));
+    }
+
+    #[test]
+    fn any() {
+        let recognize = Recognize {
+            any: vec![
+                Recognize {
+                    host: Some("eclss.example.com".parse().unwrap()),
+                    ..Default::default()
+                },
+                Recognize {
+                    path_regex: Some("^/eclss/".parse().unwrap()),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        recognize.assert_match(&req("eclss.example.com", "/"));
+        recognize.assert_match(&req("example.com", "/eclss/metrics"));
+        recognize.assert_not_match(&req("example.com", "/grafana"));
+    }
+
+    #[test]
+    fn method_headers_and_query() {
+        let toml = r#"
+            method = "POST"
+            headers = [
+                { name = "x-api-key" },
+                { name = "content-type", value = "application/json" },
+                { name = "user-agent", regex = "^curl/" },
+            ]
+            query = [{ name = "format", value = "json" }]
+        "#;
+        let recognize: Recognize = toml::from_str(toml).unwrap();
+        let req = |method: &str, uri: &str, user_agent: &str| {
+            http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("x-api-key", "hunter2")
+                .header("content-type", "application/json")
+                .header("user-agent", user_agent)
+                .body(())
+                .unwrap()
+        };
+        recognize.assert_match(&req("POST", "/api?format=json&pretty", "curl/8.0.1"));
+        recognize.assert_not_match(&req("GET", "/api?format=json", "curl/8.0.1"));
+        recognize.assert_not_match(&req("POST", "/api?format=xml", "curl/8.0.1"));
+        recognize.assert_not_match(&req("POST", "/api?format=json", "Mozilla/5.0"));
+
+        let mut missing = req("POST", "/api?format=json", "curl/8.0.1");
+        missing.headers_mut().remove("x-api-key");
+        recognize.assert_not_match(&missing);
+    }
+
+    #[test]
+    fn source_cidrs() {
+        let recognize = Recognize {
+            source_cidrs: vec!["192.168.1.0/24".parse().unwrap()],
+            ..Default::default()
+        };
+        let req = req("example.com", "/");
+        assert!(recognize.matches(&req, Some([192, 168, 1, 20].into())));
+        assert!(!recognize.matches(&req, Some([10, 0, 0, 1].into())));
+        assert!(!recognize.matches(&req, None));
+
+        let table = [Rule::new(recognize, Name::from("lan.local."))]
+            .into_iter()
+            .collect::<RoutingTable>();
+        let lan = table.for_client([192, 168, 1, 20].into());
+        assert!(linkerd_router::SelectRoute::select(&table, &req).is_err());
+        assert!(linkerd_router::SelectRoute::select(&lan, &req).is_ok());
+    }
+
+    fn rule(service: &str, host: Option<&str>, path_regex: Option<&str>) -> Rule {
+        Rule::new(
+            Recognize {
+                host: host.map(|h| h.parse().unwrap()),
+                path_regex: path_regex.map(|p| p.parse().unwrap()),
+                ..Default::default()
+            },
+            Name::from(service),
+        )
+    }
+
+    #[test]
+    fn literal_prefixes() {
+        for (regex, prefix) in [
+            ("^/eclss/.*", "/eclss/"),
+            ("/eclss/*", "/eclss"),
+            ("^/api/v1\\.0/", "/api/v1.0/"),
+            ("^/grafana(/|$)", "/grafana"),
+            ("^/a|^/b", ""),
+            ("^/items/\\d+", "/items/"),
+        ] {
+            let regex = regex::Regex::new(regex).unwrap();
+            assert_eq!(literal_prefix(&regex), prefix, "literal prefix of {regex}");
+        }
+    }
+
+    #[test]
+    fn ordered_by_specificity() {
+        let table = [
+            rule("any-path.local.", None, Some("^/")),
+            rule("host.local.", Some("example.com"), None),
+            rule("short.local.", Some("example.com"), Some("^/a")),
+            rule("long.local.", Some("example.com"), Some("^/a/b")),
+            Rule {
+                priority: 1,
+                ..rule("priority.local.", None, Some("^/z"))
+            },
+        ]
+        .into_iter()
+        .collect::<RoutingTable>();
+        let order = table
+            .routes
+            .iter()
+            .map(|rule| &*rule.service)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            [
+                "priority.local.",
+                "long.local.",
+                "short.local.",
+                "host.local.",
+                "any-path.local.",
+            ]
+        );
+    }
+
+    #[test]
+    fn deterministic_and_ambiguous() {
+        let rules = [
+            rule("b.local.", Some("example.com"), Some("^/app")),
+            rule("a.local.", Some("example.com"), Some("^/api")),
+            rule("c.local.", Some("other.example.com"), Some("^/app")),
+        ];
+        let forward = rules.iter().cloned().collect::<RoutingTable>();
+        let reverse = rules.iter().rev().cloned().collect::<RoutingTable>();
+        let services = |table: &RoutingTable| {
+            table
+                .routes
+                .iter()
+                .map(|rule| rule.service.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(services(&forward), services(&reverse));
+
+        // `^/app` and `^/api` don't share a prefix, and `c` is on another
+        // host.
+        assert_eq!(forward.ambiguous().count(), 0);
+
+        let table = [
+            rule("a.local.", Some("example.com"), Some("^/app/.*")),
+            rule("b.local.", Some("EXAMPLE.com"), Some("^/app/.*")),
+            rule("b.local.", Some("example.com"), Some("^/app/.*")),
+        ]
+        .into_iter()
+        .collect::<RoutingTable>();
+        let ambiguous = table
+            .ambiguous()
+            .map(|(a, b)| (&*a.service, &*b.service))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ambiguous,
+            [("a.local.", "b.local."), ("a.local.", "b.local.")]
+        );
+    }
+
     impl Recognize {
         #[track_caller]
         fn assert_match(&self, req: &http::Request<()>) {
-            assert!(dbg!(self.matches(dbg!(req))), "{req:?} should match");
+            assert!(dbg!(self.matches(dbg!(req), None)), "{req:?} should match");
         }
 
         #[track_caller]
         fn assert_not_match(&self, req: &http::Request<()>) {
-            assert!(dbg!(!self.matches(dbg!(req))), "{req:?} should not match");
+            assert!(
+                dbg!(!self.matches(dbg!(req), None)),
+                "{req:?} should not match"
+            );
         }
     }
 }
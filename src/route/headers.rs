@@ -0,0 +1,349 @@
+use crate::serve::ClientAddr;
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use std::{collections::HashMap, fmt, net::SocketAddr, str::FromStr};
+
+/// Changes to the headers of a route's requests and responses.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct HeaderRules {
+    #[serde(default)]
+    pub request: HeaderActions,
+
+    #[serde(default)]
+    pub response: HeaderActions,
+}
+
+/// Changes to a set of headers. They are applied in the order the fields
+/// are declared: headers are removed, then renamed, then set, then added.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct HeaderActions {
+    /// Headers to remove.
+    #[serde_as(as = "Vec<serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    pub remove: Vec<HeaderName>,
+
+    /// Headers to rename, keeping their values.
+    #[serde_as(as = "HashMap<serde_with::DisplayFromStr, serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    pub rename: HashMap<HeaderName, HeaderName>,
+
+    /// Headers to set, replacing any existing values.
+    #[serde_as(as = "HashMap<serde_with::DisplayFromStr, serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    pub set: HashMap<HeaderName, Template>,
+
+    /// Headers to add, keeping any existing values.
+    #[serde_as(as = "HashMap<serde_with::DisplayFromStr, serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    pub add: HashMap<HeaderName, Template>,
+}
+
+/// A header value that may refer to attributes of the request, such as
+/// `{client_ip}`. Literal braces are written as `{{` and `}}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Var(Var),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Var {
+    ClientAddr,
+    ClientIp,
+    Host,
+    Route,
+    UpstreamAddr,
+}
+
+/// The attributes of a request that a [`Template`] may refer to.
+#[derive(Debug, Clone, Copy)]
+pub struct TemplateContext<'a> {
+    /// The client that sent the request, whose port may be unknown.
+    pub client_addr: ClientAddr,
+
+    /// The host that the request was routed by.
+    pub host: Option<&'a str>,
+
+    /// The name of the service that the request was routed to.
+    pub route: &'a str,
+
+    /// The address of the discovered endpoint that the request is sent to.
+    pub upstream_addr: SocketAddr,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidTemplate {
+    #[error("unknown variable `{{{0}}}`; expected one of {}", Var::NAMES.join(", "))]
+    UnknownVar(String),
+
+    #[error("unmatched `{0}`; write `{0}{0}` for a literal brace")]
+    Unmatched(char),
+}
+
+// === impl HeaderRules ===
+
+impl HeaderRules {
+    pub fn is_empty(&self) -> bool {
+        self.request.is_empty() && self.response.is_empty()
+    }
+}
+
+// === impl HeaderActions ===
+
+impl HeaderActions {
+    pub fn is_empty(&self) -> bool {
+        self.remove.is_empty()
+            && self.rename.is_empty()
+            && self.set.is_empty()
+            && self.add.is_empty()
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap, cx: &TemplateContext<'_>) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+
+        for (from, to) in &self.rename {
+            if let http::header::Entry::Occupied(entry) = headers.entry(from) {
+                let (_, values) = entry.remove_entry_mult();
+                let values = values.collect::<Vec<_>>();
+                headers.remove(to);
+                for value in values {
+                    headers.append(to, value);
+                }
+            }
+        }
+
+        for (name, template) in &self.set {
+            match template.render(cx) {
+                Some(value) => {
+                    headers.insert(name, value);
+                }
+                None => {
+                    headers.remove(name);
+                }
+            }
+        }
+
+        for (name, template) in &self.add {
+            if let Some(value) = template.render(cx) {
+                headers.append(name, value);
+            }
+        }
+    }
+}
+
+// === impl Template ===
+
+impl Template {
+    /// Renders this template, or returns `None` if it refers to an attribute
+    /// that the request doesn't have, or if the result isn't a valid header
+    /// value.
+    pub fn render(&self, cx: &TemplateContext<'_>) -> Option<HeaderValue> {
+        let mut value = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => value.push_str(literal),
+                Part::Var(Var::ClientAddr) => value.push_str(&cx.client_addr.to_string()),
+                Part::Var(Var::ClientIp) => value.push_str(&cx.client_addr.ip.to_string()),
+                Part::Var(Var::Host) => value.push_str(cx.host?),
+                Part::Var(Var::Route) => value.push_str(cx.route.trim_end_matches('.')),
+                Part::Var(Var::UpstreamAddr) => value.push_str(&cx.upstream_addr.to_string()),
+            }
+        }
+        match HeaderValue::from_str(&value) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                tracing::debug!(%value, template = %self, "Rendered header value is invalid");
+                None
+            }
+        }
+    }
+}
+
+impl FromStr for Template {
+    type Err = InvalidTemplate;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(InvalidTemplate::Unmatched('{')),
+                        }
+                    }
+                    let var = name.parse()?;
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Var(var));
+                }
+                '}' => return Err(InvalidTemplate::Unmatched('}')),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => {
+                    f.write_str(&literal.replace('{', "{{").replace('}', "}}"))?
+                }
+                Part::Var(var) => write!(f, "{{{}}}", var.name())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// === impl Var ===
+
+impl Var {
+    const ALL: [Self; 5] = [
+        Self::ClientAddr,
+        Self::ClientIp,
+        Self::Host,
+        Self::Route,
+        Self::UpstreamAddr,
+    ];
+    const NAMES: [&'static str; 5] = ["client_addr", "client_ip", "host", "route", "upstream_addr"];
+
+    fn name(self) -> &'static str {
+        Self::NAMES[Self::ALL.iter().position(|&var| var == self).unwrap()]
+    }
+}
+
+impl FromStr for Var {
+    type Err = InvalidTemplate;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::NAMES
+            .iter()
+            .position(|&name| name == s.trim())
+            .map(|i| Self::ALL[i])
+            .ok_or_else(|| InvalidTemplate::UnknownVar(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cx() -> TemplateContext<'static> {
+        TemplateContext {
+            client_addr: SocketAddr::from(([192, 168, 1, 20], 51234)).into(),
+            host: Some("eclss.example.com"),
+            route: "eclss.local.",
+            upstream_addr: ([192, 168, 1, 10], 80).into(),
+        }
+    }
+
+    #[test]
+    fn templates() {
+        let render = |template: &str| {
+            template
+                .parse::<Template>()
+                .unwrap()
+                .render(&cx())
+                .map(|v| v.to_str().unwrap().to_string())
+        };
+        assert_eq!(render("static").as_deref(), Some("static"));
+        assert_eq!(
+            render("for={client_ip} via {route} to {upstream_addr}").as_deref(),
+            Some("for=192.168.1.20 via eclss.local to 192.168.1.10:80")
+        );
+        assert_eq!(
+            render("{{host}}={host}").as_deref(),
+            Some("{host}=eclss.example.com")
+        );
+        assert_eq!(
+            render("{client_addr}").as_deref(),
+            Some("192.168.1.20:51234")
+        );
+        let portless = TemplateContext {
+            client_addr: ClientAddr {
+                ip: [192, 168, 1, 20].into(),
+                port: None,
+            },
+            ..cx()
+        };
+        assert_eq!(
+            "{client_addr}"
+                .parse::<Template>()
+                .unwrap()
+                .render(&portless)
+                .unwrap(),
+            "192.168.1.20",
+            "unknown ports are omitted"
+        );
+
+        assert!("{nope}".parse::<Template>().is_err());
+        assert!("{host".parse::<Template>().is_err());
+        assert!("host}".parse::<Template>().is_err());
+
+        let template = "{{literal}} {host}".parse::<Template>().unwrap();
+        assert_eq!(template.to_string(), "{{literal}} {host}");
+    }
+
+    #[test]
+    fn actions() {
+        let actions: HeaderActions = toml::from_str(
+            r#"
+            remove = ["server", "x-powered-by"]
+            rename = { "x-old" = "x-new" }
+            set = { "x-client" = "{client_ip}" }
+            add = { "via" = "multipass" }
+            "#,
+        )
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("server", HeaderValue::from_static("nginx"));
+        headers.insert("x-powered-by", HeaderValue::from_static("PHP"));
+        headers.append("x-old", HeaderValue::from_static("a"));
+        headers.append("x-old", HeaderValue::from_static("b"));
+        headers.insert("x-client", HeaderValue::from_static("spoofed"));
+        headers.insert("via", HeaderValue::from_static("1.1 proxy"));
+        actions.apply(&mut headers, &cx());
+
+        assert!(!headers.contains_key("server"));
+        assert!(!headers.contains_key("x-powered-by"));
+        assert!(!headers.contains_key("x-old"));
+        assert_eq!(
+            headers.get_all("x-new").iter().collect::<Vec<_>>(),
+            ["a", "b"]
+        );
+        assert_eq!(headers["x-client"], "192.168.1.20");
+        assert_eq!(
+            headers.get_all("via").iter().collect::<Vec<_>>(),
+            ["1.1 proxy", "multipass"]
+        );
+    }
+}
@@ -4,9 +4,17 @@ use hyper::{
     body::{Body, Incoming},
     Request, Response,
 };
+use crate::proxy_protocol;
 use hyper_util::{rt::tokio_executor::TokioExecutor, server::conn::auto};
+use ipnet::IpNet;
 use linkerd_stack as svc;
-use std::{future::Future, net::SocketAddr};
+use std::{
+    fmt,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
     io,
     net::{TcpListener, TcpStream},
@@ -16,26 +24,64 @@ use tracing::Instrument;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Accepted {
-    pub client_addr: SocketAddr,
+    pub client_addr: ClientAddr,
+
+    /// The address of the connection's peer, which may be a proxy that sent
+    /// the request on the client's behalf.
+    pub peer_addr: SocketAddr,
+
     pub listen_addr: SocketAddr,
+
+    /// The address that the client connected to. Unlike `listen_addr`, this is
+    /// never an unspecified address.
+    pub local_addr: SocketAddr,
+}
+
+/// The address of a client, as far as it is known.
+///
+/// The port is unknown for clients that were named by a forwarding header
+/// without one, such as most `X-Forwarded-For` values.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ClientAddr {
+    pub ip: IpAddr,
+    pub port: Option<u16>,
 }
 
 pub async fn bind(
     addr: SocketAddr,
-) -> anyhow::Result<impl Stream<Item = io::Result<(TcpStream, SocketAddr)>>> {
+) -> anyhow::Result<impl Stream<Item = io::Result<(TcpStream, SocketAddr, SocketAddr)>>> {
     let listener = TcpListener::bind(addr)
         .await
         .with_context(|| format!("failed to bind {addr}"))?;
-    Ok(TcpListenerStream::new(listener).map(|res| {
+    Ok(accept(listener))
+}
+
+/// Returns a stream of connections accepted on an already-bound `listener`,
+/// with their peer and local addresses.
+pub fn accept(
+    listener: TcpListener,
+) -> impl Stream<Item = io::Result<(TcpStream, SocketAddr, SocketAddr)>> {
+    TcpListenerStream::new(listener).map(|res| {
         let sock = res?;
         let addr = sock.peer_addr()?;
-        Ok((sock, addr))
-    }))
+        let local_addr = sock.local_addr()?;
+        Ok((sock, addr, local_addr))
+    })
 }
 
+/// How long a client that must send a PROXY protocol header has to send it.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves HTTP on connections from `listen`, which yields each connection
+/// with its peer and local addresses.
+///
+/// Connections from addresses in `accept_proxy_from` must start with a PROXY
+/// protocol header, and are served as though they came from the client that
+/// it names.
 pub async fn serve<I, S, B>(
     listen_addr: SocketAddr,
-    listen: impl Stream<Item = io::Result<(I, SocketAddr)>>,
+    listen: impl Stream<Item = io::Result<(I, SocketAddr, SocketAddr)>>,
+    accept_proxy_from: Arc<[IpNet]>,
     shutdown: impl Future + Send,
     new_svc: impl svc::NewService<Accepted, Service = S> + Clone + Send + 'static,
 ) where
@@ -52,7 +98,7 @@ pub async fn serve<I, S, B>(
             let listen = listen;
         }
         loop {
-            let (conn, addr) = match listen.next().await {
+            let (conn, addr, local_addr) = match listen.next().await {
                 Some(Ok(conn)) => conn,
                 Some(Err(error)) => {
                     tracing::error!(%error, "failed to accept connection!");
@@ -66,13 +112,46 @@ pub async fn serve<I, S, B>(
 
             let span = tracing::debug_span!("conn", client.addr = %addr).entered();
             tracing::debug!("accepted connection");
-            let svc = NewHyperService {
-                new_svc: new_svc.clone(),
-                client_addr: addr,
-                listen_addr
-            };
+            let new_svc = new_svc.clone();
+            let expects_proxy_header = accept_proxy_from
+                .iter()
+                .any(|net| net.contains(&addr.ip()));
             tokio::spawn(
                 async move {
+                    let mut conn = conn;
+                    let client_addr = if expects_proxy_header {
+                        let header = tokio::time::timeout(
+                            PROXY_HEADER_TIMEOUT,
+                            proxy_protocol::read(&mut conn),
+                        );
+                        match header.await {
+                            Ok(Ok(Some(addrs))) => {
+                                tracing::debug!(
+                                    client.addr = %addrs.source,
+                                    "read PROXY protocol header"
+                                );
+                                addrs.source
+                            }
+                            Ok(Ok(None)) => addr,
+                            Ok(Err(error)) => {
+                                tracing::warn!(%error, "closing connection");
+                                return Ok(());
+                            }
+                            Err(_) => {
+                                tracing::warn!("timed out waiting for PROXY protocol header");
+                                return Ok(());
+                            }
+                        }
+                    } else {
+                        addr
+                    };
+                    let svc = NewHyperService {
+                        new_svc,
+                        client_addr,
+                        peer_addr: addr,
+                        listen_addr,
+                        local_addr,
+                    };
                     auto::Builder::new(TokioExecutor::new())
                         .http1()
                         .keep_alive(true)
@@ -96,7 +175,9 @@ pub async fn serve<I, S, B>(
 struct NewHyperService<N> {
     new_svc: N,
     client_addr: SocketAddr,
+    peer_addr: SocketAddr,
     listen_addr: SocketAddr,
+    local_addr: SocketAddr,
 }
 
 impl<N, S, B, R> hyper::service::Service<R> for NewHyperService<N>
@@ -117,9 +198,31 @@ where
         use svc::ServiceExt;
         self.new_svc
             .new_service(Accepted {
-                client_addr: self.client_addr,
+                client_addr: self.client_addr.into(),
+                peer_addr: self.peer_addr,
                 listen_addr: self.listen_addr,
+                local_addr: self.local_addr,
             })
             .oneshot(req)
     }
 }
+
+// === impl ClientAddr ===
+
+impl From<SocketAddr> for ClientAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self {
+            ip: addr.ip(),
+            port: Some(addr.port()),
+        }
+    }
+}
+
+impl fmt::Display for ClientAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) => SocketAddr::new(self.ip, port).fmt(f),
+            None => self.ip.fmt(f),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use hyper::StatusCode;
+
+mod support;
+
+const CONFIG: &str = r#"
+domain = "example.com"
+
+[services]
+eclss = {}
+grafana = { host = "metrics.example.com" }
+"#;
+
+#[tokio::test]
+async fn routes_to_resolved_service() {
+    support::trace_init();
+    let gateway = support::gateway(CONFIG).await;
+    let eclss = support::backend("eclss").await;
+    gateway.discover.resolve("eclss.local.", eclss).unwrap();
+
+    let rsp = gateway.get("eclss.example.com", "/index.html").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert_eq!(rsp.body, "eclss /index.html");
+}
+
+#[tokio::test]
+async fn routes_by_configured_host() {
+    support::trace_init();
+    let gateway = support::gateway(CONFIG).await;
+    let eclss = support::backend("eclss").await;
+    let grafana = support::backend("grafana").await;
+    gateway.discover.resolve("eclss.local.", eclss).unwrap();
+    gateway.discover.resolve("grafana.local.", grafana).unwrap();
+
+    let rsp = gateway.get("metrics.example.com", "/").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert_eq!(rsp.body, "grafana /");
+
+    let rsp = gateway.get("eclss.example.com", "/").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert_eq!(rsp.body, "eclss /");
+}
+
+#[tokio::test]
+async fn unresolved_service_is_not_found() {
+    support::trace_init();
+    let gateway = support::gateway(CONFIG).await;
+
+    let rsp = gateway.get("eclss.example.com", "/").await;
+    assert_eq!(rsp.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn unknown_host_is_not_found() {
+    support::trace_init();
+    let gateway = support::gateway(CONFIG).await;
+
+    let rsp = gateway.get("nope.example.com", "/").await;
+    assert_eq!(rsp.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn follows_updates_and_removals() {
+    support::trace_init();
+    let gateway = support::gateway(CONFIG).await;
+    let old = support::backend("old").await;
+    let new = support::backend("new").await;
+
+    gateway.discover.resolve("eclss.local.", old).unwrap();
+    let rsp = gateway.get("eclss.example.com", "/").await;
+    assert_eq!(rsp.body, "old /");
+
+    gateway.discover.resolve("eclss.local.", new).unwrap();
+    support::eventually(|| async { gateway.get("eclss.example.com", "/").await.body == "new /" })
+        .await;
+
+    gateway.discover.remove("eclss.local.").unwrap();
+    support::eventually(|| async {
+        gateway.get("eclss.example.com", "/").await.status == StatusCode::NOT_FOUND
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn wildcard_hosts_route_to_matching_names() {
+    support::trace_init();
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+
+        [services."{label}"]
+        host = "*.lab.example.com"
+        "#,
+    )
+    .await;
+    let foo = support::backend("foo").await;
+    let bar = support::backend("bar").await;
+    gateway.discover.resolve("foo.local.", foo).unwrap();
+    gateway.discover.resolve("bar.local.", bar).unwrap();
+
+    let rsp = gateway.get("foo.lab.example.com", "/").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert_eq!(rsp.body, "foo /");
+
+    let rsp = gateway.get("BAR.lab.example.com.", "/").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert_eq!(rsp.body, "bar /");
+
+    let rsp = gateway.get("baz.lab.example.com", "/").await;
+    assert_eq!(rsp.status, StatusCode::NOT_FOUND);
+}
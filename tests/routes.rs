@@ -0,0 +1,909 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{Response, StatusCode};
+
+mod support;
+
+#[tokio::test]
+async fn strips_path_prefix() {
+    support::trace_init();
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        host = "example.com"
+        path_regex = "^/eclss(/|$)"
+        rewrite = { strip_prefix = "/eclss" }
+        "#,
+    )
+    .await;
+    let eclss = support::backend_fn(|req| {
+        let prefix = req
+            .headers()
+            .get("x-forwarded-prefix")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-");
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        let body = format!("{prefix} {path}");
+        Response::new(Full::new(Bytes::from(body)))
+    })
+    .await;
+    gateway.discover.resolve("eclss.local.", eclss).unwrap();
+
+    let rsp = gateway
+        .get("example.com", "/eclss/index.html?lang=en")
+        .await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert_eq!(rsp.body, "/eclss /index.html?lang=en");
+
+    let rsp = gateway
+        .send(
+            http::Request::builder()
+                .uri("/eclss/index.html")
+                .header(http::header::HOST, "example.com")
+                .header("x-forwarded-prefix", "/evil")
+                .body(http_body_util::Empty::new())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(
+        rsp.body, "/eclss /index.html",
+        "prefixes from untrusted clients are ignored"
+    );
+
+    let rsp = gateway.get("example.com", "/grafana").await;
+    assert_eq!(rsp.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn rewrites_upstream_redirects_and_cookies() {
+    support::trace_init();
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        host = "example.com"
+        path_regex = "^/eclss(/|$)"
+        rewrite = { strip_prefix = "/eclss" }
+        "#,
+    )
+    .await;
+    let eclss = support::backend_fn(|_| {
+        Response::builder()
+            .status(StatusCode::FOUND)
+            .header("location", "http://eclss.local:8080/login")
+            .header("set-cookie", "session=abc; Domain=eclss.local; Path=/")
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    })
+    .await;
+    gateway.discover.resolve("eclss.local.", eclss).unwrap();
+
+    let rsp = gateway.get("example.com", "/eclss/").await;
+    assert_eq!(rsp.status, StatusCode::FOUND);
+    assert_eq!(rsp.headers["location"], "http://example.com/eclss/login");
+    assert_eq!(
+        rsp.headers["set-cookie"],
+        "session=abc; Domain=example.com; Path=/eclss"
+    );
+}
+
+#[tokio::test]
+async fn splits_between_backends() {
+    support::trace_init();
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        [services."eclss-v2"]
+
+        [[routes]]
+        host = "sensors.example.com"
+        backends = [
+            { service = "eclss", weight = 0 },
+            { service = "eclss-v2" },
+        ]
+        sticky = { cookie = "eclss-backend" }
+        "#,
+    )
+    .await;
+    let eclss = support::backend("eclss").await;
+    gateway.discover.resolve("eclss.local.", eclss).unwrap();
+    let eclss_v2 = support::backend("eclss-v2").await;
+    gateway
+        .discover
+        .resolve("eclss-v2.local.", eclss_v2)
+        .unwrap();
+
+    let rsp = gateway.get("sensors.example.com", "/").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert_eq!(rsp.body, "eclss-v2 /");
+    assert_eq!(
+        rsp.headers["set-cookie"],
+        "eclss-backend=eclss-v2.local.; Path=/; HttpOnly"
+    );
+}
+
+#[tokio::test]
+async fn splits_use_each_backends_settings() {
+    support::trace_init();
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        [services."eclss-v2"]
+        forwarding = { headers = ["x-real-ip"] }
+
+        [[routes]]
+        host = "sensors.example.com"
+        backends = [{ service = "eclss" }, { service = "eclss-v2" }]
+        sticky = { cookie = "eclss-backend" }
+        "#,
+    )
+    .await;
+    let echo = || {
+        support::backend_fn(|req| {
+            let header = |name: &str| req.headers().contains_key(name);
+            let body = format!(
+                "forwarded={} x-real-ip={}",
+                header("forwarded"),
+                header("x-real-ip")
+            );
+            Response::new(Full::new(Bytes::from(body)))
+        })
+    };
+    let eclss = echo().await;
+    gateway.discover.resolve("eclss.local.", eclss).unwrap();
+    let eclss_v2 = echo().await;
+    gateway
+        .discover
+        .resolve("eclss-v2.local.", eclss_v2)
+        .unwrap();
+
+    let send = |backend: &str| {
+        gateway.send(
+            http::Request::builder()
+                .uri("/")
+                .header(http::header::HOST, "sensors.example.com")
+                .header(http::header::COOKIE, format!("eclss-backend={backend}"))
+                .body(http_body_util::Empty::new())
+                .unwrap(),
+        )
+    };
+    let rsp = send("eclss.local.").await;
+    assert_eq!(rsp.body, "forwarded=true x-real-ip=false");
+    let rsp = send("eclss-v2.local.").await;
+    assert_eq!(rsp.body, "forwarded=false x-real-ip=true");
+}
+
+#[tokio::test]
+async fn mirrors_requests() {
+    support::trace_init();
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        [services."eclss-v2"]
+        forwarding = { headers = ["x-real-ip"] }
+
+        [[routes]]
+        service = "eclss"
+        host = "sensors.example.com"
+        mirror = { service = "eclss-v2" }
+        "#,
+    )
+    .await;
+    let eclss = support::backend("eclss").await;
+    gateway.discover.resolve("eclss.local.", eclss).unwrap();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let eclss_v2 = support::backend_fn(move |req| {
+        let forwarding = ["forwarded", "x-real-ip"]
+            .into_iter()
+            .filter(|name| req.headers().contains_key(*name))
+            .collect::<Vec<_>>()
+            .join(",");
+        let _ = tx.send(format!("{} {forwarding}", req.uri().path()));
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Full::new(Bytes::from("eclss-v2")))
+            .unwrap()
+    })
+    .await;
+    gateway
+        .discover
+        .resolve("eclss-v2.local.", eclss_v2)
+        .unwrap();
+
+    let rsp = gateway.get("sensors.example.com", "/metrics").await;
+    assert_eq!(rsp.status, StatusCode::OK, "mirror responses are discarded");
+    assert_eq!(rsp.body, "eclss /metrics");
+
+    let path = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv())
+        .await
+        .expect("request must be mirrored")
+        .unwrap();
+    assert_eq!(
+        path, "/metrics x-real-ip",
+        "mirrors get their own service's forwarding headers"
+    );
+}
+
+#[tokio::test]
+async fn redirects_and_fixed_responses() {
+    support::trace_init();
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+
+        [services."eclss"]
+
+        [[routes]]
+        host = "example.com"
+        path_regex = "^/old/(.*)$"
+        redirect = { location = "https://www.example.com/new/$1", status = 308 }
+
+        [[routes]]
+        host = "example.com"
+        path_regex = "^/robots.txt$"
+        respond = { body = "User-agent: *\nDisallow: /\n" }
+
+        [[routes]]
+        host = "example.com"
+        path_regex = "^/gone$"
+        respond = { status = 410, headers = { "cache-control" = "max-age=3600" } }
+        "#,
+    )
+    .await;
+
+    let rsp = gateway.get("example.com", "/old/sensors?unit=c").await;
+    assert_eq!(rsp.status, StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(
+        rsp.headers["location"],
+        "https://www.example.com/new/sensors?unit=c"
+    );
+    assert!(rsp.headers["server"]
+        .to_str()
+        .unwrap()
+        .starts_with("multipass/"));
+
+    let rsp = gateway.get("example.com", "/robots.txt").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert_eq!(rsp.headers["content-type"], "text/plain; charset=utf-8");
+    assert_eq!(rsp.body, "User-agent: *\nDisallow: /\n");
+
+    let rsp = gateway.get("example.com", "/gone").await;
+    assert_eq!(rsp.status, StatusCode::GONE);
+    assert_eq!(rsp.headers["cache-control"], "max-age=3600");
+    assert_eq!(rsp.headers["content-type"], "text/html");
+    assert!(rsp.body.contains("410 Gone"), "{}", rsp.body);
+}
+
+#[tokio::test]
+async fn serves_files() {
+    support::trace_init();
+    let root = std::env::temp_dir().join(format!("multipass-files-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("docs")).unwrap();
+    std::fs::write(root.join("docs/index.html"), "<h1>docs</h1>").unwrap();
+    std::fs::write(root.join("app.css"), "body { margin: 0; }").unwrap();
+    std::fs::write(root.join("app.css.gz"), "not really gzip").unwrap();
+
+    let gateway = support::gateway(&format!(
+        r#"
+        domain = "example.com"
+
+        [services."eclss"]
+
+        [[routes]]
+        host = "example.com"
+        path_regex = "^/static(/|$)"
+        rewrite = {{ strip_prefix = "/static" }}
+        files = {{ root = "{}" }}
+        "#,
+        root.display(),
+    ))
+    .await;
+    let get = |path: &str| {
+        http::Request::builder()
+            .uri(path)
+            .header(http::header::HOST, "example.com")
+    };
+    let empty = http_body_util::Empty::<Bytes>::new;
+
+    let rsp = gateway.get("example.com", "/static/docs").await;
+    assert_eq!(rsp.status, StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(rsp.headers["location"], "/static/docs/");
+
+    let rsp = gateway.get("example.com", "/static/docs/").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert_eq!(rsp.headers["content-type"], "text/html");
+    assert_eq!(rsp.body, "<h1>docs</h1>");
+
+    let rsp = gateway.get("example.com", "/static/app.css").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert_eq!(rsp.headers["content-type"], "text/css");
+    assert_eq!(rsp.body, "body { margin: 0; }");
+    let etag = rsp.headers["etag"].clone();
+
+    let rsp = gateway
+        .send(
+            get("/static/app.css")
+                .header("if-none-match", etag.clone())
+                .body(empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status, StatusCode::NOT_MODIFIED);
+    assert_eq!(rsp.body, "");
+
+    let rsp = gateway
+        .send(
+            get("/static/app.css")
+                .header("range", "bytes=0-6")
+                .body(empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(rsp.headers["content-range"], "bytes 0-6/19");
+    assert_eq!(rsp.body, "body { ");
+
+    let rsp = gateway
+        .send(
+            get("/static/app.css")
+                .header("accept-encoding", "gzip")
+                .body(empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert_eq!(rsp.headers["content-encoding"], "gzip");
+    assert_eq!(rsp.headers["content-type"], "text/css");
+    assert_ne!(rsp.headers["etag"], etag);
+    assert_eq!(rsp.body, "not really gzip");
+
+    let rsp = gateway.get("example.com", "/static/%2e%2e/secret").await;
+    assert_eq!(rsp.status, StatusCode::BAD_REQUEST);
+
+    let rsp = gateway.get("example.com", "/static/missing.txt").await;
+    assert_eq!(rsp.status, StatusCode::NOT_FOUND);
+
+    #[cfg(unix)]
+    {
+        let secret = root.with_extension("secret");
+        std::fs::write(&secret, "hunter2").unwrap();
+        std::os::unix::fs::symlink(&secret, root.join("secret.txt")).unwrap();
+        let rsp = gateway.get("example.com", "/static/secret.txt").await;
+        assert_eq!(
+            rsp.status,
+            StatusCode::NOT_FOUND,
+            "symlinks out of the root are not followed"
+        );
+        std::fs::remove_file(secret).unwrap();
+    }
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[tokio::test]
+async fn falls_back_to_service_index() {
+    support::trace_init();
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+        fallback = "index"
+
+        [services."eclss"]
+        [services."grafana"]
+        [services."printer"]
+        listed = false
+        "#,
+    )
+    .await;
+    let eclss = support::backend("eclss").await;
+    gateway.discover.resolve("eclss.local.", eclss).unwrap();
+
+    let rsp = gateway.get("eclss.example.com", "/").await;
+    assert_eq!(rsp.body, "eclss /");
+
+    let rsp = gateway.get("unknown.example.com", "/").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert_eq!(rsp.headers["content-type"], "text/html");
+    assert!(rsp.body.contains(
+        "<a href=\"http://eclss.example.com/\">eclss</a> <span class=\"resolved\">resolved</span>"
+    ));
+    assert!(rsp.body.contains(
+        "<a href=\"http://grafana.example.com/\">grafana</a> <span class=\"unresolved\">"
+    ));
+    assert!(!rsp.body.contains("printer"), "printer is not listed");
+
+    let rsp = gateway
+        .send(
+            http::Request::builder()
+                .uri("/")
+                .header(http::header::HOST, "unknown.example.com")
+                .header(http::header::ACCEPT, "application/json")
+                .body(http_body_util::Empty::<Bytes>::new())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.headers["content-type"], "application/json");
+    assert_eq!(
+        rsp.body,
+        r#"{"services":[{"name":"eclss","resolved":true,"stale":false,"url":"http://eclss.example.com/"},{"name":"grafana","resolved":false,"stale":false,"url":"http://grafana.example.com/"}]}"#
+    );
+}
+
+#[tokio::test]
+async fn applies_header_rules() {
+    support::trace_init();
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        headers.request.set = { "x-client-ip" = "{client_ip}", "x-route" = "{route} via {host}" }
+        headers.request.remove = ["cookie"]
+        headers.response.remove = ["server", "x-powered-by"]
+        headers.response.rename = { "x-old" = "x-new" }
+        headers.response.add = { "x-upstream" = "{upstream_addr}" }
+        "#,
+    )
+    .await;
+    let eclss = support::backend_fn(|req| {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .map(|v| v.to_str().unwrap().to_string())
+                .unwrap_or_default()
+        };
+        let body = format!(
+            "{} {} cookie={}",
+            header("x-client-ip"),
+            header("x-route"),
+            header("cookie")
+        );
+        Response::builder()
+            .header("server", "Apache/2.4.1")
+            .header("x-powered-by", "PHP/5.6")
+            .header("x-old", "kept")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()
+    })
+    .await;
+    gateway.discover.resolve("eclss.local.", eclss).unwrap();
+
+    let rsp = gateway
+        .send(
+            http::Request::builder()
+                .uri("/")
+                .header(http::header::HOST, "eclss.example.com")
+                .header(http::header::COOKIE, "session=1")
+                .body(http_body_util::Empty::new())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert_eq!(rsp.body, "127.0.0.1 eclss via eclss.example.com cookie=");
+    assert!(!rsp.headers.contains_key("server"));
+    assert!(!rsp.headers.contains_key("x-powered-by"));
+    assert!(!rsp.headers.contains_key("x-old"));
+    assert_eq!(rsp.headers["x-new"], "kept");
+    assert_eq!(rsp.headers["x-upstream"], eclss.to_string());
+}
+
+#[tokio::test]
+async fn upstream_addr_is_the_replica_used() {
+    support::trace_init();
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        headers.response.add = { "x-upstream" = "{upstream_addr}" }
+        "#,
+    )
+    .await;
+    let primary = support::backend("primary").await;
+    let replica = support::backend("replica").await;
+    let mut discovered =
+        multipass::discover::Discovered::new(primary, "eclss.local.".parse().unwrap());
+    discovered.replicas = vec![replica];
+    gateway
+        .discover
+        .set("eclss.local.", Some(discovered))
+        .unwrap();
+
+    let mut answered = Vec::new();
+    for _ in 0..4 {
+        let rsp = gateway.get("eclss.example.com", "/").await;
+        assert_eq!(rsp.status, StatusCode::OK);
+        let expected = match rsp.body.as_str() {
+            "primary /" => primary,
+            "replica /" => replica,
+            body => panic!("unexpected body {body:?}"),
+        };
+        assert_eq!(rsp.headers["x-upstream"], expected.to_string());
+        answered.push(expected);
+    }
+    assert!(answered.contains(&primary) && answered.contains(&replica));
+}
+
+#[tokio::test]
+async fn sets_forwarding_headers() {
+    support::trace_init();
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+
+        [services."eclss"]
+
+        [services."grafana"]
+        forwarding = { headers = ["x-forwarded", "x-real-ip"], mode = "replace" }
+        "#,
+    )
+    .await;
+    let echo = || {
+        support::backend_fn(|req| {
+            let headers = [
+                "forwarded",
+                "x-forwarded-for",
+                "x-forwarded-proto",
+                "x-forwarded-host",
+                "x-forwarded-port",
+                "x-real-ip",
+            ]
+            .into_iter()
+            .filter_map(|name| {
+                let value = req.headers().get(name)?.to_str().unwrap();
+                Some(format!("{name}: {value}\n"))
+            })
+            .collect::<String>();
+            Response::new(Full::new(Bytes::from(headers)))
+        })
+    };
+    let eclss = echo().await;
+    gateway.discover.resolve("eclss.local.", eclss).unwrap();
+    let grafana = echo().await;
+    gateway.discover.resolve("grafana.local.", grafana).unwrap();
+
+    let send = |host: &str| {
+        gateway.send(
+            http::Request::builder()
+                .uri("/")
+                .header(http::header::HOST, host)
+                .header("x-forwarded-for", "203.0.113.1")
+                .body(http_body_util::Empty::new())
+                .unwrap(),
+        )
+    };
+
+    let rsp = send("eclss.example.com").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    let forwarded = rsp
+        .body
+        .lines()
+        .find_map(|line| line.strip_prefix("forwarded: "))
+        .expect("default style is Forwarded");
+    assert!(forwarded.starts_with("for=127.0.0.1;by=\"127.0.0.1:"));
+    assert!(forwarded.ends_with(";host=eclss.example.com;proto=http"));
+    assert!(
+        !rsp.body.contains("x-forwarded-for"),
+        "forwarding headers from untrusted clients are removed"
+    );
+
+    let rsp = send("grafana.example.com:8080").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert_eq!(
+        rsp.body,
+        format!(
+            "x-forwarded-for: 127.0.0.1\n\
+            x-forwarded-proto: http\n\
+            x-forwarded-host: grafana.example.com:8080\n\
+            x-forwarded-port: {}\n\
+            x-real-ip: 127.0.0.1\n",
+            gateway.addr.port(),
+        ),
+        "the port is the one the request was received on, not the Host header's"
+    );
+}
+
+#[tokio::test]
+async fn trusts_forwarding_headers_from_trusted_proxies() {
+    support::trace_init();
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+        trusted_proxies = ["127.0.0.0/8"]
+
+        [services."eclss"]
+        headers.request.set = { "x-client" = "{client_ip}" }
+
+        [services."lan"]
+        host = "lan.example.com"
+        source_cidrs = ["192.168.1.0/24"]
+        "#,
+    )
+    .await;
+    let echo = || {
+        support::backend_fn(|req| {
+            let header = |name: &str| {
+                req.headers()
+                    .get(name)
+                    .map(|v| v.to_str().unwrap().to_string())
+                    .unwrap_or_default()
+            };
+            let body = format!(
+                "client={} forwarded={}",
+                header("x-client"),
+                header("forwarded")
+            );
+            Response::new(Full::new(Bytes::from(body)))
+        })
+    };
+    let eclss = echo().await;
+    gateway.discover.resolve("eclss.local.", eclss).unwrap();
+    let lan = echo().await;
+    gateway.discover.resolve("lan.local.", lan).unwrap();
+
+    let send = |host: &str, forwarded_for: &str| {
+        gateway.send(
+            http::Request::builder()
+                .uri("/")
+                .header(http::header::HOST, host)
+                .header("x-forwarded-for", forwarded_for)
+                .body(http_body_util::Empty::new())
+                .unwrap(),
+        )
+    };
+
+    let rsp = send("eclss.example.com", "203.0.113.1, 192.168.1.20").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert!(
+        rsp.body
+            .starts_with("client=192.168.1.20 forwarded=for=192.168.1.20;"),
+        "{}",
+        rsp.body
+    );
+
+    let rsp = send("lan.example.com", "192.168.1.20").await;
+    assert_eq!(
+        rsp.status,
+        StatusCode::OK,
+        "source_cidrs match the real client"
+    );
+    let rsp = send("lan.example.com", "203.0.113.1").await;
+    assert_ne!(rsp.status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn speaks_proxy_protocol() {
+    use multipass::proxy_protocol;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    support::trace_init();
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+
+        [listen.proxy_protocol]
+        http = ["127.0.0.0/8"]
+
+        [services."eclss"]
+        proxy_protocol = "v2"
+        "#,
+    )
+    .await;
+
+    // A backend that expects each connection to start with a PROXY header.
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let eclss = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let addrs = proxy_protocol::read(&mut conn).await.unwrap();
+            tx.send(addrs).unwrap();
+            let svc = hyper::service::service_fn(|_| async {
+                Ok::<_, std::convert::Infallible>(Response::new(Full::new(Bytes::from("eclss"))))
+            });
+            tokio::spawn(hyper::server::conn::http1::Builder::new().serve_connection(conn, svc));
+        }
+    });
+    gateway.discover.resolve("eclss.local.", eclss).unwrap();
+
+    // The gateway sits behind a load balancer that sends PROXY headers.
+    let mut conn = tokio::net::TcpStream::connect(gateway.addr).await.unwrap();
+    conn.write_all(
+        b"PROXY TCP4 198.51.100.7 192.0.2.1 4711 80\r\n\
+        GET / HTTP/1.1\r\n\
+        Host: eclss.example.com\r\n\
+        Connection: close\r\n\r\n",
+    )
+    .await
+    .unwrap();
+    let mut rsp = String::new();
+    conn.read_to_string(&mut rsp).await.unwrap();
+    assert!(rsp.starts_with("HTTP/1.1 200"), "{rsp}");
+    assert!(rsp.ends_with("eclss"), "{rsp}");
+
+    let addrs = rx
+        .recv()
+        .await
+        .unwrap()
+        .expect("backend must be told the client");
+    assert_eq!(addrs.source, "198.51.100.7:4711".parse().unwrap());
+    assert_eq!(addrs.destination, gateway.addr);
+
+    // Connections to the backend are reused, but only for the same client.
+    let addr = gateway.addr;
+    let send_as = |client: &'static str| async move {
+        use http_body_util::BodyExt;
+
+        let mut io = tokio::net::TcpStream::connect(addr).await.unwrap();
+        io.write_all(format!("PROXY TCP4 {client} 192.0.2.1 4711 80\r\n").as_bytes())
+            .await
+            .unwrap();
+        let (mut conn, task) = hyper::client::conn::http1::handshake(io).await.unwrap();
+        tokio::spawn(task);
+        let req = http::Request::builder()
+            .uri("/")
+            .header(http::header::HOST, "eclss.example.com")
+            .body(http_body_util::Empty::<Bytes>::new())
+            .unwrap();
+        let rsp = conn.send_request(req).await.unwrap();
+        assert_eq!(rsp.status(), StatusCode::OK);
+        rsp.into_body().collect().await.unwrap();
+    };
+    send_as("198.51.100.7").await;
+    assert!(rx.recv().await.unwrap().is_some());
+    send_as("198.51.100.7").await;
+    assert!(rx.try_recv().is_err(), "the client's connection is reused");
+    send_as("203.0.113.9").await;
+    let addrs = rx.recv().await.unwrap().unwrap();
+    assert_eq!(
+        addrs.source,
+        "203.0.113.9:4711".parse().unwrap(),
+        "other clients get their own connections"
+    );
+
+    // Connections from sources that aren't configured are served as-is.
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+
+        [listen.proxy_protocol]
+        http = ["192.0.2.0/24"]
+
+        [services."eclss"]
+        "#,
+    )
+    .await;
+    let backend = support::backend("eclss").await;
+    gateway.discover.resolve("eclss.local.", backend).unwrap();
+    let rsp = gateway.get("eclss.example.com", "/").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn detects_request_loops() {
+    support::trace_init();
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+
+        [services."eclss"]
+        [services."loop"]
+        "#,
+    )
+    .await;
+    let eclss = support::backend("eclss").await;
+    gateway.discover.resolve("eclss.local.", eclss).unwrap();
+
+    let rsp = gateway.get("eclss.example.com", "/").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+    assert_eq!(
+        rsp.headers["via"],
+        format!("HTTP/1.1 example.com:{}", gateway.addr.port())
+    );
+
+    // A request that has already passed through the gateway.
+    let rsp = gateway
+        .send(
+            http::Request::builder()
+                .uri("/")
+                .header(http::header::HOST, "eclss.example.com")
+                .header(
+                    http::header::VIA,
+                    format!(
+                        "1.1 proxy.example.net, 1.1 example.com:{}",
+                        gateway.addr.port()
+                    ),
+                )
+                .body(http_body_util::Empty::<Bytes>::new())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status, StatusCode::LOOP_DETECTED);
+
+    // Other proxies are fine.
+    let rsp = gateway
+        .send(
+            http::Request::builder()
+                .uri("/")
+                .header(http::header::HOST, "eclss.example.com")
+                .header(http::header::VIA, "1.1 proxy.example.net")
+                .body(http_body_util::Empty::<Bytes>::new())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(rsp.status, StatusCode::OK);
+
+    // A service that resolves to the gateway itself.
+    gateway
+        .discover
+        .resolve("loop.local.", gateway.addr)
+        .unwrap();
+    let rsp = gateway.get("loop.example.com", "/").await;
+    assert_eq!(rsp.status, StatusCode::LOOP_DETECTED);
+}
+
+#[tokio::test]
+async fn times_out_slow_services() {
+    use tokio::io::AsyncReadExt;
+
+    support::trace_init();
+    let gateway = support::gateway(
+        r#"
+        domain = "example.com"
+
+        [timeouts]
+        response_headers = 0.2
+
+        [services."eclss"]
+        [services."hung"]
+        [services."patient"]
+        timeouts = { response_headers = 5, request = 0.4 }
+        "#,
+    )
+    .await;
+    let eclss = support::backend("eclss").await;
+    gateway.discover.resolve("eclss.local.", eclss).unwrap();
+
+    // A backend that accepts requests and never answers them.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hung = listener.local_addr().unwrap();
+    gateway.discover.resolve("hung.local.", hung).unwrap();
+    gateway.discover.resolve("patient.local.", hung).unwrap();
+
+    let rsp = gateway.get("eclss.example.com", "/").await;
+    assert_eq!(rsp.status, StatusCode::OK);
+
+    let started = tokio::time::Instant::now();
+    let req = tokio::spawn(async move {
+        let rsp = gateway.get("hung.example.com", "/").await;
+        (gateway, rsp)
+    });
+    let (mut conn, _) = listener.accept().await.unwrap();
+    let (gateway, rsp) = req.await.unwrap();
+    assert_eq!(rsp.status, StatusCode::GATEWAY_TIMEOUT);
+
+    // The upstream connection is closed once the request times out.
+    let mut buf = Vec::new();
+    conn.read_to_end(&mut buf).await.unwrap();
+    assert!(buf.starts_with(b"GET / HTTP/1.1\r\n"));
+
+    // The service's own request timeout applies before its response headers
+    // timeout.
+    let rsp = gateway.get("patient.example.com", "/").await;
+    assert_eq!(rsp.status, StatusCode::GATEWAY_TIMEOUT);
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+}
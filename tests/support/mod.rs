@@ -0,0 +1,132 @@
+//! Test support for running a gateway against fake backends.
+#![allow(dead_code)]
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::{body::Incoming, Request, Response, StatusCode};
+use multipass::{config::Config, discover::InMemoryDiscover, serve, svc, Proxy};
+use std::{convert::Infallible, future::Future, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+};
+
+pub struct Gateway {
+    pub addr: SocketAddr,
+    pub config: Arc<Config>,
+    pub discover: InMemoryDiscover,
+    _shutdown: oneshot::Sender<()>,
+}
+
+pub struct Rsp {
+    pub status: StatusCode,
+    pub headers: http::HeaderMap,
+    pub body: String,
+}
+
+pub fn trace_init() {
+    let _ = tracing_subscriber::fmt()
+        .with_test_writer()
+        .with_env_filter("multipass=trace,info")
+        .try_init();
+}
+
+/// Runs a gateway for the provided TOML config on an ephemeral port.
+pub async fn gateway(config: &str) -> Gateway {
+    let config = Config::parse(config).expect("config must parse");
+    let discover = InMemoryDiscover::from_config(&config);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let connect = svc::service_fn(|addr: SocketAddr| Box::pin(TcpStream::connect(addr)));
+    let http = Proxy::new(config.clone(), connect)
+        .push_http_endpoint()
+        .push_http_discover(&discover)
+        .push_http_server(&discover)
+        .into_inner();
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn(serve::serve(
+        addr,
+        serve::accept(listener),
+        config.listeners.proxy_protocol.http.clone().into(),
+        rx,
+        http,
+    ));
+
+    Gateway {
+        addr,
+        config,
+        discover,
+        _shutdown: tx,
+    }
+}
+
+/// Runs a fake backend which responds to every request with `{name} {path}`.
+pub async fn backend(name: &'static str) -> SocketAddr {
+    backend_fn(move |req| {
+        let body = format!("{name} {}", req.uri().path());
+        Response::new(Full::new(Bytes::from(body)))
+    })
+    .await
+}
+
+/// Runs a fake backend which responds to every request with `f`.
+pub async fn backend_fn(
+    f: impl Fn(Request<Incoming>) -> Response<Full<Bytes>> + Clone + Send + Sync + 'static,
+) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (conn, _) = listener.accept().await.unwrap();
+            let f = f.clone();
+            let svc = hyper::service::service_fn(move |req| {
+                let rsp = f(req);
+                async move { Ok::<_, Infallible>(rsp) }
+            });
+            tokio::spawn(hyper::server::conn::http1::Builder::new().serve_connection(conn, svc));
+        }
+    });
+    addr
+}
+
+impl Gateway {
+    /// Sends a `GET` request for `path` with the provided `Host` header.
+    pub async fn get(&self, host: &str, path: &str) -> Rsp {
+        self.send(
+            Request::builder()
+                .uri(path)
+                .header(http::header::HOST, host)
+                .body(Empty::<Bytes>::new())
+                .unwrap(),
+        )
+        .await
+    }
+
+    pub async fn send(&self, req: Request<Empty<Bytes>>) -> Rsp {
+        let io = TcpStream::connect(self.addr).await.unwrap();
+        let (mut client, conn) = hyper::client::conn::http1::handshake(io).await.unwrap();
+        tokio::spawn(conn);
+        let rsp = client.send_request(req).await.unwrap();
+        let status = rsp.status();
+        let headers = rsp.headers().clone();
+        let body = rsp.into_body().collect().await.unwrap().to_bytes();
+        Rsp {
+            status,
+            headers,
+            body: String::from_utf8(body.to_vec()).unwrap(),
+        }
+    }
+}
+
+/// Retries `f` until it returns `true`, to wait for discovery updates to
+/// propagate through the stack.
+pub async fn eventually<F: Future<Output = bool>>(mut f: impl FnMut() -> F) {
+    tokio::time::timeout(Duration::from_secs(5), async move {
+        while !f().await {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("condition was not met in time")
+}